@@ -0,0 +1,104 @@
+// Geographic helpers shared by the location based queries.
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+
+pub fn is_valid_coordinate(latitude: f64, longitude: f64) -> bool {
+    (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+}
+
+
+// Great-circle distance between two points using the haversine formula
+pub fn distance_km(latitude1: f64, longitude1: f64, latitude2: f64, longitude2: f64) -> f64 {
+    let d_latitude = (latitude2 - latitude1).to_radians();
+    let d_longitude = (longitude2 - longitude1).to_radians();
+    let a = (d_latitude / 2.0).sin().powi(2)
+        + latitude1.to_radians().cos() * latitude2.to_radians().cos() * (d_longitude / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+}
+
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const KM_PER_DEGREE: f64 = EARTH_RADIUS_KM * std::f64::consts::PI / 180.0;
+
+// Precision of the geohashes stored in the indexes, cells of about 150m
+pub const GEOHASH_PRECISION: usize = 7;
+
+// Radius searches look up at most this many cells, falling back to coarser
+// cells for larger radiuses
+const MAX_SEARCH_CELLS: usize = 32;
+
+
+pub fn geohash(latitude: f64, longitude: f64, precision: usize) -> String {
+    let (mut latitude_range, mut longitude_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even_bit = true;
+    for _ in 0..precision {
+        let mut index = 0;
+        for _ in 0..5 {
+            let (range, value): (&mut (f64, f64), f64) = if even_bit {
+                (&mut longitude_range, longitude)
+            } else {
+                (&mut latitude_range, latitude)
+            };
+            let middle = (range.0 + range.1) / 2.0;
+            index <<= 1;
+            if value >= middle {
+                index |= 1;
+                range.0 = middle;
+            } else {
+                range.1 = middle;
+            }
+            even_bit = !even_bit;
+        }
+        hash.push(GEOHASH_ALPHABET[index] as char);
+    }
+    hash
+}
+
+
+// Height and width in degrees of the cells of the given precision
+fn cell_size(precision: usize) -> (f64, f64) {
+    let bits = 5 * precision as i32;
+    let latitude_bits = bits / 2;
+    let longitude_bits = bits - latitude_bits;
+    (180.0 / 2f64.powi(latitude_bits), 360.0 / 2f64.powi(longitude_bits))
+}
+
+
+// Geohash prefixes whose cells together cover every point within
+// `radius_km` of the given point. Candidates still have to be checked with
+// `distance_km`, the cells cover a bounding box rather than a circle.
+pub fn covering_geohashes(latitude: f64, longitude: f64, radius_km: f64) -> Vec<String> {
+    let d_latitude = radius_km / KM_PER_DEGREE;
+    let (south, north) = ((latitude - d_latitude).max(-90.0), (latitude + d_latitude).min(90.0));
+    let widest = south.abs().max(north.abs());
+    let d_longitude = if widest >= 90.0 {
+        180.0
+    } else {
+        (d_latitude / widest.to_radians().cos()).min(180.0)
+    };
+
+    for precision in (1..=GEOHASH_PRECISION).rev() {
+        let (height, width) = cell_size(precision);
+        let rows = ((north - south) / height).ceil() as usize + 1;
+        let columns = (2.0 * d_longitude / width).ceil() as usize + 1;
+        if rows * columns > MAX_SEARCH_CELLS {
+            continue;
+        }
+
+        let mut cells = std::collections::BTreeSet::new();
+        for row in 0..rows {
+            let cell_latitude = (south + row as f64 * height).min(north);
+            for column in 0..columns {
+                let offset = (column as f64 * width).min(2.0 * d_longitude);
+                let cell_longitude = (longitude - d_longitude + offset + 180.0).rem_euclid(360.0) - 180.0;
+                cells.insert(geohash(cell_latitude, cell_longitude, precision));
+            }
+        }
+        return cells.into_iter().collect();
+    }
+
+    // The whole world
+    vec![String::new()]
+}
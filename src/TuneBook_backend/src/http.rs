@@ -0,0 +1,457 @@
+use crate::ical;
+use crate::midi;
+use crate::types::{
+    HttpRequest, HttpResponse, StreamingCallback, StreamingCallbackHttpResponse,
+    StreamingCallbackToken, StreamingStrategy,
+};
+use crate::types::Session;
+use crate::utils;
+use candid::Encode;
+use sha2::{Digest, Sha256};
+
+
+const IMMUTABLE_CACHE: &str = "public, max-age=31536000, immutable";
+const SHORT_CACHE: &str = "public, max-age=300";
+const PRIVATE_CACHE: &str = "private, max-age=300"; // For responses behind a secret token
+
+
+// Content that is stored in fixed-size chunks and can therefore be streamed
+// and served in byte ranges.
+enum Chunked {
+    Blob(String),
+    Recording(u64),
+}
+
+impl Chunked {
+    fn chunk_size(&self) -> u64 {
+        match self {
+            Chunked::Blob(_) => utils::BLOB_CHUNK_SIZE,
+            Chunked::Recording(_) => utils::RECORDING_CHUNK_SIZE,
+        }
+    }
+
+    fn chunk(&self, index: u32) -> Option<Vec<u8>> {
+        match self {
+            Chunked::Blob(blob_id) => utils::get_blob_chunk(blob_id.clone(), index),
+            Chunked::Recording(recording_id) => utils::get_recording_chunk(*recording_id, index),
+        }
+    }
+
+    fn token_key(&self) -> String {
+        match self {
+            Chunked::Blob(blob_id) => format!("blobs/{}", blob_id),
+            Chunked::Recording(recording_id) => format!("recordings/{}", recording_id),
+        }
+    }
+
+    fn from_token_key(key: &str) -> Option<Chunked> {
+        match key.split('/').collect::<Vec<&str>>().as_slice() {
+            ["blobs", blob_id] => Some(Chunked::Blob(blob_id.to_string())),
+            ["recordings", id] => id.parse().ok().map(Chunked::Recording),
+            _ => None,
+        }
+    }
+}
+
+
+struct Asset {
+    content: Chunked,
+    content_type: String,
+    size: u64,
+    chunk_count: u32,
+    etag: String,
+    cache_control: &'static str,
+}
+
+
+pub fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.method != "GET" && req.method != "HEAD" {
+        return error_response(405, "Method not allowed");
+    }
+
+    let path = req.url.split('?').next().unwrap_or("/");
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|segment| segment.as_str()).collect();
+
+    let mut response = match segments.as_slice() {
+        ["recordings", file] => match strip_extension(file).parse::<u64>().ok().and_then(recording_asset) {
+            Some(asset) => asset_response(&req, asset),
+            None => error_response(404, "Recording not found"),
+        },
+        ["blobs", blob_id] => match blob_asset(strip_extension(blob_id), IMMUTABLE_CACHE) {
+            Some(asset) => asset_response(&req, asset),
+            None => error_response(404, "Blob not found"),
+        },
+        ["avatars", principal] => match avatar_id(principal).and_then(|id| blob_asset(&id, SHORT_CACHE)) {
+            Some(asset) => asset_response(&req, asset),
+            None => error_response(404, "Avatar not found"),
+        },
+        ["instruments", id, "photos", index] => {
+            match instrument_photo_id(id, index).and_then(|id| blob_asset(&id, SHORT_CACHE)) {
+                Some(asset) => asset_response(&req, asset),
+                None => error_response(404, "Photo not found"),
+            }
+        }
+        ["forum", "posts", id, "photos", index] => {
+            match post_photo_id(id, index).and_then(|id| blob_asset(&id, SHORT_CACHE)) {
+                Some(asset) => asset_response(&req, asset),
+                None => error_response(404, "Photo not found"),
+            }
+        }
+        ["tunes", file] => tune_response(&req, file),
+        ["sessions", file] => match file.strip_suffix(".ics").and_then(|id| id.parse::<u32>().ok()) {
+            Some(id) => match utils::SESSION_STORE.with(|session_store| session_store.borrow().get(&id)) {
+                Some(session) if session.schedule.is_some() => calendar_response(&req, &session.name.clone(), vec![session], SHORT_CACHE),
+                _ => error_response(404, "Session not found"),
+            },
+            None => error_response(404, "Not found"),
+        },
+        ["users", principal, "sessions.ics"] => match utils::get_calendar_sessions(&principal.to_string(), &query_param(&req.url, "token").unwrap_or_default()) {
+            Some(sessions) => calendar_response(&req, "TuneBook sessions", sessions, PRIVATE_CACHE),
+            None => error_response(404, "User not found"),
+        },
+        _ => error_response(404, "Not found"),
+    };
+
+    if req.method == "HEAD" {
+        response.body = vec![];
+        response.streaming_strategy = None;
+    }
+    response
+}
+
+
+pub fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let content = match Chunked::from_token_key(&token.key) {
+        Some(content) => content,
+        None => return StreamingCallbackHttpResponse { body: vec![], token: None },
+    };
+
+    let chunk_count = match &content {
+        Chunked::Blob(blob_id) => utils::get_blob_info(blob_id.clone()).map(|blob| blob.chunk_count),
+        Chunked::Recording(id) => utils::get_recording(*id).map(|recording| recording.chunk_count),
+    }
+    .unwrap_or_default();
+
+    let body = content.chunk(token.index).unwrap_or_default();
+    let next_token = if token.index + 1 < chunk_count {
+        Some(StreamingCallbackToken {
+            key: token.key,
+            index: token.index + 1,
+        })
+    } else {
+        None
+    };
+    StreamingCallbackHttpResponse { body, token: next_token }
+}
+
+
+fn recording_asset(recording_id: u64) -> Option<Asset> {
+    let recording = utils::get_recording(recording_id)?;
+    Some(Asset {
+        content: Chunked::Recording(recording.id),
+        content_type: recording.mime_type,
+        size: recording.size,
+        chunk_count: recording.chunk_count,
+        etag: format!("\"recording-{}-{}\"", recording.id, recording.created_at),
+        cache_control: SHORT_CACHE,
+    })
+}
+
+
+fn blob_asset(blob_id: &str, cache_control: &'static str) -> Option<Asset> {
+    let blob = utils::get_blob_info(blob_id.to_string())?;
+    Some(Asset {
+        etag: format!("\"{}\"", blob.id),
+        content: Chunked::Blob(blob.id),
+        content_type: utils::servable_content_type(&blob.content_type).to_string(),
+        size: blob.size,
+        chunk_count: blob.chunk_count,
+        cache_control,
+    })
+}
+
+
+fn avatar_id(principal: &str) -> Option<String> {
+    utils::PROFILE_STORE.with(|profile_store| {
+        profile_store
+            .borrow()
+            .get(&principal.to_string())
+            .and_then(|profile| profile.avatar_id)
+    })
+}
+
+
+fn instrument_photo_id(id: &str, index: &str) -> Option<String> {
+    let id = id.parse::<u32>().ok()?;
+    let index = strip_extension(index).parse::<usize>().ok()?;
+    utils::INSTRUMENT_STORE.with(|instrument_store| {
+        instrument_store
+            .borrow()
+            .get(&id)
+            .and_then(|instrument| instrument.photo_ids)
+            .and_then(|photo_ids| photo_ids.get(index).cloned())
+    })
+}
+
+
+fn post_photo_id(id: &str, index: &str) -> Option<String> {
+    let id = id.parse::<u64>().ok()?;
+    let index = strip_extension(index).parse::<usize>().ok()?;
+    utils::FORUM_DATA_STORE.with(|forum_data_store| {
+        forum_data_store
+            .borrow()
+            .get(&id)
+            .and_then(|post| post.photo_ids)
+            .and_then(|photo_ids| photo_ids.get(index).cloned())
+    })
+}
+
+
+// Tunes are served as /tunes/<title>.abc or /tunes/<title>.mid
+fn tune_response(req: &HttpRequest, file: &str) -> HttpResponse {
+    let (title, content_type) = if let Some(title) = file.strip_suffix(".abc") {
+        (title, "text/vnd.abc; charset=utf-8")
+    } else if let Some(title) = file.strip_suffix(".mid") {
+        (title, "audio/midi")
+    } else {
+        return error_response(404, "Not found");
+    };
+
+    let tune = match utils::TUNE_STORE.with(|tune_store| tune_store.borrow().get(&title.to_string())) {
+        Some(tune) => tune,
+        None => return error_response(404, "Tune not found"),
+    };
+
+    let digest = Sha256::digest(tune.tune_data.as_bytes());
+    let etag = format!("\"{}\"", digest.iter().take(8).map(|byte| format!("{:02x}", byte)).collect::<String>());
+    if if_none_match(req, &etag) {
+        return not_modified(&etag, SHORT_CACHE);
+    }
+
+    let body = if content_type == "audio/midi" {
+        midi::abc_to_midi(&tune.tune_data)
+    } else {
+        tune.tune_data.into_bytes()
+    };
+
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("ETag".to_string(), etag),
+            ("Cache-Control".to_string(), SHORT_CACHE.to_string()),
+        ],
+        body,
+        streaming_strategy: None,
+    }
+}
+
+
+// The ETag is derived from the sessions rather than the body, whose DTSTAMP
+// changes on every request.
+fn calendar_response(req: &HttpRequest, name: &str, sessions: Vec<Session>, cache_control: &'static str) -> HttpResponse {
+    let digest = Sha256::digest(Encode!(&name, &sessions).unwrap_or_default());
+    let etag = format!("\"{}\"", digest.iter().take(8).map(|byte| format!("{:02x}", byte)).collect::<String>());
+    if if_none_match(req, &etag) {
+        return not_modified(&etag, cache_control);
+    }
+
+    let body = ical::calendar(name, &sessions, ic_cdk::api::time()).into_bytes();
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), "text/calendar; charset=utf-8".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("ETag".to_string(), etag),
+            ("Cache-Control".to_string(), cache_control.to_string()),
+        ],
+        body,
+        streaming_strategy: None,
+    }
+}
+
+
+fn asset_response(req: &HttpRequest, asset: Asset) -> HttpResponse {
+    if if_none_match(req, &asset.etag) {
+        return not_modified(&asset.etag, asset.cache_control);
+    }
+
+    let mut headers = vec![
+        ("Content-Type".to_string(), asset.content_type.clone()),
+        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        ("ETag".to_string(), asset.etag.clone()),
+        ("Cache-Control".to_string(), asset.cache_control.to_string()),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+    ];
+
+    if let Some(range) = header(req, "range") {
+        return match parse_range(range, asset.size) {
+            Some((start, end)) => range_response(&asset, start, end, headers),
+            None => {
+                headers.push(("Content-Range".to_string(), format!("bytes */{}", asset.size)));
+                HttpResponse {
+                    status_code: 416,
+                    headers,
+                    body: vec![],
+                    streaming_strategy: None,
+                }
+            }
+        };
+    }
+
+    headers.push(("Content-Length".to_string(), asset.size.to_string()));
+    let streaming_strategy = if asset.chunk_count > 1 {
+        Some(StreamingStrategy::Callback {
+            callback: StreamingCallback::new(
+                ic_cdk::api::id(),
+                "http_request_streaming_callback".to_string(),
+            ),
+            token: StreamingCallbackToken {
+                key: asset.content.token_key(),
+                index: 1,
+            },
+        })
+    } else {
+        None
+    };
+
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: asset.content.chunk(0).unwrap_or_default(),
+        streaming_strategy,
+    }
+}
+
+
+// Serves at most one chunk worth of data per range request; clients follow
+// up with further ranges based on the returned Content-Range.
+fn range_response(asset: &Asset, start: u64, end: u64, mut headers: Vec<(String, String)>) -> HttpResponse {
+    let chunk_size = asset.content.chunk_size();
+    let end = end.min(start + chunk_size - 1);
+
+    let mut body = Vec::with_capacity((end - start + 1) as usize);
+    for index in (start / chunk_size)..=(end / chunk_size) {
+        let chunk = asset.content.chunk(index as u32).unwrap_or_default();
+        let chunk_start = index * chunk_size;
+        let from = start.saturating_sub(chunk_start) as usize;
+        let to = ((end + 1 - chunk_start) as usize).min(chunk.len());
+        if from < to {
+            body.extend_from_slice(&chunk[from..to]);
+        }
+    }
+
+    headers.push(("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, asset.size)));
+    headers.push(("Content-Length".to_string(), body.len().to_string()));
+    HttpResponse {
+        status_code: 206,
+        headers,
+        body,
+        streaming_strategy: None,
+    }
+}
+
+
+// Supports a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || size == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+
+    let (start, end) = if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?.min(size);
+        (size - suffix, size - 1)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() { size - 1 } else { end.parse::<u64>().ok()?.min(size - 1) };
+        (start, end)
+    };
+
+    if start > end || start >= size {
+        return None;
+    }
+    Some((start, end))
+}
+
+
+fn header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    header(req, "if-none-match")
+        .map(|value| value.split(',').any(|candidate| candidate.trim() == etag || candidate.trim() == "*"))
+        .unwrap_or(false)
+}
+
+
+fn not_modified(etag: &str, cache_control: &str) -> HttpResponse {
+    HttpResponse {
+        status_code: 304,
+        headers: vec![
+            ("ETag".to_string(), etag.to_string()),
+            ("Cache-Control".to_string(), cache_control.to_string()),
+        ],
+        body: vec![],
+        streaming_strategy: None,
+    }
+}
+
+
+fn strip_extension(file: &str) -> &str {
+    file.split('.').next().unwrap_or_default()
+}
+
+
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && index + 2 < bytes.len()
+            && bytes[index + 1].is_ascii_hexdigit()
+            && bytes[index + 2].is_ascii_hexdigit()
+        {
+            let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).unwrap_or_default();
+            decoded.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+            index += 3;
+            continue;
+        }
+        decoded.push(bytes[index]);
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: message.as_bytes().to_vec(),
+        streaming_strategy: None,
+    }
+}
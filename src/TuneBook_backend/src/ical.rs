@@ -0,0 +1,227 @@
+// RFC 5545 calendars for sessions, served through http_request so that
+// calendar apps can subscribe to them.
+
+use crate::schedule;
+use crate::types::{Frequency, Recurrence, Session, SessionSchedule, Weekday};
+use chrono::{DateTime, Datelike, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+
+const PRODUCT_ID: &str = "-//TuneBook//Sessions//EN";
+const TIMEZONE_YEARS_AHEAD: i32 = 5;
+const MAX_LINE_OCTETS: usize = 75;
+const SECONDS_PER_DAY: i64 = 86_400;
+
+
+// Builds a calendar with one event per scheduled session. Sessions without a
+// schedule can't be placed in a calendar and are left out.
+pub fn calendar(name: &str, sessions: &[Session], now: u64) -> String {
+    let scheduled: Vec<(&Session, &SessionSchedule, Tz)> = sessions
+        .iter()
+        .filter_map(|session| {
+            let schedule = session.schedule.as_ref()?;
+            let timezone = schedule::parse_timezone(&schedule.timezone).ok()?;
+            Some((session, schedule, timezone))
+        })
+        .collect();
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{}", PRODUCT_ID),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{}", escape_text(name)),
+    ];
+
+    // Every TZID used by an event needs a matching VTIMEZONE
+    let mut timezones: Vec<(Tz, i32)> = vec![];
+    for (_, schedule, timezone) in scheduled.iter() {
+        let year = to_datetime(schedule.start).year();
+        match timezones.iter_mut().find(|(known, _)| known == timezone) {
+            Some((_, first_year)) => *first_year = (*first_year).min(year),
+            None => timezones.push((*timezone, year)),
+        }
+    }
+    let last_year = to_datetime(now).year() + TIMEZONE_YEARS_AHEAD;
+    for (timezone, first_year) in timezones {
+        lines.extend(timezone_lines(&timezone, first_year - 1, last_year));
+    }
+
+    for (session, schedule, timezone) in scheduled {
+        lines.extend(event_lines(session, schedule, &timezone, now));
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect::<Vec<_>>().join("")
+}
+
+
+fn event_lines(session: &Session, schedule: &SessionSchedule, timezone: &Tz, now: u64) -> Vec<String> {
+    let start = to_datetime(schedule.start).with_timezone(timezone).naive_local();
+    let mut description = session.comment.clone();
+    if !session.contact.is_empty() {
+        description = format!("{}\nContact: {}", description, session.contact).trim().to_string();
+    }
+    description = format!("{}\nOrganized by {}", description, session.username).trim().to_string();
+
+    let mut lines = vec![
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:session-{}@tunebook", session.id),
+        format!("DTSTAMP:{}", format_utc(now)),
+        format!("DTSTART;TZID={}:{}", timezone.name(), format_local(&start)),
+        format!("DURATION:PT{}M", schedule.duration_minutes),
+    ];
+    if let Some(recurrence) = &schedule.recurrence {
+        lines.push(format!("RRULE:{}", rrule(recurrence)));
+    }
+    lines.push(format!("SUMMARY:{}", escape_text(&session.name)));
+    if !session.location.is_empty() {
+        lines.push(format!("LOCATION:{}", escape_text(&session.location)));
+    }
+    lines.push(format!("DESCRIPTION:{}", escape_text(&description)));
+    lines.push("END:VEVENT".to_string());
+    lines
+}
+
+
+pub fn rrule(recurrence: &Recurrence) -> String {
+    let frequency = match recurrence.frequency {
+        Frequency::Daily => "DAILY",
+        Frequency::Weekly => "WEEKLY",
+        Frequency::Monthly => "MONTHLY",
+    };
+    let mut parts = vec![format!("FREQ={}", frequency)];
+    if recurrence.interval > 1 {
+        parts.push(format!("INTERVAL={}", recurrence.interval));
+    }
+    if !recurrence.by_day.is_empty() {
+        let days: Vec<&str> = recurrence.by_day.iter().map(|day| weekday_code(*day)).collect();
+        parts.push(format!("BYDAY={}", days.join(",")));
+    }
+    if let Some(position) = recurrence.by_set_pos {
+        parts.push(format!("BYSETPOS={}", position));
+    }
+    if let Some(until) = recurrence.until {
+        parts.push(format!("UNTIL={}", format_utc(until)));
+    }
+    if let Some(count) = recurrence.count {
+        parts.push(format!("COUNT={}", count));
+    }
+    parts.join(";")
+}
+
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Monday => "MO",
+        Weekday::Tuesday => "TU",
+        Weekday::Wednesday => "WE",
+        Weekday::Thursday => "TH",
+        Weekday::Friday => "FR",
+        Weekday::Saturday => "SA",
+        Weekday::Sunday => "SU",
+    }
+}
+
+
+// Describes the timezone through its actual offset changes between the two
+// years, one observance per change. The tz database has no RRULEs to copy,
+// and listing the transitions keeps historical rule changes correct.
+fn timezone_lines(timezone: &Tz, first_year: i32, last_year: i32) -> Vec<String> {
+    let offset_at = |seconds: i64| -> i32 {
+        let utc = DateTime::from_timestamp(seconds, 0).unwrap_or_default().naive_utc();
+        timezone.offset_from_utc_datetime(&utc).fix().local_minus_utc()
+    };
+    let year_start = |year: i32| -> i64 {
+        Utc.with_ymd_and_hms(year, 1, 1, 0, 0, 0).single().map(|datetime| datetime.timestamp()).unwrap_or_default()
+    };
+
+    let mut lines = vec!["BEGIN:VTIMEZONE".to_string(), format!("TZID:{}", timezone.name())];
+    let mut transitions = vec![];
+    let mut day = year_start(first_year);
+    let end = year_start(last_year + 1);
+    while day < end {
+        let next = day + SECONDS_PER_DAY;
+        let (before, after) = (offset_at(day), offset_at(next));
+        if before != after {
+            // Narrow the change down to the second
+            let (mut low, mut high) = (day, next);
+            while high - low > 1 {
+                let middle = low + (high - low) / 2;
+                if offset_at(middle) == before {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            transitions.push((high, before, after));
+        }
+        day = next;
+    }
+
+    if transitions.is_empty() {
+        let offset = offset_at(year_start(first_year));
+        transitions.push((0, offset, offset));
+    }
+    for (at, from, to) in transitions {
+        let kind = if to > from { "DAYLIGHT" } else { "STANDARD" };
+        let local = DateTime::from_timestamp(at + from as i64, 0).unwrap_or_default().naive_utc();
+        lines.push(format!("BEGIN:{}", kind));
+        lines.push(format!("DTSTART:{}", format_local(&local)));
+        lines.push(format!("TZOFFSETFROM:{}", format_offset(from)));
+        lines.push(format!("TZOFFSETTO:{}", format_offset(to)));
+        lines.push(format!("END:{}", kind));
+    }
+    lines.push("END:VTIMEZONE".to_string());
+    lines
+}
+
+
+fn to_datetime(nanos: u64) -> DateTime<Utc> {
+    Utc.timestamp_nanos(nanos.min(i64::MAX as u64) as i64)
+}
+
+
+fn format_utc(nanos: u64) -> String {
+    to_datetime(nanos).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+
+fn format_local(datetime: &NaiveDateTime) -> String {
+    datetime.format("%Y%m%dT%H%M%S").to_string()
+}
+
+
+fn format_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.unsigned_abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace(['\n', '\r'], "\\n")
+}
+
+
+// Content lines are limited to 75 octets; longer ones continue on lines
+// starting with a space. Multi-byte characters are never split.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut octets = 0;
+    for character in line.chars() {
+        if octets + character.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(character);
+        octets += character.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
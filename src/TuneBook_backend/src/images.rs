@@ -0,0 +1,291 @@
+// Server side checks for user supplied images. Uploads are sniffed by their
+// magic bytes rather than trusting the client, their dimensions are read from
+// the headers before anything is decoded, metadata such as EXIF (which often
+// carries GPS coordinates) is stripped, and a small preview is generated.
+
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageReader, Limits};
+use std::io::Cursor;
+
+
+pub const MAX_IMAGE_BYTES: usize = 8 * 1_048_576;
+const MAX_DIMENSION: u32 = 6000;
+const THUMBNAIL_SIZE: u32 = 256;
+const THUMBNAIL_QUALITY: u8 = 80;
+const REENCODE_QUALITY: u8 = 90;
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    WebP,
+}
+
+impl ImageFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
+
+    fn codec(&self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::WebP => image::ImageFormat::WebP,
+        }
+    }
+}
+
+
+pub struct ProcessedImage {
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+    pub thumbnail_content_type: &'static str,
+}
+
+
+pub fn sniff(data: &[u8]) -> Option<ImageFormat> {
+    if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(ImageFormat::Png)
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageFormat::Jpeg)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(ImageFormat::WebP)
+    } else {
+        None
+    }
+}
+
+
+// Validates the image, strips its metadata and renders a thumbnail.
+pub fn process(data: &[u8]) -> Result<ProcessedImage, String> {
+    if data.len() > MAX_IMAGE_BYTES {
+        return Err(format!("Images must be smaller than {} bytes", MAX_IMAGE_BYTES));
+    }
+    let format = sniff(data).ok_or("Unsupported image format, expected PNG, JPEG or WebP")?;
+    let (width, height) = dimensions(format, data).ok_or("Could not read the image dimensions")?;
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(format!("Images must be at most {}x{} pixels", MAX_DIMENSION, MAX_DIMENSION));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format.codec());
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(|_| "The image could not be decoded")?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut decoded = DynamicImage::from_decoder(decoder).map_err(|_| "The image could not be decoded")?;
+
+    // Stripping EXIF would also drop the orientation tag, so rotated photos
+    // are re-encoded upright instead of being patched in place.
+    let (content_type, data, width, height) = if orientation != Orientation::NoTransforms {
+        decoded.apply_orientation(orientation);
+        let (data, content_type) = encode(&decoded, REENCODE_QUALITY)?;
+        (content_type, data, decoded.width(), decoded.height())
+    } else {
+        (format.content_type(), strip_metadata(format, data), width, height)
+    };
+
+    let (thumbnail, thumbnail_content_type) = encode(&decoded.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE), THUMBNAIL_QUALITY)?;
+
+    Ok(ProcessedImage {
+        content_type,
+        width,
+        height,
+        data,
+        thumbnail,
+        thumbnail_content_type,
+    })
+}
+
+
+// JPEG for opaque images, PNG when transparency has to be kept
+fn encode(image: &DynamicImage, quality: u8) -> Result<(Vec<u8>, &'static str), String> {
+    let mut buffer = Cursor::new(vec![]);
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut buffer, image::ImageFormat::Png)
+            .map_err(|_| "The image could not be encoded")?;
+        Ok((buffer.into_inner(), ImageFormat::Png.content_type()))
+    } else {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buffer, quality);
+        image
+            .to_rgb8()
+            .write_with_encoder(encoder)
+            .map_err(|_| "The image could not be encoded")?;
+        Ok((buffer.into_inner(), ImageFormat::Jpeg.content_type()))
+    }
+}
+
+
+fn dimensions(format: ImageFormat, data: &[u8]) -> Option<(u32, u32)> {
+    match format {
+        ImageFormat::Png => {
+            // The IHDR chunk always comes first
+            if data.get(12..16)? != b"IHDR" {
+                return None;
+            }
+            Some((read_u32_be(data, 16)?, read_u32_be(data, 20)?))
+        }
+        ImageFormat::Jpeg => {
+            let mut offset = 2;
+            loop {
+                while *data.get(offset)? == 0xFF && *data.get(offset + 1)? == 0xFF {
+                    offset += 1;
+                }
+                if *data.get(offset)? != 0xFF {
+                    return None;
+                }
+                let marker = *data.get(offset + 1)?;
+                if (0xD0..=0xD8).contains(&marker) || marker == 0x01 {
+                    offset += 2;
+                    continue;
+                }
+                let length = read_u16_be(data, offset + 2)? as usize;
+                // Start of frame markers, excluding DHT, JPG and DAC
+                if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+                    let height = read_u16_be(data, offset + 5)? as u32;
+                    let width = read_u16_be(data, offset + 7)? as u32;
+                    return Some((width, height));
+                }
+                if marker == 0xDA {
+                    return None; // Reached the scan without a frame header
+                }
+                offset += 2 + length;
+            }
+        }
+        ImageFormat::WebP => match data.get(12..16)? {
+            b"VP8 " => {
+                let width = read_u16_le(data, 26)? & 0x3FFF;
+                let height = read_u16_le(data, 28)? & 0x3FFF;
+                Some((width as u32, height as u32))
+            }
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => {
+                let width = read_u24_le(data, 24)? + 1;
+                let height = read_u24_le(data, 27)? + 1;
+                Some((width, height))
+            }
+            _ => None,
+        },
+    }
+}
+
+
+fn strip_metadata(format: ImageFormat, data: &[u8]) -> Vec<u8> {
+    match format {
+        ImageFormat::Png => strip_png(data),
+        ImageFormat::Jpeg => strip_jpeg(data),
+        ImageFormat::WebP => strip_webp(data),
+    }
+}
+
+
+// Drops APP1 (EXIF and XMP), APP13 (IPTC) and comment segments
+fn strip_jpeg(data: &[u8]) -> Vec<u8> {
+    let mut output = data[..2].to_vec();
+    let mut offset = 2;
+
+    while offset + 4 <= data.len() && data[offset] == 0xFF {
+        let marker = data[offset + 1];
+        if marker == 0xDA {
+            break; // Start of scan, the rest is entropy coded data
+        }
+        let length = match read_u16_be(data, offset + 2) {
+            Some(length) => length as usize,
+            None => break,
+        };
+        let end = (offset + 2 + length).min(data.len());
+        if ![0xE1, 0xED, 0xFE].contains(&marker) {
+            output.extend_from_slice(&data[offset..end]);
+        }
+        offset = end;
+    }
+
+    output.extend_from_slice(&data[offset.min(data.len())..]);
+    output
+}
+
+
+// Drops EXIF, textual and timestamp chunks
+fn strip_png(data: &[u8]) -> Vec<u8> {
+    let mut output = data[..8].to_vec();
+    let mut offset = 8;
+
+    while offset + 8 <= data.len() {
+        let length = read_u32_be(data, offset).unwrap_or_default() as usize;
+        // A bogus length near u32::MAX overflows usize on wasm32
+        let end = match offset.checked_add(12).and_then(|end| end.checked_add(length)) {
+            Some(end) => end.min(data.len()),
+            None => break,
+        };
+        let chunk_type = &data[offset + 4..offset + 8];
+        if ![b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"].iter().any(|t| t.as_slice() == chunk_type) {
+            output.extend_from_slice(&data[offset..end]);
+        }
+        offset = end;
+    }
+
+    output
+}
+
+
+// Drops the EXIF and XMP chunks and clears their flags in the VP8X header
+fn strip_webp(data: &[u8]) -> Vec<u8> {
+    let mut chunks = vec![];
+    let mut offset = 12;
+
+    while offset + 8 <= data.len() {
+        let length = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap_or_default()) as usize;
+        let padded = length.checked_add(length & 1);
+        let end = match padded.and_then(|padded| offset.checked_add(8)?.checked_add(padded)) {
+            Some(end) => end.min(data.len()),
+            None => break,
+        };
+        let chunk_type = &data[offset..offset + 4];
+        if chunk_type != b"EXIF" && chunk_type != b"XMP " {
+            let mut chunk = data[offset..end].to_vec();
+            if chunk_type == b"VP8X" && chunk.len() > 8 {
+                chunk[8] &= !(0x08 | 0x04);
+            }
+            chunks.push(chunk);
+        }
+        offset = end;
+    }
+
+    let body: Vec<u8> = chunks.concat();
+    let mut output = b"RIFF".to_vec();
+    output.extend(((body.len() + 4) as u32).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend(body);
+    output
+}
+
+
+fn read_u16_be(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u24_le(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 3)?;
+    Some(bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16)
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
@@ -1,6 +1,15 @@
+#![allow(non_snake_case)]
+
 mod utils;
 mod types;
-use crate::types::{Forum, ForumData};
+mod http;
+mod midi;
+mod images;
+mod geo;
+mod schedule;
+mod ical;
+mod price;
+use crate::types::ForumData;
 
 
 #[ic_cdk::init]
@@ -12,17 +21,29 @@ fn init(time: u64) {
     ic_cdk_timers::set_timer(std::time::Duration::from_secs(time), || {
         ic_cdk::spawn(update_data())
     });
+
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(3600), utils::sweep_blobs);
+    ic_cdk_timers::set_timer_interval(std::time::Duration::from_secs(3600), utils::expire_listings);
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade(time: u64) {
+    utils::migrate_id_counters();
+
     ic_cdk::spawn(async {
         utils::init().await;  
     });
     init(time);
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, utils::run_background_migrations);
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, utils::report_id_collisions);
 }
 
 
+#[ic_cdk::query]
+fn get_id_collisions(principal: String) -> Result<Vec<types::IdCollision>, String> {
+    utils::get_id_collisions(principal)
+}
+
 #[ic_cdk::update]
 async fn update_data() {
     utils::init().await
@@ -33,8 +54,19 @@ fn authentication(principal: String) -> Option<types::Profile> {
     utils::authentication(principal)
 }
 
+#[ic_cdk::query]
+fn check_username_available(principal: String, username: String) -> Result<(), String> {
+    utils::check_username_available(principal, username)
+}
+
+#[ic_cdk::query]
+fn get_profile_by_username(username: String) -> Option<types::PublicProfile> {
+    utils::get_profile_by_username(username, utils::caller_principal())
+}
+
 #[ic_cdk::update]
-async fn update_profile(principal: String, username: String, pob: String, instruments: String, bio: Option<String>, avatar: Vec<u8>) -> types::Profile {
+async fn update_profile(principal: String, username: String, pob: String, instruments: String, bio: Option<String>, avatar: Vec<u8>) -> Result<types::Profile, String> {
+    utils::check_caller(&principal)?;
     utils::update_profile(principal, username, pob, instruments, bio, avatar).await
 }
 
@@ -50,7 +82,7 @@ fn get_original_tune(title: String) -> String {
 
 #[ic_cdk::query]
 fn get_user_tune_list(principal: String, page_number: i32) -> (Vec<types::Tuneinfo>, i32) {
-    utils::get_user_tune_list(principal, page_number)
+    utils::get_user_tune_list(principal, page_number, utils::caller_principal())
 }
 
 #[ic_cdk::query]
@@ -70,25 +102,74 @@ async fn update_tune(principal: String, title: String, tune_data: String, origin
 
 #[ic_cdk::query]
 pub fn get_friends(principal: String) -> Vec<types::Friend> {
-    utils::get_friends(principal)
+    utils::get_friends(principal, utils::caller_principal())
+}
+
+#[ic_cdk::query]
+pub fn get_relationships(principal: String, state: types::RelationshipState, page_num: i32) -> (Vec<types::Friend>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::get_relationships(principal, state, page_num)
 }
 
 #[ic_cdk::update]
 pub async fn send_friend_request(sender: String, receiver: String) -> Option<types::Friend> {
+    if utils::check_caller(&sender).is_err() {
+        return None;
+    }
     utils::send_friend_request(sender, receiver).await
 }
 
 #[ic_cdk::update]
 pub async fn accept_friend_request(sender: String, receiver: String) -> bool {
+    if utils::check_caller(&sender).is_err() {
+        return false;
+    }
     utils::accept_friend_request(sender, receiver).await
 }
 
 #[ic_cdk::update]
 pub async fn cancel_friend_request(sender: String, receiver: String) -> bool {
+    if utils::check_caller(&sender).is_err() {
+        return false;
+    }
     utils::cancel_friend_request(sender, receiver).await
 }
 
 
+#[ic_cdk::update]
+pub fn decline_friend_request(principal: String, requester: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::decline_friend_request(principal, requester)
+}
+
+#[ic_cdk::update]
+pub fn unfriend(principal: String, friend: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::unfriend(principal, friend)
+}
+
+#[ic_cdk::update]
+pub fn block_user(principal: String, blocked: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::block_user(principal, blocked)
+}
+
+#[ic_cdk::update]
+pub fn unblock_user(principal: String, blocked: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::unblock_user(principal, blocked)
+}
+
 #[ic_cdk::query]
 pub fn filter_tunes(title:String, rithm: String, key: String, page_num: i32) -> (Vec<types::Tuneinfo>, i32) {
     utils::filter_tunes(title.as_str(), rithm.as_str(), key.as_str(), page_num)
@@ -96,37 +177,213 @@ pub fn filter_tunes(title:String, rithm: String, key: String, page_num: i32) ->
 
 #[ic_cdk::query]
 pub fn browse_people(principal: String, filter: String, page_num:i32) -> (Vec<types::Friend>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
     utils::browse_people(principal, filter, page_num)
 }
 
+#[ic_cdk::query]
+pub fn suggest_friends(principal: String, page_num: i32) -> (Vec<types::FriendSuggestion>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::suggest_friends(principal, page_num)
+}
+
+#[ic_cdk::query]
+pub fn find_musicians(principal: String, instrument: Option<types::InstrumentKind>, near: Option<types::Location>, radius_km: f64, page_num: i32) -> (Vec<types::Musician>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::find_musicians(principal, instrument, near, radius_km, page_num)
+}
+
+#[ic_cdk::update]
+pub fn update_musician_profile(principal: String, instruments: Vec<types::PlayedInstrument>, home: Option<types::Location>) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::update_musician_profile(principal, instruments, home)
+}
+
 #[ic_cdk::query]
 pub fn get_new_tunes_from_friends(principal: String) -> Vec<types::Tune> {
     utils::get_new_tunes_from_friends(principal)
 }
 
 #[ic_cdk::query]
-pub fn get_sessions(sub_name: String, page_num: i32) -> (Vec<types::Session>, i32) {
+pub fn get_sessions(sub_name: String, page_num: i32) -> (Vec<types::SessionView>, i32) {
     utils::get_sessions(sub_name.as_str(), page_num)
 }
 
 #[ic_cdk::update]
-pub fn add_session(principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String) -> bool {
-    utils::add_session(principal, username, name, location, daytime, contact, comment, recurring)
+#[allow(clippy::too_many_arguments)]
+pub fn add_session(principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String, schedule: Option<types::SessionSchedule>, coordinates: Option<types::Coordinates>) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::add_session(principal, username, name, location, daytime, contact, comment, recurring, schedule, coordinates)
+}
+
+#[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
+pub fn update_session(id: u32, principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String, schedule: Option<Option<types::SessionSchedule>>, coordinates: Option<Option<types::Coordinates>>) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::update_session(id, principal, username, name, location, daytime, contact, comment, recurring, schedule, coordinates)
+}
+
+#[ic_cdk::query]
+pub fn get_sessions_near(latitude: f64, longitude: f64, radius_km: f64, page_num: i32) -> (Vec<types::NearbySession>, i32) {
+    utils::get_sessions_near(latitude, longitude, radius_km, page_num)
+}
+
+#[ic_cdk::query]
+pub fn get_upcoming_sessions(from: u64, to: u64) -> Vec<types::SessionOccurrence> {
+    utils::get_upcoming_sessions(from, to)
 }
 
 #[ic_cdk::update]
-pub fn update_session(id: u32, principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String) -> bool {
-    utils::update_session(id, principal, username, name, location, daytime, contact, comment, recurring)
+pub fn follow_session(principal: String, session_id: u32) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::follow_session(principal, session_id)
+}
+
+#[ic_cdk::update]
+pub fn unfollow_session(principal: String, session_id: u32) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::unfollow_session(principal, session_id)
+}
+
+#[ic_cdk::query]
+pub fn get_followed_sessions(principal: String) -> Vec<types::SessionView> {
+    if utils::check_caller(&principal).is_err() {
+        return vec![];
+    }
+    utils::get_followed_sessions(principal)
+}
+
+#[ic_cdk::update]
+pub async fn get_calendar_token(rotate: bool) -> Result<String, String> {
+    let principal = utils::caller_principal().ok_or("Sign in first")?;
+    utils::calendar_token(principal, rotate).await
+}
+
+#[ic_cdk::update]
+pub fn rsvp_session(principal: String, session_id: u32, occurrence: u64) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::rsvp_session(principal, session_id, occurrence)
+}
+
+#[ic_cdk::update]
+pub fn cancel_rsvp(principal: String, session_id: u32, occurrence: u64) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::cancel_rsvp(principal, session_id, occurrence)
+}
+
+#[ic_cdk::update]
+pub fn set_session_regular(principal: String, session_id: u32, regular: bool) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::set_session_regular(principal, session_id, regular)
+}
+
+#[ic_cdk::query]
+pub fn get_session_attendance(session_id: u32, occurrence: u64) -> Option<types::SessionAttendance> {
+    utils::get_session_attendance(session_id, occurrence, utils::caller_principal())
+}
+
+#[ic_cdk::update]
+pub fn add_co_organizer(principal: String, session_id: u32, co_organizer: String) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::add_co_organizer(principal, session_id, co_organizer)
+}
+
+#[ic_cdk::update]
+pub fn remove_co_organizer(principal: String, session_id: u32, co_organizer: String) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::remove_co_organizer(principal, session_id, co_organizer)
+}
+
+#[ic_cdk::update]
+pub fn transfer_session(principal: String, session_id: u32, new_owner: String) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::transfer_session(principal, session_id, new_owner)
+}
+
+#[ic_cdk::query]
+pub fn get_session_audit(session_id: u32, principal: String, page_num: i32) -> Result<(Vec<types::SessionAuditEntry>, i32), String> {
+    utils::check_caller(&principal)?;
+    utils::get_session_audit(session_id, principal, page_num)
+}
+
+#[ic_cdk::update]
+pub fn add_played_set(principal: String, session_id: u32, occurrence: u64, tunes: Vec<String>) -> Result<types::PlayedSet, String> {
+    utils::check_caller(&principal)?;
+    utils::add_played_set(principal, session_id, occurrence, tunes)
+}
+
+#[ic_cdk::update]
+pub fn remove_played_set(principal: String, session_id: u32, occurrence: u64, set_id: u64) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::remove_played_set(principal, session_id, occurrence, set_id)
+}
+
+#[ic_cdk::query]
+pub fn get_played_sets(session_id: u32, occurrence: u64) -> Vec<types::PlayedSet> {
+    utils::get_played_sets(session_id, occurrence)
+}
+
+#[ic_cdk::query]
+pub fn get_most_played_tunes(session_id: u32, page_num: i32) -> (Vec<types::TunePlayCount>, i32) {
+    utils::get_most_played_tunes(session_id, page_num)
+}
+
+#[ic_cdk::query]
+pub fn get_popular_tunes(page_num: i32) -> (Vec<types::TunePlayCount>, i32) {
+    utils::get_popular_tunes(page_num)
 }
 
 #[ic_cdk::update]
 pub fn delete_session(id: u32, principal: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
     utils::delete_session(id, principal)
 }
 
 #[ic_cdk::query]
-pub fn get_profile(principal: String) -> Option<types::Profile> {
-    utils::get_profile(principal)
+pub fn get_profile(principal: String) -> Option<types::PublicProfile> {
+    utils::get_profile(principal, utils::caller_principal())
+}
+
+#[ic_cdk::update]
+pub fn delete_account(remove_posts: bool) -> bool {
+    match utils::caller_principal() {
+        Some(principal) => utils::delete_account(principal, remove_posts),
+        None => false,
+    }
+}
+
+#[ic_cdk::query]
+pub fn export_my_data(cursor: Option<u64>) -> Result<types::AccountExport, String> {
+    let principal = utils::caller_principal().ok_or("Sign in first")?;
+    utils::export_my_data(principal, cursor.unwrap_or(0)).ok_or("Profile not found".to_string())
+}
+
+#[ic_cdk::update]
+pub fn update_privacy_settings(principal: String, settings: types::PrivacySettings) -> Result<(), String> {
+    utils::check_caller(&principal)?;
+    utils::update_privacy_settings(principal, settings)
 }
 
 #[ic_cdk::update]
@@ -135,13 +392,76 @@ pub fn remove_tune(principal: String, title: String) -> bool {
 }
 
 #[ic_cdk::query]
-pub fn get_instruments(sub_name: String, page_num: i32) -> (Vec<types::Instrument>, i32) {
-    utils::get_instruments(sub_name.as_str(), page_num)
+pub fn get_instruments(sub_name: String, page_num: i32, filter: Option<types::InstrumentFilter>) -> (Vec<types::Instrument>, i32) {
+    utils::get_instruments(sub_name.as_str(), page_num, filter)
 }
 
 #[ic_cdk::update]
-pub fn add_instrument(seller_principal: String, buyer_principal: String, username: String, name: String, location: String, product: String, comment: String, price: String, photos: Vec<Vec<u8>>) -> bool {
-    utils::add_instrument(seller_principal, buyer_principal, username, name, location, product, comment, price, photos)
+#[allow(clippy::too_many_arguments)]
+pub fn add_instrument(seller_principal: String, buyer_principal: String, username: String, name: String, location: String, product: String, comment: String, price: String, photos: Vec<Vec<u8>>, coordinates: Option<types::Coordinates>, draft: Option<bool>, asking_price: Option<types::Price>, details: Option<types::ListingDetails>) -> bool {
+    utils::add_instrument(seller_principal, buyer_principal, username, name, location, product, comment, price, photos, coordinates, draft, asking_price, details)
+}
+
+#[ic_cdk::update]
+pub fn update_instrument(seller: String, id: u32, update: types::InstrumentUpdate) -> Result<types::Instrument, String> {
+    utils::check_caller(&seller)?;
+    utils::update_instrument(seller, id, update)
+}
+
+#[ic_cdk::update]
+pub fn make_offer(buyer: String, instrument_id: u32, price: types::Price, message: String) -> Result<types::Offer, String> {
+    utils::check_caller(&buyer)?;
+    utils::make_offer(buyer, instrument_id, price, message)
+}
+
+#[ic_cdk::update]
+pub fn respond_to_offer(principal: String, instrument_id: u32, offer_id: u64, response: types::OfferResponse, message: Option<String>) -> Result<types::Offer, String> {
+    utils::check_caller(&principal)?;
+    utils::respond_to_offer(principal, instrument_id, offer_id, response, message)
+}
+
+#[ic_cdk::update]
+pub fn withdraw_offer(principal: String, instrument_id: u32, offer_id: u64) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::withdraw_offer(principal, instrument_id, offer_id)
+}
+
+#[ic_cdk::query]
+pub fn get_offers(principal: String, instrument_id: u32) -> Result<Vec<types::Offer>, String> {
+    utils::check_caller(&principal)?;
+    utils::get_offers(principal, instrument_id)
+}
+
+#[ic_cdk::query]
+pub fn get_instrument_facets(sub_name: String, filter: Option<types::InstrumentFilter>) -> types::InstrumentFacets {
+    utils::get_instrument_facets(sub_name.as_str(), filter)
+}
+
+#[ic_cdk::update]
+pub fn set_listing_status(seller_principal: String, id: u32, status: types::ListingStatus) -> Result<(), String> {
+    utils::check_caller(&seller_principal)?;
+    utils::set_listing_status(seller_principal, id, status)
+}
+
+#[ic_cdk::update]
+pub fn mark_sold(seller_principal: String, id: u32, buyer: Option<String>) -> Result<(), String> {
+    utils::check_caller(&seller_principal)?;
+    utils::mark_sold(seller_principal, id, buyer)
+}
+
+#[ic_cdk::query]
+pub fn get_my_instruments(seller_principal: String, status: Option<types::ListingStatus>, page_num: i32) -> (Vec<types::Instrument>, i32) {
+    if utils::check_caller(&seller_principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::get_my_instruments(seller_principal, status, page_num)
+}
+
+#[ic_cdk::query]
+pub fn get_instruments_near(latitude: f64, longitude: f64, radius_km: f64, page_num: i32) -> (Vec<types::NearbyInstrument>, i32) {
+    utils::get_instruments_near(latitude, longitude, radius_km, page_num)
 }
 
 #[ic_cdk::update]
@@ -200,6 +520,9 @@ pub fn add_post_to_forum(
     comment: String,
     photos: Option<Vec<Vec<u8>>>
 ) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
     utils::add_post_to_forum(forum_id, username, principal, comment, photos)
 }
 
@@ -217,10 +540,167 @@ pub fn add_forum(
 
 #[ic_cdk::query] 
 pub fn get_forum_posts(forum_id: u64, page_num: i32) -> (Vec<ForumData>, i32) {
-    utils::get_forum_posts(forum_id, page_num).expect("REASON")
+    utils::get_forum_posts(forum_id, page_num, utils::caller_principal()).expect("REASON")
 }
 
 #[ic_cdk::query] 
 pub fn get_forum_posts_without_photos(forum_id: u64, page_num: i32) -> (Vec<ForumData>, i32) {
-    utils::get_forum_posts_without_photos(forum_id, page_num)
+    utils::get_forum_posts_without_photos(forum_id, page_num, utils::caller_principal())
+}
+
+
+#[ic_cdk::update]
+pub fn start_recording_upload(
+    principal: String,
+    username: String,
+    tune_title: String,
+    title: String,
+    mime_type: String,
+    size: u64,
+) -> Result<u64, String> {
+    utils::check_caller(&principal)?;
+    utils::start_recording_upload(principal, username, tune_title, title, mime_type, size)
+}
+
+#[ic_cdk::update]
+pub fn upload_recording_chunk(recording_id: u64, principal: String, chunk_index: u32, data: Vec<u8>) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::upload_recording_chunk(recording_id, principal, chunk_index, data)
+}
+
+#[ic_cdk::update]
+pub fn finish_recording_upload(recording_id: u64, principal: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::finish_recording_upload(recording_id, principal)
+}
+
+#[ic_cdk::update]
+pub fn delete_recording(recording_id: u64, principal: String) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::delete_recording(recording_id, principal)
+}
+
+#[ic_cdk::query]
+pub fn get_recording(recording_id: u64) -> Option<types::Recording> {
+    utils::get_recording(recording_id)
+}
+
+#[ic_cdk::query]
+pub fn get_recording_chunk(recording_id: u64, chunk_index: u32) -> Option<Vec<u8>> {
+    utils::get_recording_chunk(recording_id, chunk_index)
+}
+
+#[ic_cdk::query]
+pub fn get_tune_recordings(tune_title: String, page_num: i32) -> (Vec<types::Recording>, i32) {
+    utils::get_tune_recordings(tune_title, page_num)
+}
+
+
+#[ic_cdk::update]
+pub fn send_message(sender: String, recipient: String, body: String, instrument_id: Option<u32>) -> Result<types::Message, String> {
+    utils::check_caller(&sender)?;
+    utils::send_message(sender, recipient, body, instrument_id)
+}
+
+#[ic_cdk::query]
+pub fn get_conversations(principal: String, page_num: i32) -> (Vec<types::ConversationSummary>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::get_conversations(principal, page_num)
+}
+
+#[ic_cdk::query]
+pub fn get_messages(conversation_id: u64, principal: String, page_num: i32) -> Result<(Vec<types::Message>, i32), String> {
+    utils::check_caller(&principal)?;
+    utils::get_messages(conversation_id, principal, page_num)
+}
+
+#[ic_cdk::update]
+pub fn mark_conversation_read(conversation_id: u64, principal: String, message_id: u64) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::mark_conversation_read(conversation_id, principal, message_id)
+}
+
+
+#[ic_cdk::query]
+pub fn get_notifications(principal: String, unread_only: bool, page_num: i32) -> (Vec<types::Notification>, i32) {
+    if utils::check_caller(&principal).is_err() {
+        return (vec![], 0);
+    }
+    utils::get_notifications(principal, unread_only, page_num)
+}
+
+#[ic_cdk::query]
+pub fn get_unread_notification_count(principal: String) -> u32 {
+    if utils::check_caller(&principal).is_err() {
+        return 0;
+    }
+    utils::get_unread_notification_count(principal)
+}
+
+#[ic_cdk::update]
+pub fn mark_notifications_read(principal: String, ids: Option<Vec<u64>>) -> u32 {
+    if utils::check_caller(&principal).is_err() {
+        return 0;
+    }
+    utils::mark_notifications_read(principal, ids)
+}
+
+
+#[ic_cdk::query]
+fn http_request(req: types::HttpRequest) -> types::HttpResponse {
+    http::http_request(req)
+}
+
+#[ic_cdk::query]
+fn http_request_streaming_callback(token: types::StreamingCallbackToken) -> types::StreamingCallbackHttpResponse {
+    http::http_request_streaming_callback(token)
+}
+
+
+#[ic_cdk::update]
+pub fn start_blob_upload(principal: String, content_type: String, size: u64) -> Result<u64, String> {
+    utils::check_caller(&principal)?;
+    utils::start_blob_upload(principal, content_type, size)
+}
+
+#[ic_cdk::update]
+pub fn upload_blob_chunk(upload_id: u64, principal: String, chunk_index: u32, data: Vec<u8>) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::upload_blob_chunk(upload_id, principal, chunk_index, data)
+}
+
+#[ic_cdk::update]
+pub fn finish_blob_upload(upload_id: u64, principal: String) -> Result<String, String> {
+    utils::check_caller(&principal)?;
+    utils::finish_blob_upload(upload_id, principal)
+}
+
+#[ic_cdk::query]
+pub fn get_blob_info(blob_id: String) -> Option<types::BlobInfo> {
+    utils::get_blob_info(blob_id)
+}
+
+#[ic_cdk::query]
+pub fn get_blob_chunk(blob_id: String, chunk_index: u32) -> Option<Vec<u8>> {
+    utils::get_blob_chunk(blob_id, chunk_index)
+}
+
+#[ic_cdk::update]
+pub fn set_avatar(principal: String, blob_id: Option<String>) -> bool {
+    if utils::check_caller(&principal).is_err() {
+        return false;
+    }
+    utils::set_avatar(principal, blob_id)
 }
@@ -0,0 +1,573 @@
+// Renders ABC notation into a single track Standard MIDI File so that tunes
+// can be played back directly from the HTTP gateway. Only the subset of ABC
+// that appears in the tune database is supported: header fields, key
+// signatures and modes, accidentals, note lengths, broken rhythm, tuplets,
+// ties, chords and simple repeats with first and second endings. Anything
+// else (decorations, grace notes, chord symbols, lyrics) is skipped.
+
+const TICKS_PER_QUARTER: u64 = 480;
+const TICKS_PER_WHOLE: u64 = TICKS_PER_QUARTER * 4;
+const VELOCITY: u8 = 80;
+const PROGRAM_VIOLIN: u8 = 40;
+// Largest delta time a MIDI variable length quantity can hold
+const MAX_TICKS: u64 = 0x0FFF_FFFF;
+const MAX_MICROS_PER_QUARTER: u64 = 0xFF_FFFF;
+
+
+struct Event {
+    notes: Vec<u8>,
+    ticks: u64,
+}
+
+
+struct Parser {
+    unit: Option<(u64, u64)>,
+    meter: (u64, u64),
+    micros_per_quarter: u32,
+    title: Option<String>,
+    key: [i8; 7],
+    bar_accidentals: Vec<(u8, i8)>,
+    events: Vec<Event>,
+    repeat_start: usize,
+    first_ending_start: Option<usize>,
+    broken_factor: Option<(u64, u64)>,
+    tuplet: Option<(u64, u64, u32)>,
+    tie: bool,
+}
+
+
+pub fn abc_to_midi(abc: &str) -> Vec<u8> {
+    parse(abc).render()
+}
+
+
+fn parse(abc: &str) -> Parser {
+    let mut parser = Parser {
+        unit: None,
+        meter: (4, 4),
+        micros_per_quarter: 500_000,
+        title: None,
+        key: [0; 7],
+        bar_accidentals: vec![],
+        events: vec![],
+        repeat_start: 0,
+        first_ending_start: None,
+        broken_factor: None,
+        tuplet: None,
+        tie: false,
+    };
+
+    for line in abc.lines() {
+        let line = line.split('%').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if is_field(line) {
+            parser.field(&line[..1], line[2..].trim());
+        } else {
+            parser.music(line);
+        }
+    }
+    parser
+}
+
+
+fn is_field(line: &str) -> bool {
+    let bytes = line.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+
+impl Parser {
+    fn field(&mut self, name: &str, value: &str) {
+        match name {
+            "T" if self.title.is_none() => self.title = Some(value.to_string()),
+            "M" => self.meter = parse_meter(value),
+            "L" => self.unit = parse_fraction(value),
+            "Q" => {
+                if let Some(micros) = parse_tempo(value, self.unit_length()) {
+                    self.micros_per_quarter = micros;
+                }
+            }
+            "K" => self.key = key_signature(value),
+            _ => {}
+        }
+    }
+
+    // Default unit note length is 1/16 for meters below 3/4 and 1/8 otherwise
+    fn unit_length(&self) -> (u64, u64) {
+        let (beats, beat) = self.meter;
+        self.unit.unwrap_or(if beats.saturating_mul(4) < beat.saturating_mul(3) { (1, 16) } else { (1, 8) })
+    }
+
+    fn music(&mut self, line: &str) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                '"' => i = skip_past(&chars, i + 1, '"'),
+                '!' => i = skip_past(&chars, i + 1, '!'),
+                '+' => i = skip_past(&chars, i + 1, '+'),
+                '{' => i = skip_past(&chars, i + 1, '}'),
+                '[' if chars.get(i + 1).is_some_and(|next| next.is_ascii_digit()) => {
+                    self.ending(chars[i + 1]);
+                    i += 2;
+                }
+                '[' if chars.get(i + 1).is_some_and(|next| next.is_ascii_alphabetic())
+                    && chars.get(i + 2) == Some(&':') =>
+                {
+                    let end = skip_past(&chars, i + 1, ']');
+                    let field: String = chars[i + 1..end.saturating_sub(1).max(i + 1)].iter().collect();
+                    if field.len() >= 2 {
+                        self.field(&field[..1], field[2..].trim());
+                    }
+                    i = end;
+                }
+                '[' => i = self.chord(&chars, i + 1),
+                '|' => {
+                    self.bar_accidentals.clear();
+                    match chars.get(i + 1) {
+                        Some(':') => {
+                            self.repeat_start = self.events.len();
+                            self.first_ending_start = None;
+                            i += 2;
+                        }
+                        Some(']') | Some('|') => {
+                            self.repeat_start = self.events.len();
+                            i += 2;
+                        }
+                        Some(digit) if digit.is_ascii_digit() => {
+                            self.ending(*digit);
+                            i += 2;
+                        }
+                        _ => i += 1,
+                    }
+                }
+                ':' => {
+                    self.bar_accidentals.clear();
+                    self.repeat();
+                    i += 1;
+                    while i < chars.len() && (chars[i] == '|' || chars[i] == ':') {
+                        i += 1;
+                    }
+                    if chars.get(i).is_some_and(|next| next.is_ascii_digit()) {
+                        self.ending(chars[i]);
+                        i += 1;
+                    }
+                }
+                '(' if chars.get(i + 1).is_some_and(|next| next.is_ascii_digit()) => {
+                    // (2 to (9 only, anything else is not a tuplet
+                    let p = chars[i + 1].to_digit(10).unwrap_or_default() as u64;
+                    if (2..=9).contains(&p) {
+                        let q = match p {
+                            2 | 4 | 8 => 3,
+                            _ => 2,
+                        };
+                        self.tuplet = Some((q, p, p as u32));
+                    }
+                    i += 2;
+                }
+                '>' | '<' => {
+                    let mut count = 1;
+                    while chars.get(i + count) == Some(&c) {
+                        count += 1;
+                    }
+                    let shortened = 1u64 << count.min(8);
+                    let (longer, shorter) = ((2 * shortened - 1, shortened), (1, shortened));
+                    let (previous, next) = if c == '>' { (longer, shorter) } else { (shorter, longer) };
+                    if let Some(event) = self.events.last_mut() {
+                        event.ticks = scale(event.ticks, previous.0, previous.1);
+                    }
+                    self.broken_factor = Some(next);
+                    i += count;
+                }
+                '-' => {
+                    self.tie = true;
+                    i += 1;
+                }
+                _ => {
+                    if let Some((notes, base, next)) = self.note(&chars, i) {
+                        let (num, den, after) = parse_length(&chars, next);
+                        self.push(notes, scale(base, num, den));
+                        i = after;
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Parses a single note or rest starting at `start`. Returns the pitches
+    // (empty for rests), the duration in ticks for one unit note length and
+    // the index just past the note.
+    fn note(&mut self, chars: &[char], start: usize) -> Option<(Vec<u8>, u64, usize)> {
+        let (unit_num, unit_den) = self.unit_length();
+        let base = scale(TICKS_PER_WHOLE, unit_num, unit_den);
+        let mut i = start;
+
+        let mut accidental: Option<i8> = None;
+        while let Some(c) = chars.get(i) {
+            match c {
+                '^' => accidental = Some(accidental.unwrap_or(0).max(0) + 1),
+                '_' => accidental = Some(accidental.unwrap_or(0).min(0) - 1),
+                '=' => accidental = Some(0),
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let letter = *chars.get(i)?;
+        if (letter == 'z' || letter == 'x') && accidental.is_none() {
+            return Some((vec![], base, i + 1));
+        }
+        let (step, mut octave) = match letter {
+            'C'..='G' | 'A' | 'B' => (letter, 5i32),
+            'c'..='g' | 'a' | 'b' => (letter.to_ascii_uppercase(), 6i32),
+            _ => return None,
+        };
+        i += 1;
+
+        while let Some(c) = chars.get(i) {
+            match c {
+                '\'' => octave += 1,
+                ',' => octave -= 1,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let index = "CDEFGAB".find(step)?;
+        let semitone = [0, 2, 4, 5, 7, 9, 11][index];
+        let pitch_class = (octave * 12 + semitone).clamp(0, 127) as u8;
+
+        let shift = match accidental {
+            Some(shift) => {
+                self.bar_accidentals.retain(|(pitch, _)| *pitch != pitch_class);
+                self.bar_accidentals.push((pitch_class, shift));
+                shift
+            }
+            None => self
+                .bar_accidentals
+                .iter()
+                .find(|(pitch, _)| *pitch == pitch_class)
+                .map(|(_, shift)| *shift)
+                .unwrap_or(self.key[index]),
+        };
+
+        let pitch = (pitch_class as i32 + shift as i32).clamp(0, 127) as u8;
+        Some((vec![pitch], base, i))
+    }
+
+    fn chord(&mut self, chars: &[char], start: usize) -> usize {
+        let mut i = start;
+        let mut pitches = vec![];
+        let mut duration = None;
+
+        while i < chars.len() && chars[i] != ']' {
+            if let Some((notes, base, next)) = self.note(chars, i) {
+                let (num, den, after) = parse_length(chars, next);
+                duration.get_or_insert(scale(base, num, den));
+                pitches.extend(notes);
+                i = after;
+            } else {
+                i += 1;
+            }
+        }
+
+        let (num, den, after) = parse_length(chars, i + 1);
+        if let Some(duration) = duration {
+            self.push(pitches, scale(duration, num, den));
+        }
+        after
+    }
+
+    fn push(&mut self, notes: Vec<u8>, mut ticks: u64) {
+        if let Some((num, den)) = self.broken_factor.take() {
+            ticks = scale(ticks, num, den);
+        }
+        if let Some((num, den, remaining)) = self.tuplet {
+            ticks = scale(ticks, num, den);
+            self.tuplet = if remaining > 1 { Some((num, den, remaining - 1)) } else { None };
+        }
+
+        if self.tie {
+            self.tie = false;
+            if let Some(previous) = self.events.last_mut() {
+                if previous.notes == notes {
+                    previous.ticks = (previous.ticks + ticks).min(MAX_TICKS);
+                    return;
+                }
+            }
+        }
+        self.events.push(Event { notes, ticks });
+    }
+
+    fn ending(&mut self, number: char) {
+        if number == '1' {
+            self.first_ending_start = Some(self.events.len());
+        }
+    }
+
+    // Replays the repeated section, leaving out the first ending
+    fn repeat(&mut self) {
+        let end = self.first_ending_start.take().unwrap_or(self.events.len());
+        let start = self.repeat_start.min(end);
+        let replay: Vec<Event> = self.events[start..end]
+            .iter()
+            .map(|event| Event {
+                notes: event.notes.clone(),
+                ticks: event.ticks,
+            })
+            .collect();
+        self.events.extend(replay);
+        self.repeat_start = self.events.len();
+    }
+
+    fn render(&self) -> Vec<u8> {
+        let mut track = vec![];
+
+        if let Some(ref title) = self.title {
+            track.extend([0x00, 0xFF, 0x03]);
+            write_var_len(&mut track, title.len() as u64);
+            track.extend(title.as_bytes());
+        }
+
+        let tempo = self.micros_per_quarter.to_be_bytes();
+        track.extend([0x00, 0xFF, 0x51, 0x03, tempo[1], tempo[2], tempo[3]]);
+
+        let denominator_power = (self.meter.1.max(1) as f64).log2() as u8;
+        track.extend([0x00, 0xFF, 0x58, 0x04, self.meter.0 as u8, denominator_power, 24, 8]);
+        track.extend([0x00, 0xC0, PROGRAM_VIOLIN]);
+
+        let mut delta = 0;
+        for event in self.events.iter() {
+            if event.notes.is_empty() {
+                delta = (delta + event.ticks).min(MAX_TICKS);
+                continue;
+            }
+            for note in event.notes.iter() {
+                write_var_len(&mut track, delta);
+                track.extend([0x90, *note, VELOCITY]);
+                delta = 0;
+            }
+            for (index, note) in event.notes.iter().enumerate() {
+                write_var_len(&mut track, if index == 0 { event.ticks } else { 0 });
+                track.extend([0x80, *note, 0]);
+            }
+        }
+        track.extend([0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut file = vec![];
+        file.extend(b"MThd");
+        file.extend(6u32.to_be_bytes());
+        file.extend(0u16.to_be_bytes()); // Format 0: a single track
+        file.extend(1u16.to_be_bytes());
+        file.extend((TICKS_PER_QUARTER as u16).to_be_bytes());
+        file.extend(b"MTrk");
+        file.extend((track.len() as u32).to_be_bytes());
+        file.extend(track);
+        file
+    }
+}
+
+
+fn skip_past(chars: &[char], start: usize, end: char) -> usize {
+    chars[start.min(chars.len())..]
+        .iter()
+        .position(|c| *c == end)
+        .map(|offset| start + offset + 1)
+        .unwrap_or(chars.len())
+}
+
+
+// `ticks * num / den`, clamped to what a MIDI event can hold
+fn scale(ticks: u64, num: u64, den: u64) -> u64 {
+    (ticks.saturating_mul(num) / den.max(1)).min(MAX_TICKS)
+}
+
+
+// Parses a length suffix such as `2`, `3/2`, `/2`, `/` or `//`. Absurd
+// lengths saturate rather than overflow.
+fn parse_length(chars: &[char], start: usize) -> (u64, u64, usize) {
+    let mut i = start;
+    let mut num = 0u64;
+    while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+        num = num.saturating_mul(10).saturating_add(digit as u64);
+        i += 1;
+    }
+    let num = num.max(1);
+
+    let mut den = 1u64;
+    while chars.get(i) == Some(&'/') {
+        i += 1;
+        let mut value = 0u64;
+        while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+            value = value.saturating_mul(10).saturating_add(digit as u64);
+            i += 1;
+        }
+        den = den.saturating_mul(if value == 0 { 2 } else { value });
+    }
+
+    (num, den, i)
+}
+
+
+fn parse_fraction(value: &str) -> Option<(u64, u64)> {
+    let (num, den) = value.trim().split_once('/')?;
+    let num = num.trim().parse::<u64>().ok()?;
+    let den = den.trim().parse::<u64>().ok()?;
+    if num == 0 || den == 0 {
+        return None;
+    }
+    Some((num, den))
+}
+
+
+fn parse_meter(value: &str) -> (u64, u64) {
+    match value.trim() {
+        "C" => (4, 4),
+        "C|" => (2, 2),
+        other => parse_fraction(other).unwrap_or((4, 4)),
+    }
+}
+
+
+// Accepts `1/4=120`, `"Allegro" 3/8=40` and the legacy bare `120`, which
+// counts beats of the unit note length.
+fn parse_tempo(value: &str, unit: (u64, u64)) -> Option<u32> {
+    let value = value.rsplit('"').next().unwrap_or(value).trim();
+    let ((beat_num, beat_den), bpm) = match value.split_once('=') {
+        Some((beat, bpm)) => (parse_fraction(beat)?, bpm.trim().parse::<u64>().ok()?),
+        None => (unit, value.parse::<u64>().ok()?),
+    };
+    let micros = 15_000_000u64.checked_mul(beat_den)? / beat_num.checked_mul(bpm).filter(|beats| *beats > 0)?;
+    (1..=MAX_MICROS_PER_QUARTER).contains(&micros).then_some(micros as u32)
+}
+
+
+// Returns the sharps (+1) and flats (-1) applied to C, D, E, F, G, A and B
+// for a key such as `D`, `Gmaj`, `Ador`, `Em`, `F#m` or `Bbmix`.
+fn key_signature(value: &str) -> [i8; 7] {
+    let value = value.split_whitespace().next().unwrap_or("C");
+    if value.eq_ignore_ascii_case("none") || value.starts_with("HP") || value.starts_with("Hp") {
+        return [0; 7];
+    }
+
+    let mut chars = value.chars();
+    let tonic = chars.next().unwrap_or('C').to_ascii_uppercase();
+    let rest: String = chars.collect();
+    let (tonic_shift, mode) = if let Some(mode) = rest.strip_prefix('#') {
+        (1, mode)
+    } else if let Some(mode) = rest.strip_prefix('b') {
+        (-1, mode)
+    } else {
+        (0, rest.as_str())
+    };
+
+    let base_fifths: i32 = match tonic {
+        'C' => 0,
+        'G' => 1,
+        'D' => 2,
+        'A' => 3,
+        'E' => 4,
+        'B' => 5,
+        'F' => -1,
+        _ => 0,
+    };
+    let mode = mode.to_lowercase();
+    let mode_offset = match mode.get(..3).unwrap_or(mode.as_str()) {
+        "" | "maj" | "ion" => 0,
+        "mix" => -1,
+        "dor" => -2,
+        "m" | "min" | "aeo" => -3,
+        "phr" => -4,
+        "loc" => -5,
+        "lyd" => 1,
+        _ if mode.starts_with('m') => -3,
+        _ => 0,
+    };
+    let fifths = base_fifths + tonic_shift * 7 + mode_offset;
+
+    let mut key = [0i8; 7];
+    let sharps = [3, 0, 4, 1, 5, 2, 6]; // F C G D A E B
+    let flats = [6, 2, 5, 1, 4, 0, 3]; // B E A D G C F
+    if fifths > 0 {
+        for index in sharps.iter().take(fifths.min(7) as usize) {
+            key[*index] = 1;
+        }
+    } else {
+        for index in flats.iter().take((-fifths).min(7) as usize) {
+            key[*index] = -1;
+        }
+    }
+    key
+}
+
+
+fn write_var_len(buffer: &mut Vec<u8>, mut value: u64) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push(((value & 0x7F) as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    buffer.extend(bytes);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Durations of the notes of a single line of music in 4/4, where the
+    // unit note length is an eighth (240 ticks)
+    fn durations(music: &str) -> Vec<u64> {
+        parse(&format!("X:1\nM:4/4\nK:D\n{}", music))
+            .events
+            .iter()
+            .map(|event| event.ticks)
+            .collect()
+    }
+
+    #[test]
+    fn triplets_take_the_time_of_two() {
+        assert_eq!(durations("(3ABc d"), vec![160, 160, 160, 240]);
+        assert_eq!(durations("(2AB c"), vec![360, 360, 240]);
+    }
+
+    #[test]
+    fn tuplet_digits_outside_two_to_nine_are_ignored() {
+        assert_eq!(durations("(0ABc"), vec![240, 240, 240]);
+        assert_eq!(durations("(1AB"), vec![240, 240]);
+        assert!(!abc_to_midi("X:1\nK:D\n(0ABc|").is_empty());
+    }
+
+    #[test]
+    fn broken_rhythm_moves_time_between_notes() {
+        assert_eq!(durations("A>B"), vec![360, 120]);
+        assert_eq!(durations("A<B"), vec![120, 360]);
+        assert_eq!(durations("A>>B"), vec![420, 60]);
+        assert_eq!(durations("A2>B2"), vec![720, 240]);
+    }
+
+    #[test]
+    fn ties_join_notes_of_the_same_pitch() {
+        assert_eq!(durations("A2-A B"), vec![720, 240]);
+        assert_eq!(durations("A-B"), vec![240, 240]);
+    }
+
+    #[test]
+    fn lengths_and_tempos_never_overflow() {
+        assert_eq!(durations("A99999999999999999999999"), vec![MAX_TICKS]);
+        assert_eq!(durations(&format!("A{}", "/".repeat(100))), vec![0]);
+        assert_eq!(durations(&format!("A{}>B", ">".repeat(100))).len(), 2);
+        assert_eq!(parse_tempo("1/4=120", (1, 8)), Some(500_000));
+        assert_eq!(parse_tempo("99999999999999999999/1=1", (1, 8)), None);
+        assert_eq!(parse_tempo("1/99999999999999999=1", (1, 8)), None);
+        assert_eq!(parse_tempo("1/4=0", (1, 8)), None);
+    }
+}
@@ -0,0 +1,225 @@
+// Structured listing prices, and parsing of the free text prices older
+// listings were created with.
+
+use crate::types::Price;
+use regex::Regex;
+
+
+// Supported ISO 4217 currencies with the number of digits of their minor unit
+const CURRENCIES: &[(&str, u32)] = &[
+    ("EUR", 2),
+    ("GBP", 2),
+    ("USD", 2),
+    ("CAD", 2),
+    ("AUD", 2),
+    ("NZD", 2),
+    ("CHF", 2),
+    ("SEK", 2),
+    ("NOK", 2),
+    ("DKK", 2),
+    ("PLN", 2),
+    ("CZK", 2),
+    ("JPY", 0),
+];
+
+// Amounts above this are certainly typos, in any currency
+const MAX_MAJOR_AMOUNT: u64 = 10_000_000;
+
+thread_local! {
+    static AMOUNT_PATTERN: Regex = Regex::new(r"(\d{1,3}(?:[.,]\d{3})+|\d+)(?:[.,](\d{1,2}))?(?:\s*(k)\b)?").unwrap();
+    static NEGOTIABLE_PATTERN: Regex =
+        Regex::new(r"\b(ono|o\.n\.o|obo|o\.b\.o|neg|negotiable|or (nearest|best) offers?)\b").unwrap();
+}
+
+
+pub fn minor_unit_digits(currency: &str) -> Option<u32> {
+    CURRENCIES
+        .iter()
+        .find(|(code, _)| *code == currency)
+        .map(|(_, digits)| *digits)
+}
+
+
+pub fn validate(price: &Price) -> Result<(), String> {
+    let digits = minor_unit_digits(&price.currency).ok_or(format!("Unsupported currency: {}", price.currency))?;
+    if price.amount > MAX_MAJOR_AMOUNT * 10u64.pow(digits) {
+        return Err("Price is too high".to_string());
+    }
+    Ok(())
+}
+
+
+// Currency symbols and names people write, longest first so that "CA$"
+// wins over "$"
+const CURRENCY_NAMES: &[(&str, &str)] = &[
+    ("nz$", "NZD"),
+    ("ca$", "CAD"),
+    ("au$", "AUD"),
+    ("c$", "CAD"),
+    ("a$", "AUD"),
+    ("€", "EUR"),
+    ("£", "GBP"),
+    ("$", "USD"),
+    ("¥", "JPY"),
+    ("euros", "EUR"),
+    ("euro", "EUR"),
+    ("pounds", "GBP"),
+    ("pound", "GBP"),
+    ("sterling", "GBP"),
+    ("quid", "GBP"),
+    ("dollars", "USD"),
+    ("dollar", "USD"),
+    ("yen", "JPY"),
+];
+
+
+fn parse_currency(text: &str) -> Option<&'static str> {
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if let Some((code, _)) = CURRENCIES
+        .iter()
+        .find(|(code, _)| words.iter().any(|word| word.eq_ignore_ascii_case(code)))
+    {
+        return Some(code);
+    }
+
+    CURRENCY_NAMES
+        .iter()
+        .find(|(name, _)| {
+            if name.chars().all(char::is_alphabetic) {
+                words.iter().any(|word| word == name)
+            } else {
+                text.contains(name)
+            }
+        })
+        .map(|(_, code)| *code)
+}
+
+
+// Currency written right before or right after an amount, as in "€850",
+// "850 EUR" or "1.200 euro"
+fn adjacent_currency(before: &str, after: &str) -> Option<&'static str> {
+    let before = before.trim_end();
+    let after = after.trim_start();
+    let is_word = |name: &str| name.chars().all(char::is_alphabetic);
+    // Names have to be whole words, "neuro" doesn't end in euro
+    let ends_with = |name: &str| {
+        before.strip_suffix(name).is_some_and(|rest| !is_word(name) || !rest.ends_with(char::is_alphabetic))
+    };
+    let starts_with = |name: &str| {
+        after.strip_prefix(name).is_some_and(|rest| !is_word(name) || !rest.starts_with(char::is_alphabetic))
+    };
+
+    let codes = CURRENCIES.iter().map(|(code, _)| (code.to_lowercase(), *code));
+    let names = CURRENCY_NAMES.iter().map(|(name, code)| (name.to_string(), *code));
+    codes
+        .chain(names)
+        .find(|(name, _)| ends_with(name) || starts_with(name))
+        .map(|(_, code)| code)
+}
+
+
+// Understands prices such as "€850", "£1,250.00 ono", "1.200 euro",
+// "2.5k negotiable" or "850". Prices without a currency are assumed to be in
+// `default_currency`. When the text has more than one number, the one next
+// to a currency is the price ("Fiddle 1920, €850" is €850). Returns None when
+// the text has no amount, e.g. "POA" or "swap", or when it can't tell which
+// number is the price.
+pub fn parse(text: &str, default_currency: &str) -> Option<Price> {
+    let text = text.trim().to_lowercase();
+    let amounts: Vec<regex::Captures> = AMOUNT_PATTERN.with(|pattern| pattern.captures_iter(&text).collect());
+
+    let mut priced = amounts.iter().filter_map(|captures| {
+        let amount = captures.get(0)?;
+        adjacent_currency(&text[..amount.start()], &text[amount.end()..]).map(|currency| (captures, currency))
+    });
+    let (captures, currency) = match (priced.next(), priced.next(), amounts.as_slice()) {
+        (Some(priced), None, _) => priced,
+        (None, _, [captures]) => (captures, parse_currency(&text).unwrap_or(default_currency)),
+        _ => return None,
+    };
+
+    let whole: u64 = captures[1].replace([',', '.'], "").parse().ok()?;
+    let fraction = captures.get(2).map(|fraction| fraction.as_str()).unwrap_or("");
+    let thousands = captures.get(3).is_some();
+
+    let digits = minor_unit_digits(currency)?;
+    let scale = 10u64.pow(digits);
+
+    let amount = if thousands {
+        // "2.5k" is 2500, the fraction being a fraction of a thousand
+        let fraction_value: u64 = format!("{:0<3}", fraction).parse().ok()?;
+        whole.checked_mul(1000)?.checked_add(fraction_value)?.checked_mul(scale)?
+    } else {
+        let fraction_value: u64 = format!("{:0<width$}", fraction, width = digits as usize)
+            .chars()
+            .take(digits as usize)
+            .collect::<String>()
+            .parse()
+            .unwrap_or(0);
+        whole.checked_mul(scale)?.checked_add(fraction_value)?
+    };
+
+    let price = Price {
+        amount,
+        currency: currency.to_string(),
+        negotiable: NEGOTIABLE_PATTERN.with(|pattern| pattern.is_match(&text)),
+    };
+    validate(&price).ok().map(|_| price)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(amount: u64, currency: &str, negotiable: bool) -> Option<Price> {
+        Some(Price {
+            amount,
+            currency: currency.to_string(),
+            negotiable,
+        })
+    }
+
+    #[test]
+    fn understands_the_usual_ways_of_writing_a_price() {
+        assert_eq!(parse("€850", "EUR"), price(85_000, "EUR", false));
+        assert_eq!(parse("£1,250.00 ono", "EUR"), price(125_000, "GBP", true));
+        assert_eq!(parse("1.200 euro", "GBP"), price(120_000, "EUR", false));
+        assert_eq!(parse("2.5k negotiable", "EUR"), price(250_000, "EUR", true));
+        assert_eq!(parse("850", "EUR"), price(85_000, "EUR", false));
+        assert_eq!(parse("CA$1,200", "EUR"), price(120_000, "CAD", false));
+        assert_eq!(parse("5000 yen", "EUR"), price(5_000, "JPY", false));
+        assert_eq!(parse("850 or best offer, in sterling", "EUR"), price(85_000, "GBP", true));
+        assert_eq!(parse("Neuro 850", "GBP"), price(85_000, "GBP", false));
+    }
+
+    #[test]
+    fn texts_without_an_amount_have_no_price() {
+        assert_eq!(parse("POA", "EUR"), None);
+        assert_eq!(parse("swap", "EUR"), None);
+        assert_eq!(parse("", "EUR"), None);
+    }
+
+    #[test]
+    fn the_number_next_to_a_currency_is_the_price() {
+        assert_eq!(parse("Fiddle 1920, €850", "EUR"), price(85_000, "EUR", false));
+        assert_eq!(parse("Made in 1890, 850 EUR ono", "GBP"), price(85_000, "EUR", true));
+        assert_eq!(parse("2 bows, case and fiddle for £600", "EUR"), price(60_000, "GBP", false));
+    }
+
+    #[test]
+    fn ambiguous_texts_have_no_price() {
+        assert_eq!(parse("Fiddle 1920, 850", "EUR"), None);
+        assert_eq!(parse("€850 or £700 with the case", "EUR"), None);
+    }
+
+    #[test]
+    fn out_of_range_prices_are_rejected() {
+        assert_eq!(parse("€99999999999", "EUR"), None);
+        assert_eq!(parse("99999999999999999999999", "EUR"), None);
+        assert_eq!(parse("850 XYZ", "ZZZ"), None);
+    }
+}
@@ -0,0 +1,518 @@
+// Expansion of structured session schedules into concrete occurrences, and a
+// best effort parser for the free text "daytime" and "recurring" fields that
+// sessions had before schedules existed.
+
+use crate::types::{Frequency, Recurrence, SessionSchedule, Weekday};
+use chrono::{DateTime, Datelike, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+
+
+pub const MAX_OCCURRENCES: usize = 1000;
+const MAX_DURATION_MINUTES: u32 = 24 * 60;
+const MAX_INTERVAL: u32 = 52;
+const MAX_PERIODS: i64 = 10_000; // Upper bound on the periods looked at per expansion
+const LEGACY_DURATION_MINUTES: u32 = 180;
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+
+
+// Patterns of the legacy parser, compiled once per canister instance
+struct LegacyPatterns {
+    time: Regex,
+    weekday: Regex,
+    ordinal: Regex,
+    daily: Regex,
+    fortnightly: Regex,
+    monthly: Regex,
+    once: Regex,
+}
+
+thread_local! {
+    static LEGACY_PATTERNS: LegacyPatterns = LegacyPatterns {
+        time: Regex::new(r"\b(at\s+)?(\d{1,2})(?:[:.h](\d{2}))?\s*(am|pm|a\.m\.|p\.m\.)?").unwrap(),
+        weekday: Regex::new(r"\b(mon|tue|wed|thu|fri|sat|sun)[a-z]*\b").unwrap(),
+        ordinal: Regex::new(r"\b(first|1st|second|2nd|third|3rd|fourth|4th|last)\b").unwrap(),
+        daily: Regex::new(r"\b(daily|nightly|every (day|night))\b").unwrap(),
+        fortnightly: Regex::new(r"\b(fortnight(ly)?|bi-?weekly|every (other|two|2)( weeks?)?)\b").unwrap(),
+        monthly: Regex::new(r"\b(monthly|every month|once a month)\b").unwrap(),
+        once: Regex::new(r"\b(one[- ]off|once)\b").unwrap(),
+    };
+}
+
+
+pub fn parse_timezone(name: &str) -> Result<Tz, String> {
+    name.parse::<Tz>().map_err(|_| format!("Unknown timezone '{}'", name))
+}
+
+
+pub fn validate(schedule: &SessionSchedule) -> Result<(), String> {
+    parse_timezone(&schedule.timezone)?;
+    if schedule.duration_minutes == 0 || schedule.duration_minutes > MAX_DURATION_MINUTES {
+        return Err(format!("Sessions must last between 1 and {} minutes", MAX_DURATION_MINUTES));
+    }
+    if let Some(recurrence) = &schedule.recurrence {
+        if recurrence.interval == 0 || recurrence.interval > MAX_INTERVAL {
+            return Err(format!("The recurrence interval must be between 1 and {}", MAX_INTERVAL));
+        }
+        if recurrence.frequency == Frequency::Daily && !recurrence.by_day.is_empty() {
+            return Err("Daily recurrences can't be limited to weekdays".to_string());
+        }
+        if let Some(position) = recurrence.by_set_pos {
+            if recurrence.frequency != Frequency::Monthly || recurrence.by_day.is_empty() {
+                return Err("Set positions are only supported for monthly recurrences on given weekdays".to_string());
+            }
+            if position == 0 || !(-5..=5).contains(&position) {
+                return Err("The set position must be between 1 and 5, or -1 to -5 counting from the end".to_string());
+            }
+        }
+        if recurrence.until.is_some_and(|until| until < schedule.start) {
+            return Err("The recurrence ends before the first occurrence".to_string());
+        }
+        if recurrence.count == Some(0) {
+            return Err("The recurrence count must be at least 1".to_string());
+        }
+    }
+    Ok(())
+}
+
+
+fn to_datetime(nanos: u64) -> DateTime<Utc> {
+    Utc.timestamp_nanos(nanos.min(i64::MAX as u64) as i64)
+}
+
+
+fn to_nanos<T: TimeZone>(datetime: &DateTime<T>) -> u64 {
+    datetime.timestamp_nanos_opt().unwrap_or(0).max(0) as u64
+}
+
+
+// Wall clock time to an instant. Times that fall in a daylight saving gap
+// move forward by an hour, repeated times use their first instance.
+fn resolve(timezone: &Tz, local: NaiveDateTime) -> DateTime<Tz> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(datetime) => datetime,
+        LocalResult::Ambiguous(earliest, _) => earliest,
+        LocalResult::None => timezone
+            .from_local_datetime(&(local + Duration::hours(1)))
+            .earliest()
+            .unwrap_or_else(|| timezone.from_utc_datetime(&local)),
+    }
+}
+
+
+fn chrono_weekday(day: Weekday) -> chrono::Weekday {
+    match day {
+        Weekday::Monday => chrono::Weekday::Mon,
+        Weekday::Tuesday => chrono::Weekday::Tue,
+        Weekday::Wednesday => chrono::Weekday::Wed,
+        Weekday::Thursday => chrono::Weekday::Thu,
+        Weekday::Friday => chrono::Weekday::Fri,
+        Weekday::Saturday => chrono::Weekday::Sat,
+        Weekday::Sunday => chrono::Weekday::Sun,
+    }
+}
+
+
+fn months_since_epoch(date: NaiveDate) -> i64 {
+    date.year() as i64 * 12 + date.month0() as i64
+}
+
+
+// Dates of the `period`-th day, week or month of the recurrence, in order
+fn period_dates(recurrence: &Recurrence, first: NaiveDate, period: i64) -> Vec<NaiveDate> {
+    let step = period * recurrence.interval as i64;
+    match recurrence.frequency {
+        Frequency::Daily => first.checked_add_signed(Duration::days(step)).into_iter().collect(),
+        Frequency::Weekly => {
+            let monday = first - Duration::days(first.weekday().num_days_from_monday() as i64);
+            let week = match monday.checked_add_signed(Duration::weeks(step)) {
+                Some(week) => week,
+                None => return vec![],
+            };
+            let mut days: Vec<u32> = if recurrence.by_day.is_empty() {
+                vec![first.weekday().num_days_from_monday()]
+            } else {
+                recurrence.by_day.iter().map(|day| chrono_weekday(*day).num_days_from_monday()).collect()
+            };
+            days.sort();
+            days.dedup();
+            days.into_iter().map(|day| week + Duration::days(day as i64)).collect()
+        }
+        Frequency::Monthly => {
+            let months = months_since_epoch(first) + step;
+            let (year, month) = ((months / 12) as i32, (months % 12) as u32 + 1);
+            if recurrence.by_day.is_empty() {
+                // Months without that day are skipped, as in RFC 5545
+                return NaiveDate::from_ymd_opt(year, month, first.day()).into_iter().collect();
+            }
+            let weekdays: Vec<chrono::Weekday> = recurrence.by_day.iter().map(|day| chrono_weekday(*day)).collect();
+            let dates: Vec<NaiveDate> = (1..=31)
+                .filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+                .filter(|date| weekdays.contains(&date.weekday()))
+                .collect();
+            match recurrence.by_set_pos {
+                Some(position) if position > 0 => dates.get(position as usize - 1).copied().into_iter().collect(),
+                Some(position) => dates
+                    .len()
+                    .checked_sub(position.unsigned_abs() as usize)
+                    .and_then(|index| dates.get(index).copied())
+                    .into_iter()
+                    .collect(),
+                None => dates,
+            }
+        }
+    }
+}
+
+
+// Index of a period shortly before `date`, so that expansions of old
+// schedules don't have to walk through every past occurrence.
+fn period_before(recurrence: &Recurrence, first: NaiveDate, date: NaiveDate) -> i64 {
+    let elapsed = match recurrence.frequency {
+        Frequency::Daily => (date - first).num_days(),
+        Frequency::Weekly => (date - first).num_weeks(),
+        Frequency::Monthly => months_since_epoch(date) - months_since_epoch(first),
+    };
+    (elapsed / recurrence.interval as i64 - 1).max(0)
+}
+
+
+// Start and end of the occurrences overlapping [from, to), at most `limit` of them
+pub fn occurrences(schedule: &SessionSchedule, from: u64, to: u64, limit: usize) -> Vec<(u64, u64)> {
+    let timezone = match parse_timezone(&schedule.timezone) {
+        Ok(timezone) => timezone,
+        Err(_) => return vec![],
+    };
+    let duration = schedule.duration_minutes as u64 * NANOS_PER_MINUTE;
+
+    let recurrence = match &schedule.recurrence {
+        Some(recurrence) if recurrence.interval > 0 => recurrence,
+        _ => {
+            let end = schedule.start + duration;
+            return if schedule.start < to && end > from && limit > 0 {
+                vec![(schedule.start, end)]
+            } else {
+                vec![]
+            };
+        }
+    };
+
+    let first = to_datetime(schedule.start).with_timezone(&timezone).naive_local();
+    let mut period = if recurrence.count.is_none() {
+        let window_start = to_datetime(from.saturating_sub(duration)).with_timezone(&timezone).date_naive();
+        period_before(recurrence, first.date(), window_start)
+    } else {
+        0 // Counted recurrences have to be walked from the start
+    };
+
+    let mut result = vec![];
+    let mut seen: u32 = 0;
+    for _ in 0..MAX_PERIODS {
+        for date in period_dates(recurrence, first.date(), period) {
+            if date < first.date() {
+                continue;
+            }
+            let start = to_nanos(&resolve(&timezone, date.and_time(first.time())));
+            if recurrence.until.is_some_and(|until| start > until) || recurrence.count.is_some_and(|count| seen >= count) {
+                return result;
+            }
+            seen += 1;
+            if start >= to {
+                return result;
+            }
+            if start + duration > from {
+                result.push((start, start + duration));
+                if result.len() >= limit {
+                    return result;
+                }
+            }
+        }
+        period += 1;
+    }
+    result
+}
+
+
+pub fn next_occurrence(schedule: &SessionSchedule, from: u64) -> Option<u64> {
+    occurrences(schedule, from, u64::MAX, 1).first().map(|(start, _)| *start)
+}
+
+
+fn parse_weekday(text: &str) -> Option<Weekday> {
+    let day = match text.get(..3)? {
+        "mon" => Weekday::Monday,
+        "tue" => Weekday::Tuesday,
+        "wed" => Weekday::Wednesday,
+        "thu" => Weekday::Thursday,
+        "fri" => Weekday::Friday,
+        "sat" => Weekday::Saturday,
+        "sun" => Weekday::Sunday,
+        _ => return None,
+    };
+    Some(day)
+}
+
+
+// Finds a time of day such as "9pm", "21:00", "at 9" or "8.30 p.m.". Hours
+// without am/pm before noon are taken as evening times, which is when
+// sessions happen.
+fn parse_time(patterns: &LegacyPatterns, text: &str) -> Option<NaiveTime> {
+    for captures in patterns.time.captures_iter(text) {
+        let minutes = captures.get(3).map(|m| m.as_str().parse::<u32>().unwrap_or(60));
+        let meridiem = captures.get(4).map(|m| m.as_str().starts_with('p'));
+        if captures.get(1).is_none() && minutes.is_none() && meridiem.is_none() {
+            continue; // A plain number, probably not a time
+        }
+        let mut hour: u32 = captures[2].parse().ok()?;
+        match meridiem {
+            Some(true) if hour < 12 => hour += 12,
+            Some(false) if hour == 12 => hour = 0,
+            None if (1..12).contains(&hour) => hour += 12,
+            _ => {}
+        }
+        if let Some(time) = NaiveTime::from_hms_opt(hour, minutes.unwrap_or(0), 0) {
+            return Some(time);
+        }
+    }
+    None
+}
+
+
+// Builds a schedule from the free text fields of a legacy session, starting
+// at the first matching date from `now` on. Returns None when the text
+// doesn't say enough to place the session in a calendar.
+pub fn parse_legacy(daytime: &str, recurring: &str, now: u64, timezone: &str) -> Option<SessionSchedule> {
+    LEGACY_PATTERNS.with(|patterns| parse_legacy_with(patterns, daytime, recurring, now, timezone))
+}
+
+
+fn parse_legacy_with(
+    patterns: &LegacyPatterns,
+    daytime: &str,
+    recurring: &str,
+    now: u64,
+    timezone: &str,
+) -> Option<SessionSchedule> {
+    let tz = parse_timezone(timezone).ok()?;
+    let text = format!("{} {}", daytime, recurring).to_lowercase();
+
+    let weekday = patterns
+        .weekday
+        .captures_iter(&text)
+        .find_map(|captures| parse_weekday(&captures[0]));
+    let time = parse_time(patterns, &text)?;
+
+    let ordinal = patterns
+        .ordinal
+        .captures(&text)
+        .map(|captures| match &captures[1] {
+            "first" | "1st" => 1,
+            "second" | "2nd" => 2,
+            "third" | "3rd" => 3,
+            "fourth" | "4th" => 4,
+            _ => -1,
+        });
+
+    let recurrence = |frequency, interval, by_day: Vec<Weekday>, by_set_pos| Recurrence {
+        frequency,
+        interval,
+        by_day,
+        by_set_pos,
+        until: None,
+        count: None,
+    };
+    let recurrence = if patterns.daily.is_match(&text) {
+        recurrence(Frequency::Daily, 1, vec![], None)
+    } else if let (Some(position), Some(day)) = (ordinal, weekday) {
+        recurrence(Frequency::Monthly, 1, vec![day], Some(position))
+    } else if patterns.fortnightly.is_match(&text) {
+        recurrence(Frequency::Weekly, 2, vec![weekday?], None)
+    } else if patterns.monthly.is_match(&text) {
+        // Without an ordinal the weekday can't be placed within the month
+        return None;
+    } else if patterns.once.is_match(&text) {
+        return None; // No date to anchor a single occurrence to
+    } else {
+        recurrence(Frequency::Weekly, 1, vec![weekday?], None)
+    };
+
+    // First date from today on that matches the rule
+    let today = to_datetime(now).with_timezone(&tz).date_naive();
+    let start_date = (0..400)
+        .map(|offset| today + Duration::days(offset))
+        .find(|date| match recurrence.frequency {
+            Frequency::Daily => true,
+            Frequency::Weekly => Some(date.weekday()) == weekday.map(chrono_weekday),
+            Frequency::Monthly => {
+                let period = months_since_epoch(*date) - months_since_epoch(today);
+                period_dates(&recurrence, today, period).contains(date)
+            }
+        })?;
+
+    Some(SessionSchedule {
+        start: to_nanos(&resolve(&tz, start_date.and_time(time))),
+        duration_minutes: LEGACY_DURATION_MINUTES,
+        timezone: timezone.to_string(),
+        recurrence: Some(recurrence),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUBLIN: &str = "Europe/Dublin";
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> u64 {
+        let local = NaiveDate::from_ymd_opt(year, month, day).unwrap().and_hms_opt(hour, minute, 0).unwrap();
+        to_nanos(&resolve(&parse_timezone(DUBLIN).unwrap(), local))
+    }
+
+    fn rule(frequency: Frequency, interval: u32, by_day: Vec<Weekday>, by_set_pos: Option<i32>) -> Recurrence {
+        Recurrence {
+            frequency,
+            interval,
+            by_day,
+            by_set_pos,
+            until: None,
+            count: None,
+        }
+    }
+
+    fn schedule(start: u64, recurrence: Recurrence) -> SessionSchedule {
+        SessionSchedule {
+            start,
+            duration_minutes: 120,
+            timezone: DUBLIN.to_string(),
+            recurrence: Some(recurrence),
+        }
+    }
+
+    // Local start times of the occurrences in [from, to)
+    fn starts(schedule: &SessionSchedule, from: u64, to: u64) -> Vec<String> {
+        let timezone = parse_timezone(&schedule.timezone).unwrap();
+        occurrences(schedule, from, to, MAX_OCCURRENCES)
+            .iter()
+            .map(|(start, _)| to_datetime(*start).with_timezone(&timezone).format("%Y-%m-%d %H:%M").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn weekly_rules_repeat_on_their_weekdays() {
+        let every_week = schedule(at(2024, 1, 1, 20, 0), rule(Frequency::Weekly, 1, vec![Weekday::Monday, Weekday::Thursday], None));
+        assert_eq!(
+            starts(&every_week, at(2024, 1, 1, 0, 0), at(2024, 1, 15, 0, 0)),
+            vec!["2024-01-01 20:00", "2024-01-04 20:00", "2024-01-08 20:00", "2024-01-11 20:00"]
+        );
+
+        let fortnightly = schedule(at(2024, 1, 3, 21, 0), rule(Frequency::Weekly, 2, vec![], None));
+        assert_eq!(
+            starts(&fortnightly, at(2024, 1, 1, 0, 0), at(2024, 2, 1, 0, 0)),
+            vec!["2024-01-03 21:00", "2024-01-17 21:00", "2024-01-31 21:00"]
+        );
+    }
+
+    #[test]
+    fn occurrences_keep_their_local_time_across_daylight_saving() {
+        let weekly = schedule(at(2024, 3, 24, 20, 0), rule(Frequency::Weekly, 1, vec![], None));
+        let found = occurrences(&weekly, at(2024, 3, 24, 0, 0), at(2024, 4, 1, 0, 0), 10);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].0 - found[0].0, (7 * 24 - 1) * 60 * NANOS_PER_MINUTE);
+
+        // 01:30 doesn't exist on the 31st of March and happens twice on the
+        // 27th of October
+        let gap = schedule(at(2024, 3, 24, 1, 30), rule(Frequency::Weekly, 1, vec![], None));
+        assert_eq!(
+            starts(&gap, at(2024, 3, 30, 0, 0), at(2024, 4, 1, 0, 0)),
+            vec!["2024-03-31 02:30"]
+        );
+        let repeated = schedule(at(2024, 10, 20, 1, 30), rule(Frequency::Weekly, 1, vec![], None));
+        let found = occurrences(&repeated, at(2024, 10, 26, 0, 0), at(2024, 10, 28, 0, 0), 10);
+        assert_eq!(found.len(), 1);
+        assert_eq!(to_datetime(found[0].0).format("%H:%M").to_string(), "00:30");
+    }
+
+    #[test]
+    fn set_positions_pick_a_weekday_within_the_month() {
+        let first_friday = schedule(at(2024, 1, 5, 20, 0), rule(Frequency::Monthly, 1, vec![Weekday::Friday], Some(1)));
+        assert_eq!(
+            starts(&first_friday, at(2024, 1, 1, 0, 0), at(2024, 4, 1, 0, 0)),
+            vec!["2024-01-05 20:00", "2024-02-02 20:00", "2024-03-01 20:00"]
+        );
+
+        let last_sunday = schedule(at(2024, 1, 28, 15, 0), rule(Frequency::Monthly, 1, vec![Weekday::Sunday], Some(-1)));
+        assert_eq!(
+            starts(&last_sunday, at(2024, 1, 1, 0, 0), at(2024, 4, 1, 0, 0)),
+            vec!["2024-01-28 15:00", "2024-02-25 15:00", "2024-03-31 15:00"]
+        );
+
+        // The 31st is skipped in shorter months
+        let month_end = schedule(at(2024, 1, 31, 20, 0), rule(Frequency::Monthly, 1, vec![], None));
+        assert_eq!(
+            starts(&month_end, at(2024, 1, 1, 0, 0), at(2024, 6, 1, 0, 0)),
+            vec!["2024-01-31 20:00", "2024-03-31 20:00", "2024-05-31 20:00"]
+        );
+    }
+
+    #[test]
+    fn count_and_until_end_the_recurrence() {
+        let mut counted = schedule(at(2024, 1, 1, 20, 0), rule(Frequency::Daily, 1, vec![], None));
+        counted.recurrence.as_mut().unwrap().count = Some(3);
+        assert_eq!(
+            starts(&counted, at(2024, 1, 1, 0, 0), at(2025, 1, 1, 0, 0)),
+            vec!["2024-01-01 20:00", "2024-01-02 20:00", "2024-01-03 20:00"]
+        );
+        assert!(starts(&counted, at(2024, 1, 4, 0, 0), at(2025, 1, 1, 0, 0)).is_empty());
+
+        let mut until = schedule(at(2024, 1, 1, 20, 0), rule(Frequency::Weekly, 1, vec![], None));
+        until.recurrence.as_mut().unwrap().until = Some(at(2024, 1, 15, 20, 0));
+        assert_eq!(
+            starts(&until, at(2024, 1, 1, 0, 0), at(2025, 1, 1, 0, 0)),
+            vec!["2024-01-01 20:00", "2024-01-08 20:00", "2024-01-15 20:00"]
+        );
+        assert_eq!(next_occurrence(&until, at(2024, 1, 16, 0, 0)), None);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let start = at(2024, 1, 1, 20, 0);
+        assert!(validate(&schedule(start, rule(Frequency::Weekly, 1, vec![Weekday::Friday], Some(1)))).is_err());
+        assert!(validate(&schedule(start, rule(Frequency::Monthly, 1, vec![Weekday::Friday], Some(6)))).is_err());
+        assert!(validate(&schedule(start, rule(Frequency::Daily, 1, vec![Weekday::Friday], None))).is_err());
+        assert!(validate(&schedule(start, rule(Frequency::Weekly, 0, vec![], None))).is_err());
+        let mut counted = schedule(start, rule(Frequency::Weekly, 1, vec![], None));
+        counted.recurrence.as_mut().unwrap().count = Some(0);
+        assert!(validate(&counted).is_err());
+        let mut unknown_zone = schedule(start, rule(Frequency::Weekly, 1, vec![], None));
+        unknown_zone.timezone = "Europe/Atlantis".to_string();
+        assert!(validate(&unknown_zone).is_err());
+    }
+
+    #[test]
+    fn legacy_texts_become_schedules() {
+        // A Monday
+        let now = at(2024, 1, 1, 12, 0);
+        let parse = |daytime: &str, recurring: &str| parse_legacy(daytime, recurring, now, DUBLIN);
+
+        let weekly = parse("Friday 9pm", "weekly").unwrap();
+        assert_eq!(weekly.start, at(2024, 1, 5, 21, 0));
+        assert_eq!(weekly.recurrence, Some(rule(Frequency::Weekly, 1, vec![Weekday::Friday], None)));
+
+        let monthly = parse("First Sunday of the month at 3", "").unwrap();
+        assert_eq!(monthly.start, at(2024, 1, 7, 15, 0));
+        assert_eq!(monthly.recurrence, Some(rule(Frequency::Monthly, 1, vec![Weekday::Sunday], Some(1))));
+
+        let fortnightly = parse("Tuesdays 8.30", "every other week").unwrap();
+        assert_eq!(fortnightly.start, at(2024, 1, 2, 20, 30));
+        assert_eq!(fortnightly.recurrence, Some(rule(Frequency::Weekly, 2, vec![Weekday::Tuesday], None)));
+
+        let daily = parse("at 21:00", "daily").unwrap();
+        assert_eq!(daily.start, at(2024, 1, 1, 21, 0));
+
+        assert_eq!(parse("Wednesday 9pm", "monthly"), None);
+        assert_eq!(parse("Wednesday evening", "weekly"), None);
+        assert_eq!(parse("Saturday 2pm", "one-off"), None);
+        assert_eq!(parse_legacy("Friday 9pm", "weekly", now, "Nowhere/Nowhere"), None);
+    }
+}
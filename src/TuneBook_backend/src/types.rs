@@ -4,13 +4,136 @@ use candid::{CandidType, Deserialize};
 pub struct Profile {
     pub principal: String,
     pub username: String,
-    pub avatar: Vec<u8>,
+    pub avatar: Option<Vec<u8>>, // Legacy inline avatar, moved to the blob store on upgrade
+    pub avatar_id: Option<String>,
+    pub avatar_thumbnail_id: Option<String>,
     pub pob: String,
     pub instruments: String,
     pub bio: Option<String>,
+    // Not stored with the profile any more, the friend graph lives in the
+    // relationship store and these are filled in when a profile is read.
     pub friends: Vec<String>,
     pub incoming_fr: Vec<Friend>,
-    pub outcoming_fr: Vec<Friend>
+    pub outcoming_fr: Vec<Friend>,
+    pub played_instruments: Option<Vec<PlayedInstrument>>,
+    pub home: Option<Location>,
+    pub privacy: Option<PrivacySettings>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum Visibility {
+    Public,
+    FriendsOnly,
+    Private,
+}
+
+// `bio` also covers the place of birth and home location, `instruments`
+// covers both the free text and the structured instrument list, `friends`
+// also covers the sessions someone goes to. `messages` is who may start a
+// conversation: Public opts in to messages from anyone.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct PrivacySettings {
+    pub bio: Visibility,
+    pub instruments: Visibility,
+    pub friends: Visibility,
+    pub tunebook: Visibility,
+    pub messages: Option<Visibility>,
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        PrivacySettings {
+            bio: Visibility::Public,
+            instruments: Visibility::Public,
+            friends: Visibility::FriendsOnly,
+            tunebook: Visibility::Public,
+            messages: Some(Visibility::FriendsOnly),
+        }
+    }
+}
+
+// What other users get to see of a profile. Sections hidden by the owner's
+// privacy settings are None; friend requests are never included.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct PublicProfile {
+    pub principal: String,
+    pub username: String,
+    pub avatar_id: Option<String>,
+    pub avatar_thumbnail_id: Option<String>,
+    pub pob: Option<String>,
+    pub bio: Option<String>,
+    pub home: Option<Location>,
+    pub instruments: Option<String>,
+    pub played_instruments: Option<Vec<PlayedInstrument>>,
+    pub friends: Option<Vec<String>>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum InstrumentKind {
+    Fiddle,
+    Flute,
+    TinWhistle,
+    LowWhistle,
+    UilleannPipes,
+    Concertina,
+    ButtonAccordion,
+    PianoAccordion,
+    Banjo,
+    Mandolin,
+    Bouzouki,
+    Guitar,
+    Bodhran,
+    Harp,
+    Piano,
+    Voice,
+    Other(String),
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, PartialOrd)]
+pub enum SkillLevel {
+    Beginner,
+    Intermediate,
+    Advanced,
+    Professional,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct PlayedInstrument {
+    pub instrument: InstrumentKind,
+    pub skill: SkillLevel,
+    pub years_playing: Option<u32>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Location {
+    pub name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Entry of a geohash index, ordered so that a prefix of the geohash selects
+// every record in that cell
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GeoIndexKey {
+    pub geohash: String,
+    pub id: u32,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Musician {
+    pub principal: String,
+    pub username: String,
+    pub avatar_id: Option<String>,
+    pub avatar_thumbnail_id: Option<String>,
+    pub instruments: Vec<PlayedInstrument>,
+    pub home: Option<String>,
+    pub distance_km: Option<f64>,
 }
 
 #[derive(CandidType, Clone, Deserialize, Debug)]
@@ -34,13 +157,233 @@ pub struct Session {
     pub contact: String,
     pub comment: String,
     pub recurring: String,
+    pub schedule: Option<SessionSchedule>,
+    pub coordinates: Option<Coordinates>,
+    pub co_organizers: Option<Vec<String>>, // Principals besides the owner who can edit the session
+}
+
+// A session with the attendee counts of its next occurrence, or of the
+// occurrence it is listed for
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct SessionView {
+    pub session: Session,
+    pub going_count: Option<u32>,
+    pub regular_count: u32,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum SessionAuditAction {
+    Created,
+    Updated(Vec<String>), // Names of the fields that changed
+    CoOrganizerAdded(String),
+    CoOrganizerRemoved(String),
+    OwnershipTransferred { from: String, to: String },
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct SessionAuditEntry {
+    pub session_id: u32,
+    pub id: u64,
+    pub actor: String,
+    pub action: SessionAuditAction,
+    pub created_at: u64,
+}
+
+// RSVPs of an occurrence are contiguous, an occurrence being identified by
+// its start time
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RsvpKey {
+    pub session_id: u32,
+    pub occurrence: u64,
+    pub principal: String,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Rsvp {
+    pub session_id: u32,
+    pub occurrence: u64,
+    pub principal: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionMemberKey {
+    pub session_id: u32,
+    pub principal: String,
+}
+
+// Tunes played one after the other at an occurrence of a session, by title
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct PlayedSet {
+    pub id: u64,
+    pub session_id: u32,
+    pub occurrence: u64,
+    pub tunes: Vec<String>,
+    pub recorded_by: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct TunePlayCount {
+    pub title: String,
+    pub count: u64,
+    pub last_played: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct SessionAttendance {
+    pub session_id: u32,
+    pub occurrence: u64,
+    pub going: Vec<Friend>,
+    pub regulars: Vec<Friend>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct NearbySession {
+    pub session: SessionView,
+    pub distance_km: f64,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+// Subset of the iCalendar RRULE. `by_day` lists the weekdays of weekly
+// rules; for monthly rules it is combined with `by_set_pos` to express
+// "first Friday" (1) or "last Sunday" (-1). Monthly rules without `by_day`
+// repeat on the day of the month of the first occurrence.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub struct Recurrence {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub by_day: Vec<Weekday>,
+    pub by_set_pos: Option<i32>,
+    pub until: Option<u64>,
+    pub count: Option<u32>,
+}
+
+// `start` is the first occurrence in nanoseconds since the epoch. Occurrences
+// are expanded in `timezone` (an IANA name such as "Europe/Dublin") so that
+// they keep their local time across daylight saving changes.
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub struct SessionSchedule {
+    pub start: u64,
+    pub duration_minutes: u32,
+    pub timezone: String,
+    pub recurrence: Option<Recurrence>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct SessionOccurrence {
+    pub session: SessionView,
+    pub start: u64,
+    pub end: u64,
 }
 
 #[derive(CandidType, Clone, Deserialize, Debug)]
 pub struct Friend {
     pub principal: String,
-    pub avatar: Vec<u8>,
-    pub username: String
+    pub avatar: Option<Vec<u8>>, // Legacy inline avatar, moved to the blob store on upgrade
+    pub username: String,
+    pub avatar_id: Option<String>,
+    pub avatar_thumbnail_id: Option<String>,
+    pub since: Option<u64>, // When the relationship entered its current state
+}
+
+// One direction of the relationship between two users. Both users have an
+// edge, so a pending request is RequestSent on one side and RequestReceived
+// on the other, and a block is Blocked for the blocker and BlockedBy for the
+// blocked user (Blocked on both sides when they blocked each other).
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum RelationshipState {
+    Friends,
+    RequestSent,
+    RequestReceived,
+    Blocked,
+    BlockedBy,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum NotificationKind {
+    FriendRequest,
+    FriendRequestAccepted,
+    ForumReply,
+    Message,
+    InstrumentInquiry,
+    InstrumentOffer,
+    OfferAccepted,
+    OfferDeclined,
+    SessionRsvp,
+    SessionRegular,
+    SessionTransferred,
+}
+
+// `subject_id` points at what the notification is about: the forum for
+// replies, the conversation for messages, the session for RSVPs and regulars,
+// the listing for offers.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Notification {
+    pub id: u64,
+    pub recipient: String,
+    pub kind: NotificationKind,
+    pub actor: String,
+    pub actor_username: String,
+    pub subject_id: Option<u64>,
+    pub text: String,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+// Notification ids are sequential per recipient
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NotificationKey {
+    pub recipient: String,
+    pub id: u64,
+}
+
+// A user following a session, ordered so that a user's follows are contiguous
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionFollowKey {
+    pub principal: String,
+    pub session_id: u32,
+}
+
+// Directed (owner, other) pair, also used to index conversations
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RelationshipKey {
+    pub owner: String,
+    pub other: String,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Relationship {
+    pub state: RelationshipState,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct FriendSuggestion {
+    pub friend: Friend,
+    pub mutual_friends: u32,
+    pub shared_tunes: u32,
+    pub shared_instruments: u32,
+    pub nearby_sessions: u32,
+    pub score: u32,
 }
 
 #[derive(CandidType, Clone, Deserialize, Debug)]
@@ -61,8 +404,162 @@ pub struct Instrument {
     //pub contact: String,
     pub product: String,
     pub comment: String,
-    pub price: String,
-    pub photos: Vec<Vec<u8>>,
+    pub price: String, // As written by the seller
+    pub asking_price: Option<Price>, // None when `price` couldn't be understood
+    pub photos: Option<Vec<Vec<u8>>>, // Legacy inline photos, moved to the blob store on upgrade
+    pub photo_ids: Option<Vec<String>>,
+    pub thumbnail_ids: Option<Vec<String>>,
+    pub coordinates: Option<Coordinates>,
+    pub status: Option<ListingStatus>, // None on listings created before statuses existed, see migrate_listing_status
+    pub created_at: Option<u64>,
+    pub expires_at: Option<u64>, // While active
+    pub sold_at: Option<u64>,
+    pub category: Option<ListingCategory>,
+    pub condition: Option<Condition>,
+    pub maker: Option<String>,
+    pub year: Option<u32>,
+    pub updated_at: Option<u64>,
+}
+
+// Draft listings are only visible to the seller. Active listings expire
+// after a while unless the seller renews them; sold is final.
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum ListingStatus {
+    Draft,
+    Active,
+    Reserved,
+    Sold,
+    Expired,
+}
+
+// `amount` is in the minor unit of `currency` (cents for EUR), `currency` an
+// ISO 4217 code
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub struct Price {
+    pub amount: u64,
+    pub currency: String,
+    pub negotiable: bool,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, PartialEq)]
+pub enum ListingCategory {
+    Instrument(InstrumentKind),
+    Accessories, // Cases, bows, reeds, strings...
+    Other,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum Condition {
+    New,
+    Excellent,
+    Good,
+    Fair,
+    NeedsRepair,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct ListingDetails {
+    pub category: Option<ListingCategory>,
+    pub condition: Option<Condition>,
+    pub maker: Option<String>,
+    pub year: Option<u32>, // When the instrument was made
+}
+
+// Changes to a listing, fields left out stay as they are. Some(None) clears
+// an optional field, as does an empty maker.
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct InstrumentUpdate {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub product: Option<String>,
+    pub comment: Option<String>,
+    pub price: Option<String>,
+    pub asking_price: Option<Price>,
+    pub coordinates: Option<Option<Coordinates>>,
+    pub category: Option<Option<ListingCategory>>,
+    pub condition: Option<Option<Condition>>,
+    pub maker: Option<String>,
+    pub year: Option<Option<u32>>,
+    pub photos: Option<PhotoChanges>,
+}
+
+// Photos are removed first, then new ones are appended. `order` lists the
+// positions of the resulting photos in the order they should appear.
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct PhotoChanges {
+    pub remove: Vec<String>, // Blob ids
+    pub add: Vec<Vec<u8>>,
+    pub order: Option<Vec<u32>>,
+}
+
+// Number of matching listings for each value of a facet, each facet being
+// counted with every filter but its own applied
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct InstrumentFacets {
+    pub categories: Vec<(ListingCategory, u32)>,
+    pub conditions: Vec<(Condition, u32)>,
+    pub makers: Vec<(String, u32)>,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum InstrumentSort {
+    Newest,
+    PriceAscending,
+    PriceDescending,
+}
+
+// Price bounds are in the minor unit of `currency` and only apply together
+// with it, prices in different currencies can't be compared
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct InstrumentFilter {
+    pub status: Option<ListingStatus>, // Active and reserved listings when None
+    pub currency: Option<String>,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+    pub sort: Option<InstrumentSort>,
+    pub category: Option<ListingCategory>,
+    pub condition: Option<Condition>,
+    pub maker: Option<String>, // Compared case-insensitively
+    pub min_year: Option<u32>,
+    pub max_year: Option<u32>,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum OfferStatus {
+    Open,
+    Accepted,
+    Declined,
+    Countered,
+    Withdrawn,
+}
+
+// An offer or counter-offer in the negotiation between the seller of a
+// listing and `buyer`. Questions and replies go through a conversation about
+// the listing instead.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Offer {
+    pub id: u64,
+    pub instrument_id: u32,
+    pub buyer: String,
+    pub author: String,
+    pub message: String,
+    pub price: Price,
+    pub status: OfferStatus,
+    pub created_at: u64,
+    pub updated_at: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum OfferResponse {
+    Accept,
+    Decline,
+    Counter(Price),
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct NearbyInstrument {
+    pub instrument: Instrument,
+    pub distance_km: f64,
 }
 
 
@@ -88,6 +585,161 @@ pub struct ForumData {
     pub principal: String,     
     pub created_at: u64,           
     pub updated_at: Option<u64>,     
-    pub photos: Option<Vec<Vec<u8>>>,
+    pub photos: Option<Vec<Vec<u8>>>, // Legacy inline photos, moved to the blob store on upgrade
     pub likes: u32,                  
+    pub photo_ids: Option<Vec<String>>,
+    pub thumbnail_ids: Option<Vec<String>>,
+}
+
+// A private conversation between two users. `read_up_to` holds, for each
+// participant, the id of the last message they have read.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Conversation {
+    pub id: u64,
+    pub participants: Vec<String>,
+    pub created_at: u64,
+    pub last_message_at: u64,
+    pub last_message_id: u64,
+    pub read_up_to: Vec<(String, u64)>,
+    pub instrument_id: Option<u32>, // Listing the conversation started about
+}
+
+// Message ids are sequential within their conversation
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Message {
+    pub id: u64,
+    pub conversation_id: u64,
+    pub sender: String,
+    pub body: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct ConversationSummary {
+    pub conversation: Conversation,
+    pub other: Friend,
+    pub last_message: Option<Message>,
+    pub unread_count: u32,
+}
+
+// Everything stored about an account, as returned by export_my_data. Avatars,
+// photos and recordings are referenced by id and can be downloaded through
+// the blob and recording endpoints. Large accounts are exported in pages:
+// every page repeats the profile and holds the next records of each list,
+// until `next_cursor` is None.
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct AccountExport {
+    pub exported_at: u64,
+    pub next_cursor: Option<u64>,
+    pub profile: Profile,
+    pub tunes: Vec<Tune>,
+    pub sessions: Vec<Session>,
+    pub instruments: Vec<Instrument>,
+    pub forums: Vec<Forum>,
+    pub forum_posts: Vec<ForumData>,
+    pub recordings: Vec<Recording>,
+    pub conversations: Vec<Conversation>,
+    pub messages: Vec<Message>,
+    pub notifications: Vec<Notification>,
+    pub followed_sessions: Vec<u32>,
+    pub session_rsvps: Vec<Rsvp>,
+    pub regular_sessions: Vec<u32>,
+    pub co_organized_sessions: Vec<u32>,
+    pub played_sets: Vec<PlayedSet>,
+    pub offers: Vec<Offer>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Recording {
+    pub id: u64,
+    pub tune_title: String,
+    pub uploader_principal: String,
+    pub username: String,
+    pub title: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub created_at: u64,
+    pub completed: bool,
+}
+
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct BlobInfo {
+    pub id: String,
+    pub content_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub ref_count: u32,
+    pub created_at: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub thumbnail_id: Option<String>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct BlobUpload {
+    pub id: u64,
+    pub uploader_principal: String,
+    pub content_type: String, // As declared, see finish_blob_upload
+    pub size: u64,
+    pub chunk_count: u32,
+    pub created_at: u64,
+}
+
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct StreamingCallbackToken {
+    pub key: String,
+    pub index: u32,
+}
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingCallbackToken>,
+}
+
+candid::define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+// A record that was overwritten by another one with the same id, as far as
+// it can be told from what is left
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct IdCollision {
+    pub entity: String,
+    pub id: u64,
+    pub detail: String,
+}
+
+// How far a background migration got; `cursor` holds the encoded key of the
+// last record it processed
+#[derive(CandidType, Clone, Deserialize, Debug, Default)]
+pub struct MigrationProgress {
+    pub cursor: Option<Vec<u8>>,
+    pub processed: u64,
+    pub finished_at: Option<u64>,
 }
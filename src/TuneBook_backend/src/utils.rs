@@ -1,16 +1,18 @@
-use ic_cdk;
+use crate::geo;
+use crate::images;
+use crate::price;
+use crate::schedule;
 use crate::types;
 use crate::types::Instrument;
 use candid::{Decode, Encode};
 use serde_json::Value;
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
 use regex::Regex;
-use crate::utils::ic_cdk::api;
-use std::borrow::Borrow;
+use ic_cdk::api;
 use crate::types::{Forum, ForumData};
-use base64::decode;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
 
 
     
@@ -33,12 +35,48 @@ type InstrumentStore = StableBTreeMap<u32, Instrument, Memory>;
 type ForumStore = StableBTreeMap<u64, Forum, Memory>;
 type ForumDataStore = StableBTreeMap<u64, ForumData, Memory>;
 
+type RecordingStore = StableBTreeMap<u64, types::Recording, Memory>;
+type RecordingChunkStore = StableBTreeMap<(u64, u32), Vec<u8>, Memory>;
+
+type BlobStore = StableBTreeMap<[u8; 32], types::BlobInfo, Memory>;
+type BlobChunkStore = StableBTreeMap<([u8; 32], u32), Vec<u8>, Memory>;
+type BlobUploadStore = StableBTreeMap<u64, types::BlobUpload, Memory>;
+type BlobUploadChunkStore = StableBTreeMap<(u64, u32), Vec<u8>, Memory>;
+
+type UsernameStore = StableBTreeMap<String, String, Memory>;
+type RelationshipStore = StableBTreeMap<types::RelationshipKey, types::Relationship, Memory>;
+
+type ConversationStore = StableBTreeMap<u64, types::Conversation, Memory>;
+type MessageStore = StableBTreeMap<(u64, u64), types::Message, Memory>;
+type ConversationIndex = StableBTreeMap<types::RelationshipKey, u64, Memory>;
+
+type NotificationStore = StableBTreeMap<types::NotificationKey, types::Notification, Memory>;
+
+type SessionFollowStore = StableBTreeMap<types::SessionFollowKey, u64, Memory>;
+
+type GeoIndex = StableBTreeMap<types::GeoIndexKey, (), Memory>;
+
+type RsvpStore = StableBTreeMap<types::RsvpKey, types::Rsvp, Memory>;
+type SessionRegularStore = StableBTreeMap<types::SessionMemberKey, u64, Memory>;
+type SessionAuditStore = StableBTreeMap<(u32, u64), types::SessionAuditEntry, Memory>;
+
+type IdCounterStore = StableBTreeMap<String, u64, Memory>;
+
+type PlayedSetStore = StableBTreeMap<(u32, u64, u64), types::PlayedSet, Memory>;
+type TunePlayCountStore = StableBTreeMap<String, u64, Memory>;
+
+type OfferStore = StableBTreeMap<(u32, u64), types::Offer, Memory>;
+
+type MigrationStore = StableBTreeMap<String, types::MigrationProgress, Memory>;
+
+type CalendarTokenStore = StableBTreeMap<String, String, Memory>;
+
 
 
 
 
 impl Storable for types::Profile {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -54,7 +92,7 @@ impl Storable for types::Profile {
 
 
 impl Storable for types::Tune {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -69,7 +107,7 @@ impl Storable for types::Tune {
 }
 
 impl Storable for types::Session {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -84,7 +122,7 @@ impl Storable for types::Session {
 }
 
 impl Storable for types::Instrument {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -99,7 +137,7 @@ impl Storable for types::Instrument {
 }
 
 impl Storable for types::Forum {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -116,7 +154,7 @@ impl Storable for types::Forum {
 }
 
 impl Storable for types::ForumData {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -131,6 +169,276 @@ impl Storable for types::ForumData {
 
 }
 
+impl Storable for types::RelationshipKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 300, // Two textual principals
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Relationship {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Conversation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1000,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Message {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20000, // MAX_MESSAGE_LENGTH characters of up to 4 bytes plus metadata
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::NotificationKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::SessionFollowKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::GeoIndexKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 100,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::RsvpKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Rsvp {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 300,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::SessionMemberKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 200,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::SessionAuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1000,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::PlayedSet {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4000, // MAX_TUNES_PER_SET titles plus metadata
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Offer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 10000, // MAX_OFFER_MESSAGE_LENGTH characters of up to 4 bytes plus metadata
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::MigrationProgress {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1000, // The cursor is a single store key
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 2000,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::Recording {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 10000, // Metadata only, the audio lives in RECORDING_CHUNK_STORE
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::BlobInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1000,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for types::BlobUpload {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1000,
+        is_fixed_size: false,
+    };
+}
+
 
 
 thread_local! {
@@ -170,22 +478,166 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))) // Forum data store
         )
     );
-}
 
+    pub static RECORDING_STORE: RefCell<RecordingStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))) // Recording metadata
+        )
+    );
 
+    pub static RECORDING_CHUNK_STORE: RefCell<RecordingChunkStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))) // Recording audio chunks
+        )
+    );
 
+    pub static BLOB_STORE: RefCell<BlobStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))) // Blob metadata by content hash
+        )
+    );
 
+    pub static BLOB_CHUNK_STORE: RefCell<BlobChunkStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))) // Blob chunks
+        )
+    );
 
-pub async fn init() {
-    ic_cdk::setup();
+    pub static BLOB_UPLOAD_STORE: RefCell<BlobUploadStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))) // Pending blob uploads
+        )
+    );
 
-    ic_cdk::println!("Initializing tunes from tune_db.json");
+    pub static BLOB_UPLOAD_CHUNK_STORE: RefCell<BlobUploadChunkStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))) // Chunks of pending uploads
+        )
+    );
 
-    /*
-    // Clear the existing tunes
-    TUNE_STORE.with(|tune_store| {
-        let mut store = tune_store.borrow_mut();
-        let old_count = store.len();
+    pub static USERNAME_STORE: RefCell<UsernameStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))) // Normalized username -> principal
+        )
+    );
+
+    pub static RELATIONSHIP_STORE: RefCell<RelationshipStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))) // Friend graph edges
+        )
+    );
+
+    pub static CONVERSATION_STORE: RefCell<ConversationStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))) // Conversations
+        )
+    );
+
+    pub static MESSAGE_STORE: RefCell<MessageStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))) // Messages by (conversation, message)
+        )
+    );
+
+    pub static CONVERSATION_INDEX: RefCell<ConversationIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))) // (participant, other) -> conversation
+        )
+    );
+
+    pub static NOTIFICATION_STORE: RefCell<NotificationStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))) // Notifications by (recipient, id)
+        )
+    );
+
+    pub static SESSION_FOLLOW_STORE: RefCell<SessionFollowStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))) // (principal, session) -> followed at
+        )
+    );
+
+    pub static SESSION_GEO_INDEX: RefCell<GeoIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))) // (geohash, session id)
+        )
+    );
+
+    pub static INSTRUMENT_GEO_INDEX: RefCell<GeoIndex> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))) // (geohash, instrument id)
+        )
+    );
+
+    pub static RSVP_STORE: RefCell<RsvpStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))) // RSVPs by (session, occurrence, principal)
+        )
+    );
+
+    pub static SESSION_REGULAR_STORE: RefCell<SessionRegularStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))) // (session, principal) -> regular since
+        )
+    );
+
+    pub static SESSION_AUDIT_STORE: RefCell<SessionAuditStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))) // Audit entries by (session, entry)
+        )
+    );
+
+    pub static ID_COUNTER_STORE: RefCell<IdCounterStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))) // Entity -> next id
+        )
+    );
+
+    pub static PLAYED_SET_STORE: RefCell<PlayedSetStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))) // Sets by (session, occurrence, set)
+        )
+    );
+
+    pub static TUNE_PLAY_COUNT_STORE: RefCell<TunePlayCountStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))) // Tune title -> sets it was played in
+        )
+    );
+
+    pub static OFFER_STORE: RefCell<OfferStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))) // Offers by (listing, offer)
+        )
+    );
+
+    pub static MIGRATION_STORE: RefCell<MigrationStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))) // Background migration progress by name
+        )
+    );
+
+    pub static CALENDAR_TOKEN_STORE: RefCell<CalendarTokenStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))) // Principal -> calendar feed token
+        )
+    );
+}
+
+
+
+
+
+pub async fn init() {
+    ic_cdk::setup();
+
+    ic_cdk::println!("Initializing tunes from tune_db.json");
+
+    /*
+    // Clear the existing tunes
+    TUNE_STORE.with(|tune_store| {
+        let mut store = tune_store.borrow_mut();
+        let old_count = store.len();
 
         // Use an iterator to get and remove all keys
         let keys_to_remove: Vec<String> = store.iter().map(|(key, _)| key.clone()).collect();
@@ -228,7 +680,27 @@ pub async fn init() {
 
 
 
+// The principal the current call was made with, None for anonymous callers
+pub fn caller_principal() -> Option<String> {
+    let caller = ic_cdk::caller();
+    (caller != candid::Principal::anonymous()).then(|| caller.to_text())
+}
+
+
+// Endpoints that act as a user take that user's principal as an argument,
+// which is only trusted when it is the caller's own.
+pub fn check_caller(principal: &str) -> Result<(), String> {
+    match caller_principal() {
+        Some(caller) if caller == principal => Ok(()),
+        Some(_) => Err("Not authorized".to_string()),
+        None => Err("Sign in first".to_string()),
+    }
+}
+
+
+// The full profile, friends and requests included, is only for its owner
 pub fn authentication(principal: String) -> Option<types::Profile> {
+    check_caller(&principal).ok()?;
     PROFILE_STORE.with(|profile_store| {
         if profile_store.borrow().get(&principal).is_some() {
             Some(profile_store.borrow().get(&principal).unwrap().clone())
@@ -236,6 +708,7 @@ pub fn authentication(principal: String) -> Option<types::Profile> {
             None
         }
     })
+    .map(with_relationships)
 }
 
 
@@ -246,43 +719,54 @@ pub async fn update_profile(
     instruments: String,
     bio: Option<String>,
     avatar: Vec<u8>,
-) -> types::Profile {
-    PROFILE_STORE.with(|profile_store| {
-        let store = profile_store.borrow();
+) -> Result<types::Profile, String> {
+    let previous_username = PROFILE_STORE.with(|profile_store| {
+        profile_store.borrow().get(&principal).map(|profile| profile.username)
+    });
 
-        // Check for username uniqueness
-        if store.iter().any(|(_, profile)| profile.username == username && profile.principal != principal) {
-            panic!("Username '{}' is already taken", username); // You can handle this differently
-        }
-        
-        drop(store);
+    // Names that predate the validation rules are kept as long as they don't change
+    if previous_username.as_ref() != Some(&username) {
+        check_username_available(principal.clone(), username.clone())?;
+    }
+    let avatar_id = store_avatar(avatar)?;
+    claim_username(&principal, previous_username.as_deref(), &username);
 
+    Ok(PROFILE_STORE.with(|profile_store| {
         // If profile exists, update it
         if profile_store.borrow().get(&principal).is_some() {
 
             let mut new_profile = profile_store.borrow().get(&principal).unwrap().clone();
 
+            if let Some(old_avatar) = new_profile.avatar_id.take() {
+                release_blob(&old_avatar);
+            }
             new_profile.username = username;
-            new_profile.avatar = avatar;
+            new_profile.avatar_thumbnail_id = avatar_id.as_deref().and_then(thumbnail_of);
+            new_profile.avatar_id = avatar_id;
             new_profile.pob = pob;
             new_profile.instruments = instruments;
             new_profile.bio = bio;
             profile_store.borrow_mut().insert(principal, new_profile.clone());
 
             println!("Created new profile for principal: ");
-            new_profile
+            with_relationships(new_profile)
         } else {
             // Otherwise, create a new profile
             let new_profile = types::Profile {
                 principal: principal.clone(),
                 username,
-                avatar,
+                avatar: None,
+                avatar_thumbnail_id: avatar_id.as_deref().and_then(thumbnail_of),
+                avatar_id,
                 pob,
                 instruments,
                 bio,
                 friends: vec![],
                 incoming_fr: vec![],
                 outcoming_fr: vec![],
+                played_instruments: None,
+                home: None,
+                privacy: None,
             };
             profile_store.borrow_mut().insert(principal, new_profile.clone());
 
@@ -290,11 +774,11 @@ pub async fn update_profile(
             new_profile
         }
     }
-)}
+))}
 
 
 // Function to get a paginated list of original tunes
-pub fn get_original_tune_list(principal: String, page_number: i32) -> (Vec<String>, i32) {
+pub fn get_original_tune_list(_principal: String, page_number: i32) -> (Vec<String>, i32) {
     TUNE_STORE.with(|tune_store| {
         let tunes: Vec<String> = tune_store
             .borrow()
@@ -321,19 +805,22 @@ pub fn get_original_tune(title: String) -> String {
 }
 
 
-pub fn get_user_tune_list(principal: String, page_number: i32) -> (Vec<types::Tuneinfo>, i32) {
+pub fn get_user_tune_list(principal: String, page_number: i32, viewer: Option<String>) -> (Vec<types::Tuneinfo>, i32) {
+    if !can_view_section(&principal, viewer.as_ref(), |privacy| &privacy.tunebook) {
+        return (vec![], 0);
+    }
+
     TUNE_STORE.with(|tune_store| {
         let user_tunes: Vec<types::Tuneinfo> = tune_store
             .borrow()
             .iter()
             .filter(|(_, tune_info)| tune_info.principals.contains(&principal))
             .map(|(_, tune_info)| {
-                let user_tune = types::Tuneinfo {
+                types::Tuneinfo {
                     title: tune_info.title.clone(),
                     tune_data: tune_info.tune_data.clone(),
                     username: tune_info.username.clone(),
-                };
-                user_tune
+                }
             })
             .collect();
 
@@ -345,11 +832,11 @@ pub fn get_user_tune_list(principal: String, page_number: i32) -> (Vec<types::Tu
                 .iter()
                 .skip(page_number as usize * 8)
                 .enumerate()
-                .filter(|(index, _)| index.clone() < 8)
+                .filter(|(index, _)| *index < 8)
                 .map(|(_, tune_info)| tune_info.clone())
                 .collect();
 
-            return (res, user_tunes.len() as i32);
+            (res, user_tunes.len() as i32)
     })
 }
 
@@ -466,40 +953,25 @@ pub async fn update_tune(
 }
     
 
-pub fn get_friends(principal: String) -> Vec<types::Friend> {
-    PROFILE_STORE.with(|profile_store| {
-        let binding = profile_store.borrow();
-        if binding.get(&principal).is_some() {
-            let friend_principals = profile_store
-                .borrow()
-                .get(&principal)
-                .unwrap()
-                .friends
-                .clone();
-            let result: Vec<types::Friend> = friend_principals
-                .iter()
-                .map(|friend_principal| {
-                    let friend_profile = binding.get(friend_principal).unwrap();
-                    let friend = types::Friend {
-                        principal: friend_principal.clone(),
-                        avatar: friend_profile.avatar.clone(),
-                        username: friend_profile.username.clone(),
-                    };
-                    friend
-                })
-                .collect();
-            result
-        } else {
-            vec![]
-        }
-    })
+pub fn get_friends(principal: String, viewer: Option<String>) -> Vec<types::Friend> {
+    if !can_view_section(&principal, viewer.as_ref(), |privacy| &privacy.friends) {
+        return vec![];
+    }
+
+    relationships_of(&principal)
+        .into_iter()
+        .filter(|(_, relationship)| relationship.state == types::RelationshipState::Friends)
+        .filter_map(|(other, relationship)| friend_view(&other, &relationship))
+        .collect()
 }
 
-pub fn get_profile(principal: String) -> Option<types::Profile> {
+pub fn get_profile(principal: String, viewer: Option<String>) -> Option<types::PublicProfile> {
     PROFILE_STORE.with(|profile_store| {
-        if let Some(profile) = profile_store.borrow().get(&principal) {
+        if let Some(profile) = profile_store.borrow().get(&principal).filter(|_| {
+            !viewer.as_ref().is_some_and(|viewer| is_blocked(viewer, &principal))
+        }) {
             ic_cdk::println!("Profile found for principal: {}", principal); // Log the success
-            Some(profile.clone()) // Return the profile if it exists
+            Some(public_profile(&profile, viewer.as_ref())) // Return what the viewer may see
         } else {
             ic_cdk::println!("No profile found for principal: {}", principal); // Log the failure
             None // Return None if no profile exists
@@ -510,121 +982,55 @@ pub fn get_profile(principal: String) -> Option<types::Profile> {
 
 
 pub async fn send_friend_request(sender: String, receiver: String) -> Option<types::Friend> {
-    PROFILE_STORE.with(|profile_store| {
-        let mut binding = profile_store.borrow_mut();
-        if binding.get(&sender).is_some() && binding.get(&receiver).is_some() {
-            let mut sender_profile = binding.get(&sender).unwrap().clone();
-            let mut receiver_profile = binding.get(&receiver).unwrap().clone();
+    if sender == receiver || !profile_exists(&sender) || !profile_exists(&receiver) {
+        return None;
+    }
+    if !change_relationship(&sender, &receiver, RelationshipChange::Request) {
+        return None;
+    }
 
-            let outcoming_principals: Vec<String> = sender_profile.outcoming_fr
-                .iter()
-                .map(|friend| friend.principal.clone())
-                .collect();
+    notify(&receiver, types::NotificationKind::FriendRequest, &sender, None, "sent you a friend request".to_string());
+    friend_view(&receiver, &get_relationship(&sender, &receiver)?)
+}
 
-            let incoming_principals: Vec<String> = sender_profile.incoming_fr
-                .iter()
-                .map(|friend| friend.principal.clone())
-                .collect();
-            
-            if sender_profile.friends.contains(&receiver) || outcoming_principals.contains(&receiver) || incoming_principals.contains(&receiver) {
-                return None;
-            }
 
-            let incoming_request = types::Friend {
-                principal: sender.clone(),
-                username: sender_profile.username.clone(),
-                avatar: sender_profile.avatar.clone(),
-            };
-            let outcoming_request = types::Friend {
-                principal: receiver.clone(),
-                username: receiver_profile.username.clone(),
-                avatar: receiver_profile.avatar.clone(),
-            };
-            sender_profile.outcoming_fr.push(outcoming_request.clone());
-            receiver_profile.incoming_fr.push(incoming_request);
-            binding.insert(sender, sender_profile);
-            binding.insert(receiver, receiver_profile);
-            Some(outcoming_request)
-        } else {
-            None
-        }
-    })
+// `sender` accepts the request `receiver` sent them
+pub async fn accept_friend_request(sender: String, receiver: String) -> bool {
+    if !change_relationship(&sender, &receiver, RelationshipChange::Accept) {
+        ic_cdk::println!("No pending friend request from {} to {}", receiver, sender);
+        return false;
+    }
+    notify(&receiver, types::NotificationKind::FriendRequestAccepted, &sender, None, "accepted your friend request".to_string());
+    true
 }
 
 
-pub async fn accept_friend_request(sender: String, receiver: String) -> bool {
-    PROFILE_STORE.with(|profile_store| {
-        let mut binding = profile_store.borrow_mut();
-        if binding.get(&sender).is_some() && binding.get(&receiver).is_some() {
-            let mut sender_profile = binding.get(&sender).unwrap().clone();
-            let mut receiver_profile = binding.get(&receiver).unwrap().clone();
-            let in_position = sender_profile
-                .incoming_fr
-                .iter()
-                .position(|ifr| ifr.principal == receiver.clone());
-            if in_position.is_some() {
-                sender_profile.incoming_fr.remove(in_position.unwrap());
-            }
-            let out_position = receiver_profile
-                .outcoming_fr
-                .iter()
-                .position(|ofr| ofr.principal == sender.clone());
-            if out_position.is_some() {
-                receiver_profile.outcoming_fr.remove(out_position.unwrap());
-            }
+// `principal` declines the request `requester` sent them
+pub fn decline_friend_request(principal: String, requester: String) -> bool {
+    change_relationship(&principal, &requester, RelationshipChange::Decline)
+}
 
-            sender_profile.friends.push(receiver.clone());
-            receiver_profile.friends.push(sender.clone());
-            binding.insert(sender, sender_profile);
-            binding.insert(receiver, receiver_profile);
-            true
-        } else {
-            false
-        }
-    })
+
+pub fn unfriend(principal: String, friend: String) -> bool {
+    change_relationship(&principal, &friend, RelationshipChange::Unfriend)
 }
 
 
-pub async fn cancel_friend_request(sender: String, receiver: String) -> bool {
-    PROFILE_STORE.with(|profile_store| {
-        let mut binding = profile_store.borrow_mut();
+pub fn block_user(principal: String, blocked: String) -> bool {
+    if principal == blocked || !profile_exists(&principal) || !profile_exists(&blocked) {
+        return false;
+    }
+    change_relationship(&principal, &blocked, RelationshipChange::Block)
+}
 
-        // Safely extract sender and receiver profiles
-        if binding.get(&sender).is_some() && binding.get(&receiver).is_some() {
-            let mut sender_profile = binding.get(&sender).unwrap().clone();
-            let mut receiver_profile = binding.get(&receiver).unwrap().clone();
-        
-            // Remove the outgoing request from the sender's profile
-            if let Some(out_position) = sender_profile
-                .outcoming_fr
-                .iter()
-                .position(|fr| fr.principal == receiver)
-            {
-                sender_profile.outcoming_fr.remove(out_position);
-            } else {
-                return false;  // Outgoing request not found
-            }
 
-            // Remove the incoming request from the receiver's profile
-            if let Some(in_position) = receiver_profile
-                .incoming_fr
-                .iter()
-                .position(|fr| fr.principal == sender)
-            {
-                receiver_profile.incoming_fr.remove(in_position);
-            } else {
-                return false;  // Incoming request not found
-            }
+pub fn unblock_user(principal: String, blocked: String) -> bool {
+    change_relationship(&principal, &blocked, RelationshipChange::Unblock)
+}
 
-            // Save the updated profiles
-            binding.insert(sender.clone(), sender_profile);
-            binding.insert(receiver.clone(), receiver_profile);
 
-            return true;  // Successfully cancelled the request
-        }
-        
-        false  // Either sender or receiver profile not found
-    })
+pub async fn cancel_friend_request(sender: String, receiver: String) -> bool {
+    change_relationship(&sender, &receiver, RelationshipChange::Cancel)
 }
 
 
@@ -635,7 +1041,6 @@ pub fn filter_tunes(
     page_num: i32,
 ) -> (Vec<types::Tuneinfo>, i32) {
     const ITEMS_PER_PAGE: usize = 15;
-    const BATCH_SIZE: usize = 100; // Process in batches of 100 items at a time
 
     TUNE_STORE.with(|tune_store| {
         let binding = tune_store.borrow();
@@ -663,7 +1068,7 @@ pub fn filter_tunes(
         let mut current_index = 0;
 
         // Instead of chunks, iterate manually in batches
-        for (key, tune_info) in binding.iter() {
+        for (_, tune_info) in binding.iter() {
             // Title filter
             let title_match = tune_info.title.to_lowercase().contains(&sub_title.to_lowercase());
 
@@ -712,55 +1117,272 @@ pub fn filter_tunes(
 }
 
 
-    pub fn browse_people(my_principal: String, filter: String, page_num: i32) -> (Vec<types::Friend>, i32) {
-        PROFILE_STORE.with(|profile_store| {
-            let my_profile = profile_store.borrow().get(&my_principal).unwrap().clone();
-            let outcoming_principals: Vec<String> = my_profile.outcoming_fr
-                .iter()
-                .map(|friend| friend.principal.clone())
-                .collect();
-    
-            let incoming_principals: Vec<String> = my_profile.incoming_fr
+// Profiles of people the user could connect with: everyone except the user,
+// their friends, people they already have a pending request with and blocked
+// users. Filters only see what the user is allowed to see of each profile.
+fn browse_profiles<F>(my_principal: &String, matches: F) -> Vec<types::PublicProfile>
+where
+    F: Fn(&types::PublicProfile) -> bool,
+{
+    // Friends and people with a pending request in either direction
+    let connected: std::collections::HashSet<String> = relationships_of(my_principal)
+        .into_iter()
+        .map(|(other, _)| other)
+        .collect();
+
+    PROFILE_STORE.with(|profile_store| {
+        profile_store
+            .borrow()
+            .iter()
+            .filter(|(_, profile)|
+                profile.principal != *my_principal &&  // Exclude current user
+                !connected.contains(&profile.principal)
+            )
+            .map(|(_, profile)| public_profile(&profile, Some(my_principal)))
+            .filter(|profile| matches(profile))
+            .collect()
+    })
+}
+
+
+pub fn browse_people(my_principal: String, filter: String, page_num: i32) -> (Vec<types::Friend>, i32) {
+    let filter = filter.to_lowercase();
+    let res: Vec<types::Friend> = browse_profiles(&my_principal, |profile| {
+        profile.username.to_lowercase().contains(&filter)
+    })
+    .into_iter()
+    .map(|profile| {
+        types::Friend {
+            principal: profile.principal,
+            avatar: None,
+            username: profile.username,
+            avatar_id: profile.avatar_id,
+            avatar_thumbnail_id: profile.avatar_thumbnail_id,
+            since: None,
+        }
+    })
+    .collect();
+
+    let result: Vec<types::Friend> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+fn same_instrument(a: &types::InstrumentKind, b: &types::InstrumentKind) -> bool {
+    match (a, b) {
+        (types::InstrumentKind::Other(a), types::InstrumentKind::Other(b)) => a.trim().eq_ignore_ascii_case(b.trim()),
+        _ => a == b,
+    }
+}
+
+
+// Musicians playing `instrument` (any instrument when None). When `near` is
+// given only people whose home is within `radius_km` are returned, closest first.
+pub fn find_musicians(
+    my_principal: String,
+    instrument: Option<types::InstrumentKind>,
+    near: Option<types::Location>,
+    radius_km: f64,
+    page_num: i32,
+) -> (Vec<types::Musician>, i32) {
+    let distance_to = |profile: &types::PublicProfile| -> Option<f64> {
+        let near = near.as_ref()?;
+        let home = profile.home.as_ref()?;
+        Some(geo::distance_km(near.latitude, near.longitude, home.latitude, home.longitude))
+    };
+
+    let mut res: Vec<types::Musician> = browse_profiles(&my_principal, |profile| {
+        let plays = match &instrument {
+            Some(instrument) => profile
+                .played_instruments
                 .iter()
-                .map(|friend| friend.principal.clone())
-                .collect();
-    
-            // Filter profiles: exclude the current user, friends, and people with existing requests
-            let res: Vec<types::Friend> = profile_store
-                .borrow()
+                .flatten()
+                .any(|played| same_instrument(&played.instrument, instrument)),
+            None => profile.played_instruments.as_ref().is_some_and(|played| !played.is_empty()),
+        };
+        plays && (near.is_none() || distance_to(profile).is_some_and(|distance| distance <= radius_km))
+    })
+    .into_iter()
+    .map(|profile| types::Musician {
+        distance_km: distance_to(&profile),
+        principal: profile.principal,
+        username: profile.username,
+        avatar_id: profile.avatar_id,
+        avatar_thumbnail_id: profile.avatar_thumbnail_id,
+        instruments: profile.played_instruments.unwrap_or_default(),
+        home: profile.home.map(|home| home.name),
+    })
+    .collect();
+
+    res.sort_by(|a, b| a.distance_km.unwrap_or(0.0).total_cmp(&b.distance_km.unwrap_or(0.0)));
+
+    let result: Vec<types::Musician> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+const MUTUAL_FRIEND_WEIGHT: u32 = 3;
+const SHARED_TUNE_WEIGHT: u32 = 1;
+const SHARED_INSTRUMENT_WEIGHT: u32 = 2;
+const NEARBY_SESSION_WEIGHT: u32 = 2;
+
+// People the user might know, ranked by mutual friends, tunes both have in
+// their tunebook, instruments they share and sessions they run near the
+// user's home. Candidates come from the browse_people flow, so friends,
+// pending requests and blocked users are never suggested.
+pub fn suggest_friends(my_principal: String, page_num: i32) -> (Vec<types::FriendSuggestion>, i32) {
+    let me = match PROFILE_STORE.with(|profile_store| profile_store.borrow().get(&my_principal)) {
+        Some(profile) => profile,
+        None => return (vec![], 0),
+    };
+    let my_friends = friend_principals(&my_principal);
+    let my_instruments = me.played_instruments.clone().unwrap_or_default();
+    let my_places: Vec<String> = me
+        .home
+        .iter()
+        .map(|home| home.name.clone())
+        .chain(std::iter::once(me.pob.clone()))
+        .map(|place| place.trim().to_lowercase())
+        .filter(|place| !place.is_empty())
+        .collect();
+
+    let mut shared_tunes: std::collections::HashMap<String, u32> = std::collections::HashMap::new();
+    TUNE_STORE.with(|tune_store| {
+        for (_, tune) in tune_store.borrow().iter() {
+            if tune.principals.contains(&my_principal) {
+                for principal in tune.principals.iter().filter(|p| **p != my_principal) {
+                    *shared_tunes.entry(principal.clone()).or_default() += 1;
+                }
+            }
+        }
+    });
+
+    let mut nearby_sessions: std::collections::HashMap<String, u32> = std::collections::HashMap::new();
+    if !my_places.is_empty() {
+        SESSION_STORE.with(|session_store| {
+            for (_, session) in session_store.borrow().iter() {
+                let location = session.location.to_lowercase();
+                if my_places.iter().any(|place| location.contains(place.as_str())) {
+                    *nearby_sessions.entry(session.principal.clone()).or_default() += 1;
+                }
+            }
+        });
+    }
+
+    let mut res: Vec<types::FriendSuggestion> = browse_profiles(&my_principal, |_| true)
+        .into_iter()
+        .filter_map(|profile| {
+            // Only count what the candidate's privacy settings show the user:
+            // the public profile leaves out hidden friends and instruments
+            let mutual_friends = profile
+                .friends
                 .iter()
-                .filter(|(_, profile)| 
-                    profile.username.to_lowercase().contains(&filter.to_lowercase()) &&
-                    profile.principal != my_principal &&  // Exclude current user
-                    !my_profile.friends.contains(&profile.principal) &&  // Exclude friends
-                    !outcoming_principals.contains(&profile.principal) &&  // Exclude outgoing requests
-                    !incoming_principals.contains(&profile.principal)  // Exclude incoming requests
-                )
-                .map(|(principal, profile)| {
-                    let user = types::Friend {
-                        principal: principal.clone(),
-                        avatar: profile.avatar.clone(),
-                        username: profile.username.clone(),
-                    };
-                    user
-                })
-                .collect();
-    
-            let result: Vec<types::Friend> = res
+                .flatten()
+                .filter(|friend| my_friends.contains(friend))
+                .count() as u32;
+            let shared_tunes = if can_view_section(&profile.principal, Some(&my_principal), |privacy| &privacy.tunebook) {
+                shared_tunes.get(&profile.principal).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            let shared_instruments = profile
+                .played_instruments
                 .iter()
-                .skip(page_num as usize * 15)
-                .take(15)
-                .cloned()
-                .collect();
-    
-            (result, res.len() as i32)
+                .flatten()
+                .filter(|played| my_instruments.iter().any(|mine| same_instrument(&mine.instrument, &played.instrument)))
+                .count() as u32;
+            let nearby_sessions = nearby_sessions.get(&profile.principal).copied().unwrap_or(0);
+
+            let score = mutual_friends * MUTUAL_FRIEND_WEIGHT
+                + shared_tunes * SHARED_TUNE_WEIGHT
+                + shared_instruments * SHARED_INSTRUMENT_WEIGHT
+                + nearby_sessions * NEARBY_SESSION_WEIGHT;
+            if score == 0 {
+                return None;
+            }
+
+            Some(types::FriendSuggestion {
+                friend: types::Friend {
+                    principal: profile.principal,
+                    avatar: None,
+                    username: profile.username,
+                    avatar_id: profile.avatar_id,
+                    avatar_thumbnail_id: profile.avatar_thumbnail_id,
+                    since: None,
+                },
+                mutual_friends,
+                shared_tunes,
+                shared_instruments,
+                nearby_sessions,
+                score,
+            })
         })
+        .collect();
+
+    res.sort_by_key(|suggestion| std::cmp::Reverse(suggestion.score));
+
+    let result: Vec<types::FriendSuggestion> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+const MAX_PLAYED_INSTRUMENTS: usize = 20;
+
+pub fn update_musician_profile(
+    principal: String,
+    instruments: Vec<types::PlayedInstrument>,
+    home: Option<types::Location>,
+) -> Result<(), String> {
+    if instruments.len() > MAX_PLAYED_INSTRUMENTS {
+        return Err(format!("At most {} instruments can be listed", MAX_PLAYED_INSTRUMENTS));
+    }
+    if instruments.iter().any(|played| matches!(&played.instrument, types::InstrumentKind::Other(name) if name.trim().is_empty())) {
+        return Err("Other instruments need a name".to_string());
+    }
+    if let Some(home) = &home {
+        if !geo::is_valid_coordinate(home.latitude, home.longitude) {
+            return Err("Invalid home coordinates".to_string());
+        }
     }
+
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        let mut profile = store.get(&principal).ok_or("Profile not found")?;
+        profile.played_instruments = Some(instruments);
+        profile.home = home;
+        store.insert(principal, profile);
+        Ok(())
+    })
+}
     
 
 
 
-pub fn get_new_tunes_from_friends(_principal: String) -> Vec<types::Tune> {
+pub fn get_new_tunes_from_friends(principal: String) -> Vec<types::Tune> {
+    let blocked: Vec<String> = relationships_of(&principal)
+        .into_iter()
+        .filter(|(other, _)| is_blocked(&principal, other))
+        .map(|(other, _)| other)
+        .collect();
+
     // let friends = PROFILE_STORE.with(|profile_store| {
     //     let binding = profile_store.borrow();
     //     if binding.get(&principal).is_some() {
@@ -774,6 +1396,9 @@ pub fn get_new_tunes_from_friends(_principal: String) -> Vec<types::Tune> {
             .borrow()
             .iter()
             .filter(|(_, tune_info)| ic_cdk::api::time() - tune_info.timestamp < 604800000000000)
+            .filter(|(_, tune_info)| {
+                tune_info.principals.is_empty() || !tune_info.principals.iter().all(|p| blocked.contains(p))
+            })
             .map(|(_, tune)| tune.clone())
             .collect()
     })
@@ -790,9 +1415,11 @@ pub fn get_new_tunes_from_friends(_principal: String) -> Vec<types::Tune> {
 }
 
 
-pub fn get_sessions(sub_name: &str, page_num: i32) -> (Vec<types::Session>, i32) {
+// Scheduled sessions come first, ordered by their next occurrence
+pub fn get_sessions(sub_name: &str, page_num: i32) -> (Vec<types::SessionView>, i32) {
+    let now = ic_cdk::api::time();
     SESSION_STORE.with(|session_store| {
-        let res: Vec<types::Session> = session_store
+        let mut res: Vec<types::Session> = session_store
             .borrow()
             .iter()
             .filter(|(_, session)| 
@@ -801,13 +1428,20 @@ pub fn get_sessions(sub_name: &str, page_num: i32) -> (Vec<types::Session>, i32)
             )
             .map(|(_, session)| session.clone())
             .collect();
-
-        let result: Vec<types::Session> = res
+        res.sort_by_cached_key(|session| {
+            session
+                .schedule
+                .as_ref()
+                .and_then(|schedule| schedule::next_occurrence(schedule, now))
+                .unwrap_or(u64::MAX)
+        });
+
+        let result: Vec<types::SessionView> = res
             .iter()
-            .skip(page_num as usize * 15 as usize)
+            .skip(page_num as usize * 15)
             .enumerate()
-            .filter(|(index, _)| index.clone() < 15 as usize)
-            .map(|(_, session)| session.clone())
+            .filter(|(index, _)| *index < 15)
+            .map(|(_, session)| with_attendance(session.clone(), None))
             .collect();
 
         (result, res.len() as i32)
@@ -816,12 +1450,29 @@ pub fn get_sessions(sub_name: &str, page_num: i32) -> (Vec<types::Session>, i32)
     
 
 
-pub fn add_session(principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String) -> bool {
+#[allow(clippy::too_many_arguments)]
+pub fn add_session(principal: String, username: String, name: String, location: String, daytime: String, contact: String, comment: String, recurring: String, schedule: Option<types::SessionSchedule>, coordinates: Option<types::Coordinates>) -> bool {
     ic_cdk::println!("Adding session: principal: {}, username: {}, name: {}", principal, username, name); 
 
+    if let Err(err) = check_name(&name) {
+        ic_cdk::println!("Rejected session name: {}", err);
+        return false;
+    }
+    if let Some(Err(err)) = schedule.as_ref().map(schedule::validate) {
+        ic_cdk::println!("Rejected session schedule: {}", err);
+        return false;
+    }
+    if !valid_coordinates(&coordinates) {
+        ic_cdk::println!("Rejected session coordinates: {:?}", coordinates);
+        return false;
+    }
+    // Clients that only know the free text fields still get a calendar entry
+    let schedule = schedule.or_else(|| legacy_schedule(&daytime, &recurring));
+
     SESSION_STORE.with(|session_store| {
+        let id = allocate_id(SESSION_IDS, u32::MAX as u64, |id| session_store.borrow().contains_key(&(id as u32))) as u32;
         let new_session = types::Session {
-            id: ic_cdk::api::time() as u32,  
+            id,
             principal,
             username,
             name,
@@ -829,14 +1480,20 @@ pub fn add_session(principal: String, username: String, name: String, location:
             daytime,
             contact,
             comment,
-            recurring
+            recurring,
+            schedule,
+            coordinates,
+            co_organizers: None,
         };
 
-        session_store.borrow_mut().insert(new_session.id.clone(), new_session);
+        update_geo_index(&SESSION_GEO_INDEX, new_session.id, None, coordinates);
+        record_session_audit(new_session.id, &new_session.principal, types::SessionAuditAction::Created);
+        session_store.borrow_mut().insert(new_session.id, new_session);
         true
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn update_session(
     id: u32,
     principal: String,
@@ -847,38 +1504,125 @@ pub fn update_session(
     contact: String,
     comment: String,
     recurring: String,
+    schedule: Option<Option<types::SessionSchedule>>,
+    coordinates: Option<Option<types::Coordinates>>,
 ) -> bool {
-    SESSION_STORE.with(|session_store| {
+    if let Err(err) = check_name(&name) {
+        ic_cdk::println!("Rejected session name: {}", err);
+        return false;
+    }
+    if let Some(Err(err)) = schedule.as_ref().and_then(Option::as_ref).map(schedule::validate) {
+        ic_cdk::println!("Rejected session schedule: {}", err);
+        return false;
+    }
+    if !valid_coordinates(&coordinates.flatten()) {
+        ic_cdk::println!("Rejected session coordinates: {:?}", coordinates);
+        return false;
+    }
+
+    SESSION_STORE.with(|session_store| {
         let mut store = session_store.borrow_mut();
 
-        // Check if the session exists and if the requesting principal owns the session
-        if let Some(session) = store.get(&id) {
-                // Update the session with new details, preserving the session ID and principal
+        // Check if the session exists and if the requesting principal may edit it
+        if let Some(previous) = store.get(&id) {
+                if !can_edit_session(&previous, &principal) {
+                    ic_cdk::println!("Unauthorized update attempt by {}", principal);
+                    return false;
+                }
+
+                // Update the session with new details, preserving the session ID and owner
                 let updated_session = types::Session {
                     id,
-                    principal,
-                    username,
+                    principal: previous.principal.clone(),
+                    username: if principal == previous.principal { username } else { previous.username.clone() },
                     name,
                     location,
                     daytime,
                     contact,
                     comment,
                     recurring,
+                    // Clients unaware of schedules and coordinates keep the
+                    // existing ones, Some(None) removes them
+                    schedule: schedule.unwrap_or(previous.schedule.clone()),
+                    coordinates: coordinates.unwrap_or(previous.coordinates),
+                    co_organizers: previous.co_organizers.clone(),
                 };
+                update_geo_index(&SESSION_GEO_INDEX, id, previous.coordinates, updated_session.coordinates);
+
+                let changed = changed_session_fields(&previous, &updated_session);
+                if !changed.is_empty() {
+                    record_session_audit(id, &principal, types::SessionAuditAction::Updated(changed));
+                }
 
                 // Insert the updated session back into the store
                 store.insert(id, updated_session);
-                return true; // Update successful
+                true // Update successful
         
         } else {
             ic_cdk::println!("Session with ID {} not found", id);
-            return false; // Session not found
+            false // Session not found
         }
     })
 }
     
 
 
+const MAX_UPCOMING_WINDOW_NS: u64 = 366 * 86_400_000_000_000;
+
+// Occurrences of all scheduled sessions starting in [from, to), in order.
+// The window is limited to a year.
+pub fn get_upcoming_sessions(from: u64, to: u64) -> Vec<types::SessionOccurrence> {
+    let to = to.min(from.saturating_add(MAX_UPCOMING_WINDOW_NS));
+    let mut res: Vec<types::SessionOccurrence> = SESSION_STORE.with(|session_store| {
+        session_store
+            .borrow()
+            .iter()
+            .filter_map(|(_, session)| {
+                let schedule = session.schedule.clone()?;
+                Some(
+                    schedule::occurrences(&schedule, from, to, schedule::MAX_OCCURRENCES)
+                        .into_iter()
+                        .filter(|(start, _)| *start >= from)
+                        .map(|(start, end)| types::SessionOccurrence {
+                            session: with_attendance(session.clone(), Some(start)),
+                            start,
+                            end,
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .flatten()
+            .collect()
+    });
+
+    res.sort_by_key(|occurrence| occurrence.start);
+    res.truncate(schedule::MAX_OCCURRENCES);
+    res
+}
+
+
+// Sessions created before schedules existed are assumed to be in this timezone
+const LEGACY_SESSION_TIMEZONE: &str = "Europe/Dublin";
+
+// Derives a schedule from the free text day, time and recurrence of older
+// sessions. Sessions whose text can't be understood are left unscheduled.
+fn migrate_session_schedule(mut session: types::Session) -> Option<types::Session> {
+    if session.schedule.is_some() {
+        return None;
+    }
+    session.schedule = legacy_schedule(&session.daytime, &session.recurring);
+    if session.schedule.is_none() {
+        ic_cdk::println!("Could not derive a schedule for session {}", session.id);
+    }
+    session.schedule.is_some().then_some(session)
+}
+
+
+fn legacy_schedule(daytime: &str, recurring: &str) -> Option<types::SessionSchedule> {
+    schedule::parse_legacy(daytime, recurring, ic_cdk::api::time(), LEGACY_SESSION_TIMEZONE)
+}
+
+
 pub fn delete_session(id: u32, principal: String) -> bool {
     SESSION_STORE.with(|session_store| {
         let mut store = session_store.borrow_mut();
@@ -887,35 +1631,45 @@ pub fn delete_session(id: u32, principal: String) -> bool {
            
             if session.principal == principal {
                 store.remove(&id);  
-                return true;        
+                remove_session_follows(id);
+                remove_session_attendance(id);
+                remove_session_audit(id);
+                remove_played_sets(id);
+                update_geo_index(&SESSION_GEO_INDEX, id, session.coordinates, None);
+                true
             } else {
                 ic_cdk::println!("Unauthorized delete attempt by {}", principal);
-                return false;     
+                false
             }
         } else {
             ic_cdk::println!("Session with ID {} not found", id);
-            return false;          
+            false
         }
     })
 }
 
-pub fn get_instruments(sub_name: &str, page_num: i32) -> (Vec<types::Instrument>, i32) {
+pub fn get_instruments(sub_name: &str, page_num: i32, filter: Option<types::InstrumentFilter>) -> (Vec<types::Instrument>, i32) {
+    let filter = filter.unwrap_or_default();
     INSTRUMENT_STORE.with(|instrument_store| {
-        let res: Vec<types::Instrument> = instrument_store
+        let mut res: Vec<types::Instrument> = instrument_store
             .borrow()
             .iter()
             .filter(|(_, instrument)| 
                 instrument.name.to_lowercase().contains(&sub_name.to_lowercase()) ||
                 instrument.location.to_lowercase().contains(&sub_name.to_lowercase())
             )
+            .filter(|(_, instrument)| listed_with_status(instrument, filter.status))
+            .filter(|(_, instrument)| matches_price(instrument, &filter))
+            .filter(|(_, instrument)| matches_details(instrument, &filter))
             .map(|(_, instrument)| instrument.clone())
             .collect();
+        sort_instruments(&mut res, filter.sort);
 
         let result: Vec<types::Instrument> = res
             .iter()
             .skip(page_num as usize * 15) // Pagination logic: Skip previous pages
             .enumerate()
-            .filter(|(index, _)| index.clone() < 15) // Limit to 15 instruments per page
+            .filter(|(index, _)| *index < 15) // Limit to 15 instruments per page
             .map(|(_, instrument)| instrument.clone())
             .collect();
 
@@ -923,9 +1677,10 @@ pub fn get_instruments(sub_name: &str, page_num: i32) -> (Vec<types::Instrument>
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn add_instrument(
     seller_principal: String,
-    buyer_principal: String,
+    _buyer_principal: String, // Ignored, the seller no longer picks the buyer up front
     username: String,
     name: String,
     location: String,
@@ -933,24 +1688,72 @@ pub fn add_instrument(
     comment: String,
     price: String,
     photos: Vec<Vec<u8>>,
+    coordinates: Option<types::Coordinates>,
+    draft: Option<bool>,
+    asking_price: Option<types::Price>,
+    details: Option<types::ListingDetails>,
 ) -> bool {
     ic_cdk::println!("Adding instrument: seller_principal: {}, username: {}, name: {}", seller_principal, username, name);
 
+    if let Err(err) = check_name(&name) {
+        ic_cdk::println!("Rejected instrument name: {}", err);
+        return false;
+    }
+    if !valid_coordinates(&coordinates) {
+        ic_cdk::println!("Rejected instrument coordinates: {:?}", coordinates);
+        return false;
+    }
+    if let Some(Err(err)) = asking_price.as_ref().map(price::validate) {
+        ic_cdk::println!("Rejected instrument price: {}", err);
+        return false;
+    }
+    let asking_price = asking_price.or_else(|| price::parse(&price, DEFAULT_CURRENCY));
+    let details = details.unwrap_or_default();
+    if let Err(err) = validate_listing_details(&details) {
+        ic_cdk::println!("Rejected instrument details: {}", err);
+        return false;
+    }
+
+    let photo_ids = match store_images(photos) {
+        Ok(photo_ids) => photo_ids,
+        Err(err) => {
+            ic_cdk::println!("Rejected instrument photos: {}", err);
+            return false;
+        }
+    };
+
     INSTRUMENT_STORE.with(|instrument_store| {
+        let id = allocate_id(INSTRUMENT_IDS, u32::MAX as u64, |id| instrument_store.borrow().contains_key(&(id as u32))) as u32;
+        let now = ic_cdk::api::time();
+        let status = if draft.unwrap_or(false) { types::ListingStatus::Draft } else { types::ListingStatus::Active };
         let new_instrument = types::Instrument {
-            id: ic_cdk::api::time() as u32, 
+            id,
             seller_principal,
-            buyer_principal, 
+            buyer_principal: String::new(), // Only known once the listing is reserved or sold
             username,
             name,
             location,
             product,
             comment,
             price,
-            photos,
+            asking_price,
+            photos: None,
+            thumbnail_ids: Some(thumbnails(&photo_ids)),
+            photo_ids: Some(photo_ids),
+            coordinates,
+            status: Some(status),
+            created_at: Some(now),
+            expires_at: (status == types::ListingStatus::Active).then_some(now + LISTING_LIFETIME_NS),
+            sold_at: None,
+            category: details.category,
+            condition: details.condition,
+            maker: details.maker.map(|maker| maker.trim().to_string()).filter(|maker| !maker.is_empty()),
+            year: details.year,
+            updated_at: None,
         };
 
-        instrument_store.borrow_mut().insert(new_instrument.id.clone(), new_instrument);
+        update_geo_index(&INSTRUMENT_GEO_INDEX, new_instrument.id, None, coordinates);
+        instrument_store.borrow_mut().insert(new_instrument.id, new_instrument);
         true
     })
 }
@@ -962,36 +1765,143 @@ pub fn delete_instrument(id: u32, seller_principal: String) -> bool {
         if let Some(instrument) = store.get(&id) {
             if instrument.seller_principal == seller_principal {
                 store.remove(&id); // Remove the instrument if the seller matches
-                return true;
+                release_blobs(&instrument.photo_ids);
+                remove_offers(id, None);
+                update_geo_index(&INSTRUMENT_GEO_INDEX, id, instrument.coordinates, None);
+                true
             } else {
                 ic_cdk::println!("Unauthorized delete attempt by {}", seller_principal);
-                return false;
+                false
             }
         } else {
             ic_cdk::println!("Instrument with ID {} not found", id);
-            return false;
+            false
         }
     })
 }
 
 
+// Applies `update` to a listing of `seller_principal`. Sold listings can't be
+// edited anymore.
+pub fn update_instrument(seller_principal: String, id: u32, update: types::InstrumentUpdate) -> Result<types::Instrument, String> {
+    let mut instrument = seller_listing(id, &seller_principal)?;
+    match listing_status(&instrument) {
+        types::ListingStatus::Sold => return Err("Sold listings can't be edited".to_string()),
+        // The buyer agreed to the price it had
+        types::ListingStatus::Reserved if update.price.is_some() || update.asking_price.is_some() => {
+            return Err("The price of a reserved listing can't be changed".to_string())
+        }
+        _ => {}
+    }
+    let previous = instrument.clone();
+
+    if let Some(coordinates) = update.coordinates {
+        if !valid_coordinates(&coordinates) {
+            return Err("Invalid coordinates".to_string());
+        }
+        instrument.coordinates = coordinates;
+    }
+    if let Some(Err(err)) = update.asking_price.as_ref().map(price::validate) {
+        return Err(err);
+    }
+    let details_changed = update.category.is_some() || update.condition.is_some() || update.year.is_some() || update.maker.is_some();
+    if let Some(category) = update.category {
+        instrument.category = category;
+    }
+    if let Some(condition) = update.condition {
+        instrument.condition = condition;
+    }
+    if let Some(year) = update.year {
+        instrument.year = year;
+    }
+    if let Some(maker) = update.maker {
+        instrument.maker = Some(maker.trim().to_string()).filter(|maker| !maker.is_empty());
+    }
+    if details_changed {
+        validate_listing_details(&types::ListingDetails {
+            category: instrument.category.clone(),
+            condition: instrument.condition,
+            maker: instrument.maker.clone(),
+            year: instrument.year,
+        })?;
+    }
+
+    if let Some(name) = update.name {
+        check_name(&name)?;
+        instrument.name = name;
+    }
+    if let Some(location) = update.location {
+        instrument.location = location;
+    }
+    if let Some(product) = update.product {
+        instrument.product = product;
+    }
+    if let Some(comment) = update.comment {
+        instrument.comment = comment;
+    }
+    // A new price text is understood again unless the structured price comes with it
+    if let Some(price) = update.price {
+        instrument.asking_price = price::parse(&price, DEFAULT_CURRENCY);
+        instrument.price = price;
+    }
+    if update.asking_price.is_some() {
+        instrument.asking_price = update.asking_price;
+    }
+
+    let mut removed_photos = vec![];
+    if let Some(changes) = update.photos {
+        let mut photo_ids = instrument.photo_ids.clone().unwrap_or_default();
+        for blob_id in changes.remove.iter() {
+            let position = photo_ids
+                .iter()
+                .position(|photo_id| photo_id == blob_id)
+                .ok_or(format!("Photo {} is not part of this listing", blob_id))?;
+            removed_photos.push(photo_ids.remove(position));
+        }
+
+        let photo_count = photo_ids.len() + changes.add.iter().filter(|photo| !photo.is_empty()).count();
+        if let Some(order) = changes.order.as_ref() {
+            let mut positions = order.clone();
+            positions.sort();
+            if !positions.iter().copied().eq(0..photo_count as u32) {
+                return Err("Photo order must list every photo once".to_string());
+            }
+        }
+
+        // Nothing can fail past this point, so the new photos are stored last
+        photo_ids.extend(store_images(changes.add)?);
+        if let Some(order) = changes.order {
+            photo_ids = order.iter().map(|position| photo_ids[*position as usize].clone()).collect();
+        }
+        instrument.thumbnail_ids = Some(thumbnails(&photo_ids));
+        instrument.photo_ids = Some(photo_ids);
+    }
+
+    instrument.updated_at = Some(ic_cdk::api::time());
+    release_blobs(&Some(removed_photos));
+    update_geo_index(&INSTRUMENT_GEO_INDEX, id, previous.coordinates, instrument.coordinates);
+    INSTRUMENT_STORE.with(|instrument_store| instrument_store.borrow_mut().insert(id, instrument.clone()));
+    Ok(instrument)
+}
+
+
 pub fn get_profile_count() -> u64 {
     PROFILE_STORE.with(|profile_store| {
-        profile_store.borrow().len() as u64  // Return the count of profiles
+        profile_store.borrow().len()  // Return the count of profiles
     })
 }
 
 
 pub fn get_tune_count() -> u64 {
     TUNE_STORE.with(|tune_store| {
-        tune_store.borrow().len() as u64  // Return the count of tunes
+        tune_store.borrow().len()  // Return the count of tunes
     })
 }
 
 
 pub fn get_session_count() -> u64 {
     SESSION_STORE.with(|session_store| {
-        session_store.borrow().len() as u64  // Return the count of sessions
+        session_store.borrow().len()  // Return the count of sessions
     })
 }
 
@@ -1058,8 +1968,12 @@ pub fn add_forum(
     forum_name: String,
     comment: String,
 ) -> bool {
+    if let Err(err) = check_name(&forum_name) {
+        ic_cdk::println!("Rejected forum name: {}", err);
+        return false;
+    }
     FORUM_STORE.with(|forum_store| {
-        let id = ic_cdk::api::time(); // Unique ID
+        let id = allocate_id(FORUM_IDS, u64::MAX, |id| forum_store.borrow().contains_key(&id));
         let new_forum = Forum {
             id,
             poster_principal: principal.clone(),
@@ -1085,15 +1999,40 @@ pub fn add_post_to_forum(
     comment: String,
     photos: Option<Vec<Vec<u8>>>,
 ) -> bool {
+    // Users the thread starter blocked, or who blocked them, can't reply
+    let starter = FORUM_STORE.with(|forum_store| forum_store.borrow().get(&forum_id)).map(|forum| forum.poster_principal);
+    if starter.is_some_and(|starter| is_blocked(&starter, &principal)) {
+        ic_cdk::println!("{} can't post in forum {}", principal, forum_id);
+        return false;
+    }
 
-    FORUM_DATA_STORE.with(|forum_data_store| {
+    let photo_ids = match photos.map(store_images).transpose() {
+        Ok(photo_ids) => photo_ids,
+        Err(err) => {
+            ic_cdk::println!("Rejected forum photos: {}", err);
+            return false;
+        }
+    };
+
+    let author = principal.clone();
+    let added = FORUM_DATA_STORE.with(|forum_data_store| {
         FORUM_STORE.with(|forum_store| {
 
             let mut forum_store = forum_store.borrow_mut();
             let mut forum_data_store = forum_data_store.borrow_mut();
 
             if let Some(mut forum) = forum_store.get(&forum_id) {
-                let post_id = ic_cdk::api::time(); // Unique ID for post
+                // The thread starter and everyone who replied before hear about the new post
+                let mut participants: Vec<String> = vec![forum.poster_principal.clone()];
+                for post_id in forum.threads.iter().flatten() {
+                    if let Some(post) = forum_data_store.get(post_id) {
+                        if !participants.contains(&post.principal) {
+                            participants.push(post.principal);
+                        }
+                    }
+                }
+
+                let post_id = allocate_id(FORUM_POST_IDS, u64::MAX, |id| forum_data_store.contains_key(&id));
                 let new_post = ForumData {
                     id: post_id,
                     forum_id: Some(forum_id),
@@ -1102,8 +2041,10 @@ pub fn add_post_to_forum(
                     principal,
                     created_at: ic_cdk::api::time(),
                     updated_at: None,
-                    photos,
+                    photos: None,
                     likes: 0,
+                    thumbnail_ids: photo_ids.as_ref().map(|photo_ids| thumbnails(photo_ids)),
+                    photo_ids,
                 };
 
                 // Add the post ID to the forum's threads
@@ -1113,21 +2054,34 @@ pub fn add_post_to_forum(
                     forum.threads = Some(vec![post_id]); // Initialize threads if None
                 }
 
+                let text = format!("replied in \"{}\"", forum.forum_name);
                 forum_store.insert(forum_id, forum); // Update forum
                 forum_data_store.insert(post_id, new_post); // Add new post
 
-                true
+                Some((participants, text))
             } else {
+                release_blobs(&photo_ids);
                 ic_cdk::println!("Forum with ID {} not found", forum_id);
-                false
+                None
             }
         })
-    })
+    });
+
+    match added {
+        Some((participants, text)) => {
+            for participant in participants.iter().filter(|p| **p != author && !p.is_empty()) {
+                notify(participant, types::NotificationKind::ForumReply, &author, Some(forum_id), text.clone());
+            }
+            true
+        }
+        None => false,
+    }
 }
 
 
 
-pub fn like_post(post_id: u64, principal: String) -> bool {
+#[allow(dead_code)]
+pub fn like_post(post_id: u64, _principal: String) -> bool {
     FORUM_DATA_STORE.with(|forum_data_store| {
         let mut store = forum_data_store.borrow_mut();
 
@@ -1160,15 +2114,24 @@ pub fn update_forum_post(
                 }
                 if let Some(new_photos) = photos {
                     if !new_photos.is_empty() {
-                        post.photos = Some(new_photos);
+                        let photo_ids = match store_images(new_photos) {
+                            Ok(photo_ids) => photo_ids,
+                            Err(err) => {
+                                ic_cdk::println!("Rejected forum photos: {}", err);
+                                return false;
+                            }
+                        };
+                        release_blobs(&post.photo_ids);
+                        post.thumbnail_ids = Some(thumbnails(&photo_ids));
+                        post.photo_ids = Some(photo_ids);
                     }
                 }
                 post.updated_at = Some(ic_cdk::api::time());
                 store.insert(post_id, post);
-                return true;
+                true
             } else {
                 ic_cdk::println!("Unauthorized update attempt by {}", principal);
-                return false;
+                false
             }
         } else {
             ic_cdk::println!("Post with ID {} not found", post_id);
@@ -1180,7 +2143,7 @@ pub fn update_forum_post(
 
 
 
-pub fn delete_forum(forum_id: u64, principal: String) -> bool {
+pub fn delete_forum(forum_id: u64, _principal: String) -> bool {
     /*
     if !is_admin(&principal) {
         ic_cdk::println!("Unauthorized delete attempt by {}", principal);
@@ -1195,14 +2158,15 @@ pub fn delete_forum(forum_id: u64, principal: String) -> bool {
 
             if forum_store.remove(&forum_id).is_some() {
                 // Manually iterate and remove all posts related to the forum
-                let posts_to_remove: Vec<u64> = forum_data_store
+                let posts_to_remove: Vec<ForumData> = forum_data_store
                     .iter()
-                    .filter(|(_, post)| post.id == forum_id)
-                    .map(|(post_id, _)| post_id) // Use post_id directly
+                    .filter(|(_, post)| post.forum_id == Some(forum_id))
+                    .map(|(_, post)| post)
                     .collect();
 
-                for post_id in posts_to_remove {
-                    forum_data_store.remove(&post_id);
+                for post in posts_to_remove {
+                    forum_data_store.remove(&post.id);
+                    release_blobs(&post.photo_ids);
                 }
 
                 ic_cdk::println!("Forum with ID {} and its posts were deleted", forum_id);
@@ -1217,12 +2181,13 @@ pub fn delete_forum(forum_id: u64, principal: String) -> bool {
 
 
 
-pub fn delete_post(post_id: u64, principal: String) -> bool {
+pub fn delete_post(post_id: u64, _principal: String) -> bool {
 
     FORUM_DATA_STORE.with(|forum_data_store| {
         let mut store = forum_data_store.borrow_mut();
 
-        if store.remove(&post_id).is_some() {
+        if let Some(post) = store.remove(&post_id) {
+            release_blobs(&post.photo_ids);
             ic_cdk::println!("Post with ID {} was deleted", post_id);
             true
         } else {
@@ -1232,12 +2197,13 @@ pub fn delete_post(post_id: u64, principal: String) -> bool {
     })
 }
 
-pub fn get_forum_posts(forum_id: u64, page_num: i32) -> Result<(Vec<ForumData>, i32), String> {
+pub fn get_forum_posts(forum_id: u64, page_num: i32, viewer: Option<String>) -> Result<(Vec<ForumData>, i32), String> {
     FORUM_DATA_STORE.with(|forum_data_store| {
         let all_posts: Vec<ForumData> = forum_data_store
             .borrow()
             .iter()
             .filter(|(_, post)| post.forum_id == Some(forum_id))
+            .filter(|(_, post)| !viewer.as_ref().is_some_and(|viewer| is_blocked(viewer, &post.principal)))
             .map(|(_, post)| post.clone())
             .collect();
 
@@ -1282,12 +2248,13 @@ pub fn get_forum_posts(forum_ids: u64, page_num: i32) -> (Vec<ForumData>, i32) {
 }
 */
 
-pub fn get_forum_posts_without_photos(forum_id: u64, page_num: i32) -> (Vec<ForumData>, i32) {
+pub fn get_forum_posts_without_photos(forum_id: u64, page_num: i32, viewer: Option<String>) -> (Vec<ForumData>, i32) {
     FORUM_DATA_STORE.with(|forum_data_store| {
         let posts: Vec<ForumData> = forum_data_store
             .borrow()
             .iter()
             .filter(|(_, post)| post.forum_id == Some(forum_id))
+            .filter(|(_, post)| !viewer.as_ref().is_some_and(|viewer| is_blocked(viewer, &post.principal)))
             .map(|(_, post)| {
                 let mut post_without_photos = post.clone();
                 post_without_photos.photos = None; // Exclude photos
@@ -1309,22 +2276,4048 @@ pub fn get_forum_posts_without_photos(forum_id: u64, page_num: i32) -> (Vec<Foru
 
 
     
+    #[allow(dead_code)]
     pub fn get_post_photos(post_id: u64) -> Option<Vec<Vec<u8>>> {
         FORUM_DATA_STORE.with(|forum_data_store| {
             forum_data_store
                 .borrow()
                 .get(&post_id)
-                .and_then(|post| post.photos.clone())
+                .and_then(|post| post.photo_ids)
+                .map(|photo_ids| photo_ids.iter().filter_map(|id| get_blob_bytes(id)).collect())
         })
     }
     
 
 
+#[allow(dead_code)]
 pub fn is_admin(principal: &String) -> bool {
-    let admin_principals = vec![
+    let admin_principals = [
         "zhaxx-r7zkt-gffvf-jvw46-hxhj5-xewo7-cwrq6-nmza3-wpiwz-swnet-vqe".to_string(), // Replace with actual admin IDs
     ];
     admin_principals.contains(principal)
 }
 
 
+
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Recordings
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Every chunk except the last one must be exactly this size, which keeps
+// chunk lookups for a given byte offset trivial.
+pub const RECORDING_CHUNK_SIZE: u64 = 1_048_576;
+const MAX_RECORDING_SIZE: u64 = 50 * 1_048_576;
+const RECORDING_QUOTA_PER_USER: u64 = 200 * 1_048_576;
+const STALE_UPLOAD_NS: u64 = 86_400_000_000_000; // 24 hours
+const RECORDING_MIME_TYPES: [&str; 6] = [
+    "audio/ogg",
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/webm",
+    "audio/wav",
+    "audio/flac",
+];
+
+
+fn remove_recording(store: &mut RecordingStore, recording: &types::Recording) {
+    RECORDING_CHUNK_STORE.with(|chunk_store| {
+        let mut chunk_store = chunk_store.borrow_mut();
+        for index in 0..recording.chunk_count {
+            chunk_store.remove(&(recording.id, index));
+        }
+    });
+    store.remove(&recording.id);
+}
+
+
+pub fn start_recording_upload(
+    principal: String,
+    username: String,
+    tune_title: String,
+    title: String,
+    mime_type: String,
+    size: u64,
+) -> Result<u64, String> {
+    if !TUNE_STORE.with(|tune_store| tune_store.borrow().contains_key(&tune_title)) {
+        return Err(format!("Tune '{}' not found", tune_title));
+    }
+    if !RECORDING_MIME_TYPES.contains(&mime_type.as_str()) {
+        return Err(format!("Unsupported audio type '{}'", mime_type));
+    }
+    if size == 0 || size > MAX_RECORDING_SIZE {
+        return Err(format!("Recordings must be between 1 byte and {} bytes", MAX_RECORDING_SIZE));
+    }
+
+    RECORDING_STORE.with(|recording_store| {
+        let mut store = recording_store.borrow_mut();
+        let now = ic_cdk::api::time();
+
+        // Drop this user's uploads that were started but never finished
+        let stale: Vec<types::Recording> = store
+            .iter()
+            .filter(|(_, recording)| {
+                recording.uploader_principal == principal
+                    && !recording.completed
+                    && now.saturating_sub(recording.created_at) > STALE_UPLOAD_NS
+            })
+            .map(|(_, recording)| recording)
+            .collect();
+        for recording in stale.iter() {
+            remove_recording(&mut store, recording);
+        }
+
+        let used: u64 = store
+            .iter()
+            .filter(|(_, recording)| recording.uploader_principal == principal)
+            .map(|(_, recording)| recording.size)
+            .sum();
+        if used + size > RECORDING_QUOTA_PER_USER {
+            return Err(format!(
+                "Recording quota exceeded: {} of {} bytes used",
+                used, RECORDING_QUOTA_PER_USER
+            ));
+        }
+
+        let id = allocate_id(RECORDING_IDS, u64::MAX, |id| store.contains_key(&id));
+        let recording = types::Recording {
+            id,
+            tune_title,
+            uploader_principal: principal,
+            username,
+            title,
+            mime_type,
+            size,
+            chunk_count: size.div_ceil(RECORDING_CHUNK_SIZE) as u32,
+            created_at: now,
+            completed: false,
+        };
+        store.insert(id, recording);
+        Ok(id)
+    })
+}
+
+
+pub fn upload_recording_chunk(recording_id: u64, principal: String, chunk_index: u32, data: Vec<u8>) -> bool {
+    let recording = match RECORDING_STORE.with(|recording_store| recording_store.borrow().get(&recording_id)) {
+        Some(recording) => recording,
+        None => {
+            ic_cdk::println!("Recording with ID {} not found", recording_id);
+            return false;
+        }
+    };
+
+    if recording.uploader_principal != principal || recording.completed {
+        ic_cdk::println!("Unauthorized chunk upload by {}", principal);
+        return false;
+    }
+    if chunk_index >= recording.chunk_count {
+        return false;
+    }
+
+    // All chunks are full-sized except possibly the last one
+    let expected_size = if chunk_index + 1 == recording.chunk_count {
+        recording.size - RECORDING_CHUNK_SIZE * chunk_index as u64
+    } else {
+        RECORDING_CHUNK_SIZE
+    };
+    if data.len() as u64 != expected_size {
+        ic_cdk::println!("Chunk {} of recording {} has the wrong size", chunk_index, recording_id);
+        return false;
+    }
+
+    RECORDING_CHUNK_STORE.with(|chunk_store| {
+        chunk_store.borrow_mut().insert((recording_id, chunk_index), data);
+    });
+    true
+}
+
+
+pub fn finish_recording_upload(recording_id: u64, principal: String) -> bool {
+    RECORDING_STORE.with(|recording_store| {
+        let mut store = recording_store.borrow_mut();
+
+        if let Some(mut recording) = store.get(&recording_id) {
+            if recording.uploader_principal != principal {
+                ic_cdk::println!("Unauthorized finish attempt by {}", principal);
+                return false;
+            }
+
+            let all_chunks_present = RECORDING_CHUNK_STORE.with(|chunk_store| {
+                let chunk_store = chunk_store.borrow();
+                (0..recording.chunk_count).all(|index| chunk_store.contains_key(&(recording_id, index)))
+            });
+            if !all_chunks_present {
+                ic_cdk::println!("Recording with ID {} is missing chunks", recording_id);
+                return false;
+            }
+
+            recording.completed = true;
+            store.insert(recording_id, recording);
+            true
+        } else {
+            ic_cdk::println!("Recording with ID {} not found", recording_id);
+            false
+        }
+    })
+}
+
+
+pub fn get_recording(recording_id: u64) -> Option<types::Recording> {
+    RECORDING_STORE.with(|recording_store| {
+        recording_store
+            .borrow()
+            .get(&recording_id)
+            .filter(|recording| recording.completed)
+    })
+}
+
+
+pub fn get_recording_chunk(recording_id: u64, chunk_index: u32) -> Option<Vec<u8>> {
+    get_recording(recording_id)?;
+    RECORDING_CHUNK_STORE.with(|chunk_store| chunk_store.borrow().get(&(recording_id, chunk_index)))
+}
+
+
+pub fn get_tune_recordings(tune_title: String, page_num: i32) -> (Vec<types::Recording>, i32) {
+    RECORDING_STORE.with(|recording_store| {
+        let recordings: Vec<types::Recording> = recording_store
+            .borrow()
+            .iter()
+            .filter(|(_, recording)| recording.completed && recording.tune_title == tune_title)
+            .map(|(_, recording)| recording)
+            .collect();
+
+        let result = recordings
+            .iter()
+            .skip(page_num as usize * 15)
+            .take(15)
+            .cloned()
+            .collect();
+
+        (result, recordings.len() as i32)
+    })
+}
+
+
+pub fn delete_recording(recording_id: u64, principal: String) -> bool {
+    RECORDING_STORE.with(|recording_store| {
+        let mut store = recording_store.borrow_mut();
+
+        if let Some(recording) = store.get(&recording_id) {
+            if recording.uploader_principal == principal {
+                remove_recording(&mut store, &recording);
+                true
+            } else {
+                ic_cdk::println!("Unauthorized delete attempt by {}", principal);
+                false
+            }
+        } else {
+            ic_cdk::println!("Recording with ID {} not found", recording_id);
+            false
+        }
+    })
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Blob storage
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Blobs are content addressed: the id is the hex encoded SHA-256 of the
+// content, so identical images are only stored once. Records hold blob ids
+// and keep the blob alive through its reference count.
+pub const BLOB_CHUNK_SIZE: u64 = 1_048_576;
+const MAX_BLOB_SIZE: u64 = 30 * 1_048_576;
+const UNREFERENCED_BLOB_TTL_NS: u64 = 86_400_000_000_000; // 24 hours
+
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+fn blob_key(blob_id: &str) -> Option<[u8; 32]> {
+    if blob_id.len() != 64 || !blob_id.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&blob_id[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+
+fn insert_blob(hash: [u8; 32], content_type: &str, size: u64, chunks: Vec<Vec<u8>>) {
+    BLOB_STORE.with(|blob_store| {
+        let mut store = blob_store.borrow_mut();
+        if store.contains_key(&hash) {
+            return; // Already stored under the same content hash
+        }
+
+        let chunk_count = chunks.len() as u32;
+        BLOB_CHUNK_STORE.with(|chunk_store| {
+            let mut chunk_store = chunk_store.borrow_mut();
+            for (index, chunk) in chunks.into_iter().enumerate() {
+                chunk_store.insert((hash, index as u32), chunk);
+            }
+        });
+
+        store.insert(hash, types::BlobInfo {
+            id: to_hex(&hash),
+            content_type: content_type.to_string(),
+            size,
+            chunk_count,
+            ref_count: 0,
+            created_at: ic_cdk::api::time(),
+            width: None,
+            height: None,
+            thumbnail_id: None,
+        });
+    })
+}
+
+
+// Stores `data` without taking a reference; callers that attach the blob to
+// a record must follow up with `retain_blob`.
+pub fn put_blob(data: &[u8], content_type: &str) -> String {
+    let hash: [u8; 32] = Sha256::digest(data).into();
+    let chunks = data
+        .chunks(BLOB_CHUNK_SIZE as usize)
+        .map(|chunk| chunk.to_vec())
+        .collect();
+    insert_blob(hash, content_type, data.len() as u64, chunks);
+    to_hex(&hash)
+}
+
+
+pub fn retain_blob(blob_id: &str) -> bool {
+    let key = match blob_key(blob_id) {
+        Some(key) => key,
+        None => return false,
+    };
+    BLOB_STORE.with(|blob_store| {
+        let mut store = blob_store.borrow_mut();
+        if let Some(mut blob) = store.get(&key) {
+            blob.ref_count += 1;
+            store.insert(key, blob);
+            true
+        } else {
+            false
+        }
+    })
+}
+
+
+pub fn release_blob(blob_id: &str) {
+    let key = match blob_key(blob_id) {
+        Some(key) => key,
+        None => return,
+    };
+    let removed = BLOB_STORE.with(|blob_store| {
+        let mut store = blob_store.borrow_mut();
+        let mut blob = store.get(&key)?;
+        blob.ref_count = blob.ref_count.saturating_sub(1);
+        if blob.ref_count == 0 {
+            remove_blob(&mut store, &key, &blob);
+            Some(blob)
+        } else {
+            store.insert(key, blob);
+            None
+        }
+    });
+
+    // The thumbnail lives as long as the image it was generated from
+    if let Some(thumbnail_id) = removed.and_then(|blob| blob.thumbnail_id) {
+        release_blob(&thumbnail_id);
+    }
+}
+
+
+fn remove_blob(store: &mut BlobStore, key: &[u8; 32], blob: &types::BlobInfo) {
+    BLOB_CHUNK_STORE.with(|chunk_store| {
+        let mut chunk_store = chunk_store.borrow_mut();
+        for index in 0..blob.chunk_count {
+            chunk_store.remove(&(*key, index));
+        }
+    });
+    store.remove(key);
+}
+
+
+// Validates an image sent with a record, strips its metadata, stores it
+// along with a generated thumbnail and takes a reference on it.
+pub fn store_image(data: Vec<u8>) -> Result<String, String> {
+    let image = images::process(&data)?;
+    let blob_id = put_blob(&image.data, image.content_type);
+
+    if thumbnail_of(&blob_id).is_none() {
+        let thumbnail_id = put_blob(&image.thumbnail, image.thumbnail_content_type);
+        if thumbnail_id != blob_id {
+            retain_blob(&thumbnail_id);
+        }
+        let key = blob_key(&blob_id).unwrap();
+        BLOB_STORE.with(|blob_store| {
+            let mut store = blob_store.borrow_mut();
+            if let Some(mut blob) = store.get(&key) {
+                blob.width = Some(image.width);
+                blob.height = Some(image.height);
+                blob.thumbnail_id = Some(thumbnail_id);
+                store.insert(key, blob);
+            }
+        });
+    }
+
+    retain_blob(&blob_id);
+    Ok(blob_id)
+}
+
+
+// Stores every photo or none of them
+pub fn store_images(photos: Vec<Vec<u8>>) -> Result<Vec<String>, String> {
+    let mut blob_ids = vec![];
+    for photo in photos.into_iter().filter(|photo| !photo.is_empty()) {
+        match store_image(photo) {
+            Ok(blob_id) => blob_ids.push(blob_id),
+            Err(err) => {
+                for blob_id in blob_ids.iter() {
+                    release_blob(blob_id);
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(blob_ids)
+}
+
+
+// An empty avatar clears it; invalid images are rejected like a taken username.
+fn store_avatar(avatar: Vec<u8>) -> Result<Option<String>, String> {
+    if avatar.is_empty() {
+        return Ok(None);
+    }
+    store_image(avatar).map(Some).map_err(|err| format!("Invalid avatar: {}", err))
+}
+
+
+pub fn thumbnail_of(blob_id: &str) -> Option<String> {
+    get_blob_info(blob_id.to_string()).and_then(|blob| blob.thumbnail_id)
+}
+
+
+// Previews for a list of photos, falling back to the original when a photo
+// has no thumbnail.
+pub fn thumbnails(blob_ids: &[String]) -> Vec<String> {
+    blob_ids
+        .iter()
+        .map(|blob_id| thumbnail_of(blob_id).unwrap_or(blob_id.clone()))
+        .collect()
+}
+
+
+// Legacy images are kept even when they fail validation, they just don't
+// get a thumbnail.
+fn store_legacy_image(data: Vec<u8>) -> Option<String> {
+    if data.is_empty() {
+        return None;
+    }
+    store_image(data.clone()).ok().or_else(|| {
+        let blob_id = put_blob(&data, "application/octet-stream");
+        retain_blob(&blob_id);
+        Some(blob_id)
+    })
+}
+
+
+pub fn release_blobs(blob_ids: &Option<Vec<String>>) {
+    for blob_id in blob_ids.iter().flatten() {
+        release_blob(blob_id);
+    }
+}
+
+
+pub fn get_blob_info(blob_id: String) -> Option<types::BlobInfo> {
+    let key = blob_key(&blob_id)?;
+    BLOB_STORE.with(|blob_store| blob_store.borrow().get(&key))
+}
+
+
+pub fn get_blob_chunk(blob_id: String, chunk_index: u32) -> Option<Vec<u8>> {
+    let key = blob_key(&blob_id)?;
+    BLOB_CHUNK_STORE.with(|chunk_store| chunk_store.borrow().get(&(key, chunk_index)))
+}
+
+
+// Reassembles a whole blob, only meant for small blobs such as photos.
+pub fn get_blob_bytes(blob_id: &str) -> Option<Vec<u8>> {
+    let blob = get_blob_info(blob_id.to_string())?;
+    let mut data = Vec::with_capacity(blob.size as usize);
+    for index in 0..blob.chunk_count {
+        data.extend(get_blob_chunk(blob_id.to_string(), index)?);
+    }
+    Some(data)
+}
+
+
+// Blobs are served from the canister origin, so their type is never taken
+// from the client: anything that isn't a known image or audio format is
+// served as a download.
+const FALLBACK_CONTENT_TYPE: &str = "application/octet-stream";
+
+
+pub fn sniff_content_type(data: &[u8]) -> &'static str {
+    if let Some(format) = images::sniff(data) {
+        return format.content_type();
+    }
+    if data.starts_with(b"OggS") {
+        "audio/ogg"
+    } else if data.starts_with(b"fLaC") {
+        "audio/flac"
+    } else if data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+        "audio/mpeg"
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        "audio/wav"
+    } else if data.len() >= 8 && &data[4..8] == b"ftyp" {
+        "audio/mp4"
+    } else if data.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        "audio/webm"
+    } else {
+        FALLBACK_CONTENT_TYPE
+    }
+}
+
+
+// The type to serve a stored blob with. Blobs stored before uploads were
+// sniffed may carry whatever type the client declared.
+pub fn servable_content_type(content_type: &str) -> &str {
+    let image = ["image/png", "image/jpeg", "image/webp"].contains(&content_type);
+    if image || RECORDING_MIME_TYPES.contains(&content_type) {
+        content_type
+    } else {
+        FALLBACK_CONTENT_TYPE
+    }
+}
+
+
+// `content_type` is only what the client declared, the stored type is sniffed
+// from the data once the upload is finished.
+pub fn start_blob_upload(principal: String, content_type: String, size: u64) -> Result<u64, String> {
+    if size == 0 || size > MAX_BLOB_SIZE {
+        return Err(format!("Blobs must be between 1 byte and {} bytes", MAX_BLOB_SIZE));
+    }
+
+    BLOB_UPLOAD_STORE.with(|upload_store| {
+        let mut store = upload_store.borrow_mut();
+        let id = allocate_id(BLOB_UPLOAD_IDS, u64::MAX, |id| store.contains_key(&id));
+        store.insert(id, types::BlobUpload {
+            id,
+            uploader_principal: principal,
+            content_type,
+            size,
+            chunk_count: size.div_ceil(BLOB_CHUNK_SIZE) as u32,
+            created_at: ic_cdk::api::time(),
+        });
+        Ok(id)
+    })
+}
+
+
+pub fn upload_blob_chunk(upload_id: u64, principal: String, chunk_index: u32, data: Vec<u8>) -> bool {
+    let upload = match BLOB_UPLOAD_STORE.with(|upload_store| upload_store.borrow().get(&upload_id)) {
+        Some(upload) => upload,
+        None => {
+            ic_cdk::println!("Upload with ID {} not found", upload_id);
+            return false;
+        }
+    };
+
+    if upload.uploader_principal != principal {
+        ic_cdk::println!("Unauthorized chunk upload by {}", principal);
+        return false;
+    }
+    if chunk_index >= upload.chunk_count {
+        return false;
+    }
+
+    let expected_size = if chunk_index + 1 == upload.chunk_count {
+        upload.size - BLOB_CHUNK_SIZE * chunk_index as u64
+    } else {
+        BLOB_CHUNK_SIZE
+    };
+    if data.len() as u64 != expected_size {
+        ic_cdk::println!("Chunk {} of upload {} has the wrong size", chunk_index, upload_id);
+        return false;
+    }
+
+    BLOB_UPLOAD_CHUNK_STORE.with(|chunk_store| {
+        chunk_store.borrow_mut().insert((upload_id, chunk_index), data);
+    });
+    true
+}
+
+
+fn remove_upload(upload: &types::BlobUpload) -> Vec<Vec<u8>> {
+    let chunks = BLOB_UPLOAD_CHUNK_STORE.with(|chunk_store| {
+        let mut chunk_store = chunk_store.borrow_mut();
+        (0..upload.chunk_count)
+            .filter_map(|index| chunk_store.remove(&(upload.id, index)))
+            .collect()
+    });
+    BLOB_UPLOAD_STORE.with(|upload_store| upload_store.borrow_mut().remove(&upload.id));
+    chunks
+}
+
+
+// Moves a completed upload into the blob store. The returned blob id is not
+// referenced yet and is garbage collected unless a record retains it.
+pub fn finish_blob_upload(upload_id: u64, principal: String) -> Result<String, String> {
+    let upload = BLOB_UPLOAD_STORE
+        .with(|upload_store| upload_store.borrow().get(&upload_id))
+        .ok_or(format!("Upload with ID {} not found", upload_id))?;
+    if upload.uploader_principal != principal {
+        return Err("Only the uploader can finish an upload".to_string());
+    }
+
+    let complete = BLOB_UPLOAD_CHUNK_STORE.with(|chunk_store| {
+        let chunk_store = chunk_store.borrow();
+        (0..upload.chunk_count).all(|index| chunk_store.contains_key(&(upload_id, index)))
+    });
+    if !complete {
+        return Err(format!("Upload with ID {} is missing chunks", upload_id));
+    }
+
+    let chunks = remove_upload(&upload);
+    let mut hasher = Sha256::new();
+    for chunk in chunks.iter() {
+        hasher.update(chunk);
+    }
+    let hash: [u8; 32] = hasher.finalize().into();
+
+    let content_type = chunks.first().map(|chunk| sniff_content_type(chunk)).unwrap_or(FALLBACK_CONTENT_TYPE);
+    insert_blob(hash, content_type, upload.size, chunks);
+    Ok(to_hex(&hash))
+}
+
+
+// Called periodically from a timer to drop abandoned uploads and blobs that
+// were uploaded but never attached to anything.
+pub fn sweep_blobs() {
+    let now = ic_cdk::api::time();
+
+    let stale_uploads: Vec<types::BlobUpload> = BLOB_UPLOAD_STORE.with(|upload_store| {
+        upload_store
+            .borrow()
+            .iter()
+            .filter(|(_, upload)| now.saturating_sub(upload.created_at) > UNREFERENCED_BLOB_TTL_NS)
+            .map(|(_, upload)| upload)
+            .collect()
+    });
+    for upload in stale_uploads.iter() {
+        remove_upload(upload);
+    }
+
+    let thumbnails: Vec<String> = BLOB_STORE.with(|blob_store| {
+        let mut store = blob_store.borrow_mut();
+        let unreferenced: Vec<([u8; 32], types::BlobInfo)> = store
+            .iter()
+            .filter(|(_, blob)| {
+                blob.ref_count == 0 && now.saturating_sub(blob.created_at) > UNREFERENCED_BLOB_TTL_NS
+            })
+            .collect();
+        for (key, blob) in unreferenced.iter() {
+            remove_blob(&mut store, key, blob);
+        }
+        unreferenced.into_iter().filter_map(|(_, blob)| blob.thumbnail_id).collect()
+    });
+    for thumbnail_id in thumbnails.iter() {
+        release_blob(thumbnail_id);
+    }
+}
+
+
+// Uses a blob from a chunked upload as the avatar. The upload goes through
+// the same validation as inline avatars, which stores a cleaned copy; the
+// raw upload is left unreferenced and swept later.
+pub fn set_avatar(principal: String, blob_id: Option<String>) -> bool {
+    if !PROFILE_STORE.with(|profile_store| profile_store.borrow().contains_key(&principal)) {
+        ic_cdk::println!("No profile found for principal: {}", principal);
+        return false;
+    }
+
+    let avatar_id = match blob_id {
+        Some(blob_id) => {
+            let blob = match get_blob_info(blob_id.clone()) {
+                Some(blob) => blob,
+                None => {
+                    ic_cdk::println!("Blob {} not found", blob_id);
+                    return false;
+                }
+            };
+            if blob.size as usize > images::MAX_IMAGE_BYTES {
+                ic_cdk::println!("Blob {} is too large for an avatar", blob_id);
+                return false;
+            }
+            match get_blob_bytes(&blob_id).map(store_image) {
+                Some(Ok(avatar_id)) => Some(avatar_id),
+                Some(Err(err)) => {
+                    ic_cdk::println!("Rejected avatar: {}", err);
+                    return false;
+                }
+                None => return false,
+            }
+        }
+        None => None,
+    };
+
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        let mut profile = store.get(&principal).unwrap();
+        if let Some(old_avatar) = profile.avatar_id.take() {
+            release_blob(&old_avatar);
+        }
+        profile.avatar_thumbnail_id = avatar_id.as_deref().and_then(thumbnail_of);
+        profile.avatar_id = avatar_id;
+        store.insert(principal, profile);
+        true
+    })
+}
+
+
+// Moves avatars and photos that used to be stored inline in records into the
+// blob store. Runs in the background, see run_background_migrations.
+fn migrate_inline_avatar(mut profile: types::Profile) -> Option<types::Profile> {
+    let avatar = profile.avatar.take()?;
+    if profile.avatar_id.is_none() {
+        profile.avatar_id = store_legacy_image(avatar);
+        profile.avatar_thumbnail_id = profile.avatar_id.as_deref().and_then(thumbnail_of);
+    }
+    Some(profile)
+}
+
+
+fn migrate_inline_instrument_photos(mut instrument: Instrument) -> Option<Instrument> {
+    let legacy_photo_ids: Vec<String> = instrument.photos.take()?.into_iter().filter_map(store_legacy_image).collect();
+    let photo_ids = instrument.photo_ids.get_or_insert_with(Vec::new);
+    photo_ids.extend(legacy_photo_ids);
+    instrument.thumbnail_ids = Some(thumbnails(photo_ids));
+    Some(instrument)
+}
+
+
+fn migrate_inline_post_photos(mut post: ForumData) -> Option<ForumData> {
+    let legacy_photo_ids: Vec<String> = post.photos.take()?.into_iter().filter_map(store_legacy_image).collect();
+    let photo_ids = post.photo_ids.get_or_insert_with(Vec::new);
+    photo_ids.extend(legacy_photo_ids);
+    post.thumbnail_ids = Some(thumbnails(photo_ids));
+    Some(post)
+}
+
+
+// Blobs migrated before images were validated were stored as opaque bytes
+// without a thumbnail. Re-processes them so that they get a proper content
+// type, lose their metadata and gain a preview. Blobs that still fail
+// validation are kept as they are.
+fn upgrade_legacy_blob(blob_id: String) -> String {
+    let is_legacy = get_blob_info(blob_id.clone())
+        .map(|blob| blob.content_type == "application/octet-stream" && blob.size as usize <= images::MAX_IMAGE_BYTES)
+        .unwrap_or(false);
+    if !is_legacy {
+        return blob_id;
+    }
+
+    match get_blob_bytes(&blob_id).map(store_image) {
+        Some(Ok(new_blob_id)) => {
+            release_blob(&blob_id);
+            new_blob_id
+        }
+        _ => blob_id,
+    }
+}
+
+
+fn backfill_avatar_thumbnail(mut profile: types::Profile) -> Option<types::Profile> {
+    if profile.avatar_id.is_none() || profile.avatar_thumbnail_id.is_some() {
+        return None;
+    }
+    profile.avatar_id = profile.avatar_id.map(upgrade_legacy_blob);
+    profile.avatar_thumbnail_id = profile.avatar_id.as_deref().and_then(thumbnail_of);
+    Some(profile)
+}
+
+
+fn backfill_instrument_thumbnails(mut instrument: Instrument) -> Option<Instrument> {
+    if instrument.thumbnail_ids.is_some() {
+        return None;
+    }
+    let photo_ids: Vec<String> = instrument.photo_ids.take()?.into_iter().map(upgrade_legacy_blob).collect();
+    instrument.thumbnail_ids = Some(thumbnails(&photo_ids));
+    instrument.photo_ids = Some(photo_ids);
+    Some(instrument)
+}
+
+
+fn backfill_post_thumbnails(mut post: ForumData) -> Option<ForumData> {
+    if post.thumbnail_ids.is_some() {
+        return None;
+    }
+    let photo_ids: Vec<String> = post.photo_ids.take()?.into_iter().map(upgrade_legacy_blob).collect();
+    post.thumbnail_ids = Some(thumbnails(&photo_ids));
+    post.photo_ids = Some(photo_ids);
+    Some(post)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Usernames
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// USERNAME_STORE maps the normalized form of every username to the principal
+// owning it, so that "Alice" and "alice" can't both be registered and
+// lookups don't have to scan every profile.
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+const RESERVED_USERNAMES: [&str; 10] = [
+    "tunebook",
+    "admin",
+    "administrator",
+    "moderator",
+    "support",
+    "system",
+    "root",
+    "anonymous",
+    "unknown",
+    "null",
+];
+
+
+// Compatibility decomposition folds look-alike forms (full width letters,
+// ligatures, ...) before lowercasing.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+
+fn validate_username(username: &str) -> Result<(), String> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(format!(
+            "Usernames must be between {} and {} characters long",
+            MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+        ));
+    }
+    if username.trim() != username {
+        return Err("Usernames can't start or end with spaces".to_string());
+    }
+    if !username.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-' || c == ' ') {
+        return Err("Usernames may only contain letters, digits, spaces, '_', '.' and '-'".to_string());
+    }
+    if !username.chars().next().is_some_and(|c| c.is_alphanumeric()) {
+        return Err("Usernames must start with a letter or a digit".to_string());
+    }
+    if username.contains("  ") {
+        return Err("Usernames can't contain consecutive spaces".to_string());
+    }
+    Ok(())
+}
+
+
+// Checks whether `principal` could register `username`. Returns the reason
+// when it can't.
+pub fn check_username_available(principal: String, username: String) -> Result<(), String> {
+    validate_username(&username)?;
+
+    let normalized = normalize_username(&username);
+    if RESERVED_USERNAMES.contains(&normalized.as_str()) {
+        return Err(format!("Username '{}' is reserved", username));
+    }
+
+    USERNAME_STORE.with(|username_store| match username_store.borrow().get(&normalized) {
+        Some(owner) if owner != principal => Err(format!("Username '{}' is already taken", username)),
+        _ => Ok(()),
+    })
+}
+
+
+// Moves the index entry of `principal` from its previous username to the new one.
+fn claim_username(principal: &String, previous_username: Option<&str>, username: &str) {
+    USERNAME_STORE.with(|username_store| {
+        let mut store = username_store.borrow_mut();
+        if let Some(previous) = previous_username.map(normalize_username) {
+            if store.get(&previous).as_ref() == Some(principal) {
+                store.remove(&previous);
+            }
+        }
+        // A colliding legacy name keeps pointing at the profile that had it first
+        let normalized = normalize_username(username);
+        if store.get(&normalized).is_none() {
+            store.insert(normalized, principal.clone());
+        }
+    });
+}
+
+
+pub fn get_profile_by_username(username: String, viewer: Option<String>) -> Option<types::PublicProfile> {
+    let principal = USERNAME_STORE.with(|username_store| username_store.borrow().get(&normalize_username(&username)))?;
+    get_profile(principal, viewer)
+}
+
+
+// Builds the index for profiles created before it existed. When several
+// existing profiles normalize to the same name, the first one keeps it in the
+// index; the others keep their username but should pick a new one.
+fn migrate_username_index(profile: types::Profile) -> Option<types::Profile> {
+    USERNAME_STORE.with(|username_store| {
+        let mut store = username_store.borrow_mut();
+        let normalized = normalize_username(&profile.username);
+        match store.get(&normalized) {
+            None => {
+                store.insert(normalized, profile.principal.clone());
+            }
+            Some(owner) if owner != profile.principal => {
+                ic_cdk::println!("Username '{}' of {} collides with {}", profile.username, profile.principal, owner);
+            }
+            _ => {}
+        }
+    });
+    None
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Privacy
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+pub fn privacy_settings(profile: &types::Profile) -> types::PrivacySettings {
+    profile.privacy.clone().unwrap_or_default()
+}
+
+
+fn can_view(visibility: &types::Visibility, owner: &types::Profile, viewer: Option<&String>) -> bool {
+    let is_owner = viewer == Some(&owner.principal);
+    match visibility {
+        types::Visibility::Public => true,
+        types::Visibility::FriendsOnly => is_owner || viewer.is_some_and(|viewer| are_friends(&owner.principal, viewer)),
+        types::Visibility::Private => is_owner,
+    }
+}
+
+
+// Whether `viewer` may see the given section of `owner`'s profile. Principals
+// without a profile have nothing to hide.
+fn can_view_section<F>(owner: &String, viewer: Option<&String>, section: F) -> bool
+where
+    F: Fn(&types::PrivacySettings) -> &types::Visibility,
+{
+    PROFILE_STORE.with(|profile_store| match profile_store.borrow().get(owner) {
+        Some(profile) => can_view(section(&privacy_settings(&profile)), &profile, viewer),
+        None => true,
+    })
+}
+
+
+pub fn public_profile(profile: &types::Profile, viewer: Option<&String>) -> types::PublicProfile {
+    let privacy = privacy_settings(profile);
+    let show_bio = can_view(&privacy.bio, profile, viewer);
+    let show_instruments = can_view(&privacy.instruments, profile, viewer);
+    let show_friends = can_view(&privacy.friends, profile, viewer);
+
+    types::PublicProfile {
+        principal: profile.principal.clone(),
+        username: profile.username.clone(),
+        avatar_id: profile.avatar_id.clone(),
+        avatar_thumbnail_id: profile.avatar_thumbnail_id.clone(),
+        pob: show_bio.then(|| profile.pob.clone()),
+        bio: profile.bio.clone().filter(|_| show_bio),
+        home: profile.home.clone().filter(|_| show_bio),
+        instruments: show_instruments.then(|| profile.instruments.clone()),
+        played_instruments: profile.played_instruments.clone().filter(|_| show_instruments),
+        friends: show_friends.then(|| friend_principals(&profile.principal)),
+    }
+}
+
+
+pub fn update_privacy_settings(principal: String, settings: types::PrivacySettings) -> Result<(), String> {
+    PROFILE_STORE.with(|profile_store| {
+        let mut store = profile_store.borrow_mut();
+        let mut profile = store.get(&principal).ok_or("Profile not found")?;
+        profile.privacy = Some(settings);
+        store.insert(principal, profile);
+        Ok(())
+    })
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Accounts
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Shown instead of the username on content that outlives a deleted account
+pub const DELETED_USERNAME: &str = "[deleted]";
+
+
+// Removes the profile and everything that only makes sense with it: friend
+// links, tunebook entries, sessions, listings and recordings. Forums and
+// posts are part of other people's conversations, so they are anonymized
+// unless `remove_posts` is set.
+pub fn delete_account(principal: String, remove_posts: bool) -> bool {
+    let profile = match PROFILE_STORE.with(|profile_store| profile_store.borrow_mut().remove(&principal)) {
+        Some(profile) => profile,
+        None => {
+            ic_cdk::println!("No profile found for principal: {}", principal);
+            return false;
+        }
+    };
+    CALENDAR_TOKEN_STORE.with(|token_store| token_store.borrow_mut().remove(&principal));
+
+    USERNAME_STORE.with(|username_store| {
+        let mut store = username_store.borrow_mut();
+        let normalized = normalize_username(&profile.username);
+        if store.get(&normalized).as_ref() == Some(&principal) {
+            store.remove(&normalized);
+        }
+    });
+    if let Some(avatar_id) = profile.avatar_id.as_deref() {
+        release_blob(avatar_id);
+    }
+
+    for (other, _) in relationships_of(&principal) {
+        remove_relationship(&principal, &other);
+    }
+
+    TUNE_STORE.with(|tune_store| {
+        let mut store = tune_store.borrow_mut();
+        let tunes: Vec<types::Tune> = store
+            .iter()
+            .filter(|(_, tune)| tune.principals.contains(&principal))
+            .map(|(_, tune)| tune)
+            .collect();
+        for mut tune in tunes {
+            tune.principals.retain(|p| p != &principal);
+            if tune.principals.is_empty() && !tune.origin {
+                store.remove(&tune.title);
+                continue;
+            }
+            if tune.username.as_ref() == Some(&profile.username) {
+                tune.username = Some(DELETED_USERNAME.to_string());
+            }
+            store.insert(tune.title.clone(), tune);
+        }
+    });
+
+    SESSION_STORE.with(|session_store| {
+        let mut store = session_store.borrow_mut();
+        let owned: Vec<types::Session> = store
+            .iter()
+            .filter(|(_, session)| session.principal == principal)
+            .map(|(_, session)| session)
+            .collect();
+        let co_organized: Vec<types::Session> = store
+            .iter()
+            .filter(|(_, session)| session.co_organizers.iter().flatten().any(|co_organizer| co_organizer == &principal))
+            .map(|(_, session)| session)
+            .collect();
+        for mut session in co_organized {
+            session.co_organizers.get_or_insert_with(Vec::new).retain(|co_organizer| co_organizer != &principal);
+            store.insert(session.id, session);
+        }
+        for session in owned {
+            store.remove(&session.id);
+            remove_session_follows(session.id);
+            remove_session_attendance(session.id);
+            remove_session_audit(session.id);
+            remove_played_sets(session.id);
+            update_geo_index(&SESSION_GEO_INDEX, session.id, session.coordinates, None);
+        }
+    });
+
+    for session_id in followed_session_ids(&principal) {
+        SESSION_FOLLOW_STORE.with(|follow_store| follow_store.borrow_mut().remove(&session_follow_key(&principal, session_id)));
+    }
+    anonymize_session_audit(&principal);
+    anonymize_played_sets(&principal);
+    for rsvp in rsvps_of(&principal) {
+        RSVP_STORE.with(|rsvp_store| rsvp_store.borrow_mut().remove(&rsvp_key(rsvp.session_id, rsvp.occurrence, &principal)));
+    }
+    for session_id in regular_session_ids(&principal) {
+        SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow_mut().remove(&session_member_key(session_id, &principal)));
+    }
+
+    INSTRUMENT_STORE.with(|instrument_store| {
+        let mut store = instrument_store.borrow_mut();
+        let linked: Vec<Instrument> = store
+            .iter()
+            .filter(|(_, instrument)| instrument.seller_principal == principal || instrument.buyer_principal == principal)
+            .map(|(_, instrument)| instrument)
+            .collect();
+        for mut instrument in linked {
+            if instrument.seller_principal == principal {
+                store.remove(&instrument.id);
+                release_blobs(&instrument.photo_ids);
+                remove_offers(instrument.id, None);
+                update_geo_index(&INSTRUMENT_GEO_INDEX, instrument.id, instrument.coordinates, None);
+            } else {
+                // A listing reserved for the user is back on the market
+                if listing_status(&instrument) == types::ListingStatus::Reserved {
+                    instrument.status = Some(types::ListingStatus::Active);
+                    instrument.expires_at = Some(ic_cdk::api::time() + LISTING_LIFETIME_NS);
+                }
+                instrument.buyer_principal = String::new();
+                store.insert(instrument.id, instrument);
+            }
+        }
+    });
+    let negotiated: Vec<u32> = OFFER_STORE.with(|offer_store| {
+        offer_store
+            .borrow()
+            .iter()
+            .filter(|(_, offer)| offer.buyer == principal)
+            .map(|((instrument_id, _), _)| instrument_id)
+            .collect()
+    });
+    for instrument_id in negotiated {
+        remove_offers(instrument_id, Some(&principal));
+    }
+
+    // Posts removed with the account also leave the threads that list them
+    let mut removed_posts: Vec<(u64, u64)> = Vec::new();
+    FORUM_DATA_STORE.with(|forum_data_store| {
+        let mut store = forum_data_store.borrow_mut();
+        let posts: Vec<ForumData> = store
+            .iter()
+            .filter(|(_, post)| post.principal == principal)
+            .map(|(_, post)| post)
+            .collect();
+        for mut post in posts {
+            if remove_posts {
+                store.remove(&post.id);
+                release_blobs(&post.photo_ids);
+                if let Some(forum_id) = post.forum_id {
+                    removed_posts.push((forum_id, post.id));
+                }
+            } else {
+                post.principal = String::new();
+                post.username = DELETED_USERNAME.to_string();
+                store.insert(post.id, post);
+            }
+        }
+    });
+
+    FORUM_STORE.with(|forum_store| {
+        let mut store = forum_store.borrow_mut();
+        let linked: Vec<Forum> = store
+            .iter()
+            .filter(|(id, forum)| {
+                forum.poster_principal == principal
+                    || forum.principals.contains(&principal)
+                    || removed_posts.iter().any(|(forum_id, _)| forum_id == id)
+            })
+            .map(|(_, forum)| forum)
+            .collect();
+        for mut forum in linked {
+            if forum.poster_principal == principal {
+                forum.poster_principal = String::new();
+                forum.username = DELETED_USERNAME.to_string();
+            }
+            forum.principals.retain(|p| p != &principal);
+            if let Some(threads) = forum.threads.as_mut() {
+                threads.retain(|post_id| !removed_posts.contains(&(forum.id, *post_id)));
+            }
+            store.insert(forum.id, forum);
+        }
+    });
+
+    RECORDING_STORE.with(|recording_store| {
+        let mut store = recording_store.borrow_mut();
+        let recordings: Vec<types::Recording> = store
+            .iter()
+            .filter(|(_, recording)| recording.uploader_principal == principal)
+            .map(|(_, recording)| recording)
+            .collect();
+        for recording in recordings.iter() {
+            remove_recording(&mut store, recording);
+        }
+    });
+
+    for conversation in conversations_of(&principal) {
+        remove_conversation(&conversation);
+    }
+
+    NOTIFICATION_STORE.with(|notification_store| {
+        let mut store = notification_store.borrow_mut();
+        let keys: Vec<types::NotificationKey> = store
+            .range(notification_key(&principal, 0)..=notification_key(&principal, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            store.remove(&key);
+        }
+    });
+
+    let uploads: Vec<types::BlobUpload> = BLOB_UPLOAD_STORE.with(|upload_store| {
+        upload_store
+            .borrow()
+            .iter()
+            .filter(|(_, upload)| upload.uploader_principal == principal)
+            .map(|(_, upload)| upload)
+            .collect()
+    });
+    for upload in uploads.iter() {
+        remove_upload(upload);
+    }
+
+    ic_cdk::println!("Deleted account of principal: {}", principal);
+    true
+}
+
+
+// Replies are limited to 2 MB, leaving room for the profile and encoding overhead
+const EXPORT_PAGE_BYTES: usize = 1_500_000;
+
+
+// Pages through the records of an export in a fixed order: `cursor` counts
+// the records of earlier pages, across all lists.
+struct ExportPager {
+    cursor: u64,
+    position: u64,
+    budget: usize,
+    next_cursor: Option<u64>,
+}
+
+impl ExportPager {
+    fn page<T: candid::CandidType>(&mut self, items: Vec<T>) -> Vec<T> {
+        let mut page = vec![];
+        for item in items {
+            let position = self.position;
+            self.position += 1;
+            if position < self.cursor || self.next_cursor.is_some() {
+                continue;
+            }
+            // A record larger than a page still goes out, alone
+            let size = Encode!(&item).map(|bytes| bytes.len()).unwrap_or_default();
+            if size > self.budget && self.budget < EXPORT_PAGE_BYTES {
+                self.next_cursor = Some(position);
+                continue;
+            }
+            self.budget = self.budget.saturating_sub(size);
+            page.push(item);
+        }
+        page
+    }
+}
+
+
+pub fn export_my_data(principal: String, cursor: u64) -> Option<types::AccountExport> {
+    let profile = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(&principal)).map(with_relationships)?;
+
+    let tunes = TUNE_STORE.with(|tune_store| {
+        tune_store
+            .borrow()
+            .iter()
+            .filter(|(_, tune)| tune.principals.contains(&principal))
+            .map(|(_, tune)| tune)
+            .collect()
+    });
+    let sessions = SESSION_STORE.with(|session_store| {
+        session_store
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.principal == principal)
+            .map(|(_, session)| session)
+            .collect()
+    });
+    let instruments = INSTRUMENT_STORE.with(|instrument_store| {
+        instrument_store
+            .borrow()
+            .iter()
+            .filter(|(_, instrument)| instrument.seller_principal == principal || instrument.buyer_principal == principal)
+            .map(|(_, instrument)| instrument)
+            .collect()
+    });
+    let forums = FORUM_STORE.with(|forum_store| {
+        forum_store
+            .borrow()
+            .iter()
+            .filter(|(_, forum)| forum.poster_principal == principal)
+            .map(|(_, forum)| forum)
+            .collect()
+    });
+    let forum_posts = FORUM_DATA_STORE.with(|forum_data_store| {
+        forum_data_store
+            .borrow()
+            .iter()
+            .filter(|(_, post)| post.principal == principal)
+            .map(|(_, post)| post)
+            .collect()
+    });
+    let recordings = RECORDING_STORE.with(|recording_store| {
+        recording_store
+            .borrow()
+            .iter()
+            .filter(|(_, recording)| recording.uploader_principal == principal)
+            .map(|(_, recording)| recording)
+            .collect()
+    });
+
+    let conversations = conversations_of(&principal);
+    let messages = conversations
+        .iter()
+        .flat_map(|conversation| conversation_messages(conversation.id))
+        .collect();
+
+    let notifications = notifications_of(&principal);
+    let followed_sessions = followed_session_ids(&principal);
+    let session_rsvps = rsvps_of(&principal);
+    let regular_sessions = regular_session_ids(&principal);
+    let played_sets = PLAYED_SET_STORE.with(|played_set_store| {
+        played_set_store
+            .borrow()
+            .iter()
+            .filter(|(_, set)| set.recorded_by == principal)
+            .map(|(_, set)| set)
+            .collect()
+    });
+    let offers = OFFER_STORE.with(|offer_store| {
+        offer_store
+            .borrow()
+            .iter()
+            .filter(|(_, offer)| offer.buyer == principal || offer.author == principal)
+            .map(|(_, offer)| offer)
+            .collect()
+    });
+    let co_organized_sessions = SESSION_STORE.with(|session_store| {
+        session_store
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.co_organizers.iter().flatten().any(|co_organizer| co_organizer == &principal))
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    let mut pager = ExportPager {
+        cursor,
+        position: 0,
+        budget: EXPORT_PAGE_BYTES,
+        next_cursor: None,
+    };
+    Some(types::AccountExport {
+        exported_at: ic_cdk::api::time(),
+        profile,
+        tunes: pager.page(tunes),
+        sessions: pager.page(sessions),
+        instruments: pager.page(instruments),
+        forums: pager.page(forums),
+        forum_posts: pager.page(forum_posts),
+        recordings: pager.page(recordings),
+        conversations: pager.page(conversations),
+        messages: pager.page(messages),
+        notifications: pager.page(notifications),
+        followed_sessions: pager.page(followed_sessions),
+        session_rsvps: pager.page(session_rsvps),
+        regular_sessions: pager.page(regular_sessions),
+        co_organized_sessions: pager.page(co_organized_sessions),
+        played_sets: pager.page(played_sets),
+        offers: pager.page(offers),
+        next_cursor: pager.next_cursor,
+    })
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Relationships
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// RELATIONSHIP_STORE holds one edge per direction, keyed by (owner, other),
+// so everything a user is connected to is a single range scan. Usernames and
+// avatars are looked up from the profiles when the edges are read.
+
+fn relationship_key(owner: &str, other: &str) -> types::RelationshipKey {
+    types::RelationshipKey {
+        owner: owner.to_string(),
+        other: other.to_string(),
+    }
+}
+
+
+fn profile_exists(principal: &String) -> bool {
+    PROFILE_STORE.with(|profile_store| profile_store.borrow().contains_key(principal))
+}
+
+
+pub fn get_relationship(owner: &str, other: &str) -> Option<types::Relationship> {
+    RELATIONSHIP_STORE.with(|relationship_store| relationship_store.borrow().get(&relationship_key(owner, other)))
+}
+
+
+// Keeps the original creation time when the state of an edge changes
+fn set_relationship(owner: &str, other: &str, state: types::RelationshipState) {
+    let now = ic_cdk::api::time();
+    RELATIONSHIP_STORE.with(|relationship_store| {
+        let mut store = relationship_store.borrow_mut();
+        let key = relationship_key(owner, other);
+        let created_at = store.get(&key).map(|relationship| relationship.created_at).unwrap_or(now);
+        store.insert(key, types::Relationship {
+            state,
+            created_at,
+            updated_at: now,
+        });
+    });
+}
+
+
+// What one user can do to their relationship with another
+#[derive(Clone, Copy, Debug)]
+enum RelationshipChange {
+    Request,
+    Accept,
+    Decline,
+    Cancel,
+    Unfriend,
+    Block,
+    Unblock,
+}
+
+
+// The states of `owner -> other` and `other -> owner` after `owner` makes
+// `change`, given the current ones, or None when the change doesn't apply.
+// Blocking replaces any friendship or pending request between the two users.
+fn relationship_transition(
+    change: RelationshipChange,
+    forward: Option<&types::RelationshipState>,
+    back: Option<&types::RelationshipState>,
+) -> Option<(Option<types::RelationshipState>, Option<types::RelationshipState>)> {
+    use types::RelationshipState::*;
+    let blocked_back = back == Some(&Blocked);
+    match (change, forward) {
+        (RelationshipChange::Request, None) => Some((Some(RequestSent), Some(RequestReceived))),
+        (RelationshipChange::Accept, Some(RequestReceived)) => Some((Some(Friends), Some(Friends))),
+        (RelationshipChange::Decline, Some(RequestReceived))
+        | (RelationshipChange::Cancel, Some(RequestSent))
+        | (RelationshipChange::Unfriend, Some(Friends)) => Some((None, None)),
+        (RelationshipChange::Block, _) => Some((Some(Blocked), Some(if blocked_back { Blocked } else { BlockedBy }))),
+        (RelationshipChange::Unblock, Some(Blocked)) if blocked_back => Some((Some(BlockedBy), Some(Blocked))),
+        (RelationshipChange::Unblock, Some(Blocked)) => Some((None, None)),
+        _ => None,
+    }
+}
+
+
+// Applies `change` made by `owner` to both directions, returning whether it
+// applied
+fn change_relationship(owner: &str, other: &str, change: RelationshipChange) -> bool {
+    let forward = get_relationship(owner, other).map(|relationship| relationship.state);
+    let back = get_relationship(other, owner).map(|relationship| relationship.state);
+    let (new_forward, new_back) = match relationship_transition(change, forward.as_ref(), back.as_ref()) {
+        Some(states) => states,
+        None => return false,
+    };
+
+    for (from, to, current, new) in [(owner, other, forward, new_forward), (other, owner, back, new_back)] {
+        if current == new {
+            continue;
+        }
+        match new {
+            Some(state) => set_relationship(from, to, state),
+            None => {
+                RELATIONSHIP_STORE.with(|relationship_store| relationship_store.borrow_mut().remove(&relationship_key(from, to)));
+            }
+        }
+    }
+    true
+}
+
+
+// Removes both directions
+fn remove_relationship(a: &str, b: &str) {
+    RELATIONSHIP_STORE.with(|relationship_store| {
+        let mut store = relationship_store.borrow_mut();
+        store.remove(&relationship_key(a, b));
+        store.remove(&relationship_key(b, a));
+    });
+}
+
+
+pub fn relationships_of(owner: &str) -> Vec<(String, types::Relationship)> {
+    RELATIONSHIP_STORE.with(|relationship_store| {
+        relationship_store
+            .borrow()
+            .range(relationship_key(owner, "")..)
+            .take_while(|(key, _)| key.owner == owner)
+            .map(|(key, relationship)| (key.other, relationship))
+            .collect()
+    })
+}
+
+
+// Whether either user blocked the other
+pub fn is_blocked(a: &str, b: &str) -> bool {
+    get_relationship(a, b).is_some_and(|relationship| {
+        matches!(relationship.state, types::RelationshipState::Blocked | types::RelationshipState::BlockedBy)
+    })
+}
+
+
+pub fn are_friends(a: &str, b: &str) -> bool {
+    get_relationship(a, b).is_some_and(|relationship| relationship.state == types::RelationshipState::Friends)
+}
+
+
+pub fn friend_principals(principal: &str) -> Vec<String> {
+    relationships_of(principal)
+        .into_iter()
+        .filter(|(_, relationship)| relationship.state == types::RelationshipState::Friends)
+        .map(|(other, _)| other)
+        .collect()
+}
+
+
+fn friend_view(principal: &String, relationship: &types::Relationship) -> Option<types::Friend> {
+    let profile = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(principal))?;
+    Some(types::Friend {
+        principal: profile.principal,
+        avatar: None,
+        username: profile.username,
+        avatar_id: profile.avatar_id,
+        avatar_thumbnail_id: profile.avatar_thumbnail_id,
+        since: Some(relationship.updated_at),
+    })
+}
+
+
+// Fills in the friend lists of a profile for its owner
+fn with_relationships(mut profile: types::Profile) -> types::Profile {
+    profile.friends = vec![];
+    profile.incoming_fr = vec![];
+    profile.outcoming_fr = vec![];
+    for (other, relationship) in relationships_of(&profile.principal) {
+        match relationship.state {
+            types::RelationshipState::Friends => profile.friends.push(other),
+            types::RelationshipState::RequestSent => profile.outcoming_fr.extend(friend_view(&other, &relationship)),
+            types::RelationshipState::RequestReceived => profile.incoming_fr.extend(friend_view(&other, &relationship)),
+            types::RelationshipState::Blocked | types::RelationshipState::BlockedBy => {}
+        }
+    }
+    profile
+}
+
+
+// Paginated listing of one kind of relationship, most recent first
+pub fn get_relationships(principal: String, state: types::RelationshipState, page_num: i32) -> (Vec<types::Friend>, i32) {
+    // Users aren't told who blocked them
+    if state == types::RelationshipState::BlockedBy {
+        return (vec![], 0);
+    }
+
+    let mut res: Vec<(String, types::Relationship)> = relationships_of(&principal)
+        .into_iter()
+        .filter(|(_, relationship)| relationship.state == state)
+        .collect();
+    res.sort_by_key(|(_, relationship)| std::cmp::Reverse(relationship.updated_at));
+
+    let result: Vec<types::Friend> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .filter_map(|(other, relationship)| friend_view(other, relationship))
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+// Moves the friend lists that used to be embedded in profiles into the
+// relationship store and empties them.
+fn migrate_friend_graph(mut profile: types::Profile) -> Option<types::Profile> {
+    if profile.friends.is_empty() && profile.incoming_fr.is_empty() && profile.outcoming_fr.is_empty() {
+        return None;
+    }
+
+    let principal = profile.principal.clone();
+    let edges = profile
+        .friends
+        .iter()
+        .map(|friend| (friend.clone(), types::RelationshipState::Friends))
+        .chain(profile.outcoming_fr.iter().map(|friend| (friend.principal.clone(), types::RelationshipState::RequestSent)))
+        .chain(profile.incoming_fr.iter().map(|friend| (friend.principal.clone(), types::RelationshipState::RequestReceived)));
+    for (other, state) in edges {
+        // Friendship wins over a stale request left behind in the other profile
+        if other != principal && profile_exists(&other) && !are_friends(&principal, &other) {
+            let reverse = match state {
+                types::RelationshipState::RequestSent => types::RelationshipState::RequestReceived,
+                types::RelationshipState::RequestReceived => types::RelationshipState::RequestSent,
+                _ => types::RelationshipState::Friends,
+            };
+            set_relationship(&principal, &other, state);
+            set_relationship(&other, &principal, reverse);
+        }
+    }
+
+    profile.friends = vec![];
+    profile.incoming_fr = vec![];
+    profile.outcoming_fr = vec![];
+    Some(profile)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Messages
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Two users share at most one conversation, found through CONVERSATION_INDEX
+// which has an entry for each participant.
+const MAX_MESSAGE_LENGTH: usize = 4000;
+const MESSAGES_PER_PAGE: usize = 20;
+
+
+// Friends can always write to each other; other users only when the
+// recipient opted in to messages from anyone.
+pub fn can_message(sender: &String, recipient: &String) -> bool {
+    if sender == recipient || is_blocked(sender, recipient) {
+        return false;
+    }
+    PROFILE_STORE.with(|profile_store| match profile_store.borrow().get(recipient) {
+        Some(profile) => {
+            let visibility = privacy_settings(&profile).messages.unwrap_or(types::Visibility::FriendsOnly);
+            can_view(&visibility, &profile, Some(sender))
+        }
+        None => false,
+    })
+}
+
+
+// Anyone may ask the seller about a listing that is still available, and the
+// seller may answer the people who asked or made an offer
+fn can_discuss_listing(sender: &String, recipient: &String, instrument_id: u32) -> bool {
+    let instrument = match negotiable_listing(instrument_id) {
+        Ok(instrument) => instrument,
+        Err(_) => return false,
+    };
+    if sender == recipient || is_blocked(sender, recipient) {
+        return false;
+    }
+    if *recipient == instrument.seller_principal {
+        return true;
+    }
+    *sender == instrument.seller_principal
+        && (conversation_between(sender, recipient).is_some()
+            || listing_offers(instrument_id).iter().any(|offer| offer.buyer == *recipient))
+}
+
+
+fn conversation_between(a: &str, b: &str) -> Option<u64> {
+    CONVERSATION_INDEX.with(|index| index.borrow().get(&relationship_key(a, b)))
+}
+
+
+fn conversations_of(principal: &str) -> Vec<types::Conversation> {
+    let ids: Vec<u64> = CONVERSATION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(relationship_key(principal, "")..)
+            .take_while(|(key, _)| key.owner == principal)
+            .map(|(_, id)| id)
+            .collect()
+    });
+    CONVERSATION_STORE.with(|conversation_store| {
+        let store = conversation_store.borrow();
+        ids.iter().filter_map(|id| store.get(id)).collect()
+    })
+}
+
+
+fn conversation_messages(conversation_id: u64) -> Vec<types::Message> {
+    MESSAGE_STORE.with(|message_store| {
+        message_store
+            .borrow()
+            .range((conversation_id, 0)..=(conversation_id, u64::MAX))
+            .map(|(_, message)| message)
+            .collect()
+    })
+}
+
+
+fn remove_conversation(conversation: &types::Conversation) {
+    MESSAGE_STORE.with(|message_store| {
+        let mut store = message_store.borrow_mut();
+        for id in 1..=conversation.last_message_id {
+            store.remove(&(conversation.id, id));
+        }
+    });
+    CONVERSATION_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for participant in conversation.participants.iter() {
+            for other in conversation.participants.iter().filter(|other| *other != participant) {
+                index.remove(&relationship_key(participant, other));
+            }
+        }
+    });
+    CONVERSATION_STORE.with(|conversation_store| conversation_store.borrow_mut().remove(&conversation.id));
+}
+
+
+fn read_up_to(conversation: &types::Conversation, principal: &String) -> u64 {
+    conversation
+        .read_up_to
+        .iter()
+        .find(|(participant, _)| participant == principal)
+        .map(|(_, id)| *id)
+        .unwrap_or(0)
+}
+
+
+fn set_read_up_to(conversation: &mut types::Conversation, principal: &String, message_id: u64) {
+    match conversation.read_up_to.iter_mut().find(|(participant, _)| participant == principal) {
+        Some((_, id)) => *id = (*id).max(message_id),
+        None => conversation.read_up_to.push((principal.clone(), message_id)),
+    }
+}
+
+
+fn unread_count(conversation: &types::Conversation, principal: &String) -> u32 {
+    let read = read_up_to(conversation, principal);
+    if read >= conversation.last_message_id {
+        return 0;
+    }
+    MESSAGE_STORE.with(|message_store| {
+        message_store
+            .borrow()
+            .range((conversation.id, read + 1)..=(conversation.id, conversation.last_message_id))
+            .filter(|(_, message)| message.sender != *principal)
+            .count() as u32
+    })
+}
+
+
+// Sends a message, starting the conversation if the two users don't have one
+// yet. `instrument_id` records the listing a new conversation is about.
+pub fn send_message(
+    sender: String,
+    recipient: String,
+    body: String,
+    instrument_id: Option<u32>,
+) -> Result<types::Message, String> {
+    let body = body.trim().to_string();
+    if body.is_empty() {
+        return Err("Messages can't be empty".to_string());
+    }
+    if body.chars().count() > MAX_MESSAGE_LENGTH {
+        return Err(format!("Messages can be at most {} characters long", MAX_MESSAGE_LENGTH));
+    }
+    if !profile_exists(&sender) || !profile_exists(&recipient) {
+        return Err("Profile not found".to_string());
+    }
+    if !can_message(&sender, &recipient) && !instrument_id.is_some_and(|id| can_discuss_listing(&sender, &recipient, id)) {
+        return Err(format!("{} does not accept messages from you", recipient));
+    }
+
+    let now = ic_cdk::api::time();
+    let mut conversation = match conversation_between(&sender, &recipient)
+        .and_then(|id| CONVERSATION_STORE.with(|conversation_store| conversation_store.borrow().get(&id)))
+    {
+        Some(conversation) => conversation,
+        None => {
+            let id = CONVERSATION_STORE.with(|conversation_store| {
+                let store = conversation_store.borrow();
+                allocate_id(CONVERSATION_IDS, u64::MAX, |id| store.contains_key(&id))
+            });
+            CONVERSATION_INDEX.with(|index| {
+                let mut index = index.borrow_mut();
+                index.insert(relationship_key(&sender, &recipient), id);
+                index.insert(relationship_key(&recipient, &sender), id);
+            });
+            types::Conversation {
+                id,
+                participants: vec![sender.clone(), recipient.clone()],
+                created_at: now,
+                last_message_at: now,
+                last_message_id: 0,
+                read_up_to: vec![],
+                instrument_id,
+            }
+        }
+    };
+
+    let message = types::Message {
+        id: conversation.last_message_id + 1,
+        conversation_id: conversation.id,
+        sender: sender.clone(),
+        body,
+        created_at: now,
+    };
+    conversation.last_message_id = message.id;
+    conversation.last_message_at = now;
+    set_read_up_to(&mut conversation, &sender, message.id);
+
+    let is_inquiry = message.id == 1 && conversation.instrument_id.is_some_and(|id| {
+        INSTRUMENT_STORE.with(|instrument_store| {
+            instrument_store.borrow().get(&id).is_some_and(|instrument| instrument.seller_principal == recipient)
+        })
+    });
+    let (kind, text) = if is_inquiry {
+        (types::NotificationKind::InstrumentInquiry, "sent you a message about your listing".to_string())
+    } else {
+        (types::NotificationKind::Message, "sent you a message".to_string())
+    };
+    notify(&recipient, kind, &sender, Some(conversation.id), text);
+
+    MESSAGE_STORE.with(|message_store| message_store.borrow_mut().insert((conversation.id, message.id), message.clone()));
+    CONVERSATION_STORE.with(|conversation_store| conversation_store.borrow_mut().insert(conversation.id, conversation));
+    Ok(message)
+}
+
+
+// Conversations of the user, most recently active first. Conversations with
+// blocked users are hidden.
+pub fn get_conversations(principal: String, page_num: i32) -> (Vec<types::ConversationSummary>, i32) {
+    let mut conversations: Vec<(types::Conversation, String)> = conversations_of(&principal)
+        .into_iter()
+        .filter_map(|conversation| {
+            let other = conversation.participants.iter().find(|p| **p != principal)?.clone();
+            Some((conversation, other))
+        })
+        .filter(|(_, other)| !is_blocked(&principal, other))
+        .collect();
+    conversations.sort_by_key(|(conversation, _)| std::cmp::Reverse(conversation.last_message_at));
+
+    let result: Vec<types::ConversationSummary> = conversations
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .map(|(conversation, other)| {
+            let other = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(other));
+            types::ConversationSummary {
+                other: types::Friend {
+                    principal: other.as_ref().map(|p| p.principal.clone()).unwrap_or_default(),
+                    avatar: None,
+                    username: other.as_ref().map(|p| p.username.clone()).unwrap_or(DELETED_USERNAME.to_string()),
+                    avatar_id: other.as_ref().and_then(|p| p.avatar_id.clone()),
+                    avatar_thumbnail_id: other.as_ref().and_then(|p| p.avatar_thumbnail_id.clone()),
+                    since: None,
+                },
+                last_message: MESSAGE_STORE.with(|message_store| {
+                    message_store.borrow().get(&(conversation.id, conversation.last_message_id))
+                }),
+                unread_count: unread_count(conversation, &principal),
+                conversation: conversation.clone(),
+            }
+        })
+        .collect();
+
+    (result, conversations.len() as i32)
+}
+
+
+// Page 0 holds the most recent messages, each page in chronological order
+pub fn get_messages(conversation_id: u64, principal: String, page_num: i32) -> Result<(Vec<types::Message>, i32), String> {
+    let conversation = CONVERSATION_STORE
+        .with(|conversation_store| conversation_store.borrow().get(&conversation_id))
+        .filter(|conversation| conversation.participants.contains(&principal))
+        .ok_or(format!("Conversation with ID {} not found", conversation_id))?;
+
+    let total = conversation.last_message_id;
+    let skip = page_num.max(0) as u64 * MESSAGES_PER_PAGE as u64;
+    if skip >= total {
+        return Ok((vec![], total as i32));
+    }
+    let last = total - skip;
+    let first = last.saturating_sub(MESSAGES_PER_PAGE as u64 - 1).max(1);
+
+    let messages = MESSAGE_STORE.with(|message_store| {
+        message_store
+            .borrow()
+            .range((conversation_id, first)..=(conversation_id, last))
+            .map(|(_, message)| message)
+            .collect()
+    });
+    Ok((messages, total as i32))
+}
+
+
+// Read receipt: marks every message up to `message_id` as read by `principal`
+pub fn mark_conversation_read(conversation_id: u64, principal: String, message_id: u64) -> bool {
+    CONVERSATION_STORE.with(|conversation_store| {
+        let mut store = conversation_store.borrow_mut();
+        match store.get(&conversation_id) {
+            Some(mut conversation) if conversation.participants.contains(&principal) => {
+                let message_id = message_id.min(conversation.last_message_id);
+                set_read_up_to(&mut conversation, &principal, message_id);
+                store.insert(conversation_id, conversation);
+                true
+            }
+            _ => {
+                ic_cdk::println!("Conversation with ID {} not found", conversation_id);
+                false
+            }
+        }
+    })
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Notifications
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Only the most recent notifications of each user are kept
+const MAX_NOTIFICATIONS_PER_USER: usize = 500;
+// Characters, keeps notifications within their storage bound whatever they quote
+const MAX_NOTIFICATION_TEXT_LENGTH: usize = 250;
+// Characters, for forum, session and listing names
+const MAX_NAME_LENGTH: usize = 100;
+
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(format!("Name must be at most {} characters", MAX_NAME_LENGTH));
+    }
+    Ok(())
+}
+
+
+fn truncate_text(text: String, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+
+fn notification_key(recipient: &str, id: u64) -> types::NotificationKey {
+    types::NotificationKey {
+        recipient: recipient.to_string(),
+        id,
+    }
+}
+
+
+fn notifications_of(recipient: &str) -> Vec<types::Notification> {
+    NOTIFICATION_STORE.with(|notification_store| {
+        notification_store
+            .borrow()
+            .range(notification_key(recipient, 0)..=notification_key(recipient, u64::MAX))
+            .map(|(_, notification)| notification)
+            .collect()
+    })
+}
+
+
+// Records a notification for `recipient` about something `actor` did.
+// Nothing is recorded when the recipient blocked the actor or is the actor.
+pub fn notify(recipient: &String, kind: types::NotificationKind, actor: &String, subject_id: Option<u64>, text: String) {
+    if recipient == actor || is_blocked(recipient, actor) || !profile_exists(recipient) {
+        return;
+    }
+    let actor_username = PROFILE_STORE
+        .with(|profile_store| profile_store.borrow().get(actor))
+        .map(|profile| profile.username)
+        .unwrap_or(DELETED_USERNAME.to_string());
+    let text = truncate_text(text, MAX_NOTIFICATION_TEXT_LENGTH);
+
+    NOTIFICATION_STORE.with(|notification_store| {
+        let mut store = notification_store.borrow_mut();
+        let keys: Vec<types::NotificationKey> = store
+            .range(notification_key(recipient, 0)..=notification_key(recipient, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+
+        let id = keys.last().map(|key| key.id + 1).unwrap_or(1);
+        store.insert(notification_key(recipient, id), types::Notification {
+            id,
+            recipient: recipient.clone(),
+            kind,
+            actor: actor.clone(),
+            actor_username,
+            subject_id,
+            text,
+            created_at: ic_cdk::api::time(),
+            read: false,
+        });
+
+        for key in keys.iter().take((keys.len() + 1).saturating_sub(MAX_NOTIFICATIONS_PER_USER)) {
+            store.remove(key);
+        }
+    });
+}
+
+
+// Newest first
+pub fn get_notifications(principal: String, unread_only: bool, page_num: i32) -> (Vec<types::Notification>, i32) {
+    let res: Vec<types::Notification> = notifications_of(&principal)
+        .into_iter()
+        .rev()
+        .filter(|notification| !unread_only || !notification.read)
+        .collect();
+
+    let result: Vec<types::Notification> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+pub fn get_unread_notification_count(principal: String) -> u32 {
+    notifications_of(&principal)
+        .iter()
+        .filter(|notification| !notification.read)
+        .count() as u32
+}
+
+
+// Marks the given notifications, or all of them when `ids` is None, as read.
+// Returns how many were unread.
+pub fn mark_notifications_read(principal: String, ids: Option<Vec<u64>>) -> u32 {
+    let unread: Vec<types::Notification> = notifications_of(&principal)
+        .into_iter()
+        .filter(|notification| !notification.read)
+        .filter(|notification| ids.as_ref().is_none_or(|ids| ids.contains(&notification.id)))
+        .collect();
+
+    NOTIFICATION_STORE.with(|notification_store| {
+        let mut store = notification_store.borrow_mut();
+        for mut notification in unread.iter().cloned() {
+            notification.read = true;
+            store.insert(notification_key(&principal, notification.id), notification);
+        }
+    });
+    unread.len() as u32
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                            // Session follows
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+fn session_follow_key(principal: &str, session_id: u32) -> types::SessionFollowKey {
+    types::SessionFollowKey {
+        principal: principal.to_string(),
+        session_id,
+    }
+}
+
+
+fn followed_session_ids(principal: &str) -> Vec<u32> {
+    SESSION_FOLLOW_STORE.with(|follow_store| {
+        follow_store
+            .borrow()
+            .range(session_follow_key(principal, 0)..=session_follow_key(principal, u32::MAX))
+            .map(|(key, _)| key.session_id)
+            .collect()
+    })
+}
+
+
+fn remove_session_follows(session_id: u32) {
+    SESSION_FOLLOW_STORE.with(|follow_store| {
+        let mut store = follow_store.borrow_mut();
+        let keys: Vec<types::SessionFollowKey> = store
+            .iter()
+            .filter(|(key, _)| key.session_id == session_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            store.remove(&key);
+        }
+    });
+}
+
+
+pub fn follow_session(principal: String, session_id: u32) -> bool {
+    if !profile_exists(&principal) {
+        ic_cdk::println!("No profile found for principal: {}", principal);
+        return false;
+    }
+    if !SESSION_STORE.with(|session_store| session_store.borrow().contains_key(&session_id)) {
+        ic_cdk::println!("Session with ID {} not found", session_id);
+        return false;
+    }
+
+    SESSION_FOLLOW_STORE.with(|follow_store| {
+        let mut store = follow_store.borrow_mut();
+        let key = session_follow_key(&principal, session_id);
+        if !store.contains_key(&key) {
+            store.insert(key, ic_cdk::api::time());
+        }
+    });
+    true
+}
+
+
+pub fn unfollow_session(principal: String, session_id: u32) -> bool {
+    SESSION_FOLLOW_STORE.with(|follow_store| {
+        follow_store
+            .borrow_mut()
+            .remove(&session_follow_key(&principal, session_id))
+            .is_some()
+    })
+}
+
+
+pub fn get_followed_sessions(principal: String) -> Vec<types::SessionView> {
+    SESSION_STORE.with(|session_store| {
+        let store = session_store.borrow();
+        followed_session_ids(&principal)
+            .into_iter()
+            .filter_map(|session_id| store.get(&session_id))
+            .map(|session| with_attendance(session, None))
+            .collect()
+    })
+}
+
+
+// Everything that belongs in a user's calendar feed: the sessions they
+// follow and the ones they organize or co-organize. None when there is no
+// such user or `token` isn't their calendar token.
+pub fn get_calendar_sessions(principal: &String, token: &str) -> Option<Vec<types::Session>> {
+    let stored = CALENDAR_TOKEN_STORE.with(|token_store| token_store.borrow().get(principal))?;
+    if stored != token || !profile_exists(principal) {
+        return None;
+    }
+    SESSION_STORE.with(|session_store| {
+        let store = session_store.borrow();
+        let mut sessions: Vec<types::Session> = followed_session_ids(principal)
+            .into_iter()
+            .filter_map(|session_id| store.get(&session_id))
+            .collect();
+        for (_, session) in store.iter() {
+            if can_edit_session(&session, principal) && sessions.iter().all(|followed| followed.id != session.id) {
+                sessions.push(session);
+            }
+        }
+        sessions.sort_by_key(|session| session.id);
+        Some(sessions)
+    })
+}
+
+
+// Calendar apps fetch the feed without signing in, so its URL carries a
+// secret token instead. Rotating the token breaks every link shared before.
+pub async fn calendar_token(principal: String, rotate: bool) -> Result<String, String> {
+    if !profile_exists(&principal) {
+        return Err("Profile not found".to_string());
+    }
+    if let Some(token) = CALENDAR_TOKEN_STORE.with(|token_store| token_store.borrow().get(&principal)).filter(|_| !rotate) {
+        return Ok(token);
+    }
+
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(_, err)| format!("Could not create a calendar token: {}", err))?;
+    if !profile_exists(&principal) {
+        return Err("Profile not found".to_string()); // Deleted while waiting
+    }
+    let token = to_hex(&random);
+    CALENDAR_TOKEN_STORE.with(|token_store| token_store.borrow_mut().insert(principal, token.clone()));
+    Ok(token)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                            // Geospatial search
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+fn valid_coordinates(coordinates: &Option<types::Coordinates>) -> bool {
+    coordinates
+        .as_ref()
+        .is_none_or(|coordinates| geo::is_valid_coordinate(coordinates.latitude, coordinates.longitude))
+}
+
+
+fn geo_index_key(coordinates: &types::Coordinates, id: u32) -> types::GeoIndexKey {
+    types::GeoIndexKey {
+        geohash: geo::geohash(coordinates.latitude, coordinates.longitude, geo::GEOHASH_PRECISION),
+        id,
+    }
+}
+
+
+// Moves the index entry of record `id` from its previous position to the current one
+fn update_geo_index(
+    index: &'static std::thread::LocalKey<RefCell<GeoIndex>>,
+    id: u32,
+    previous: Option<types::Coordinates>,
+    current: Option<types::Coordinates>,
+) {
+    index.with(|geo_index| {
+        let mut geo_index = geo_index.borrow_mut();
+        if let Some(previous) = previous {
+            geo_index.remove(&geo_index_key(&previous, id));
+        }
+        if let Some(current) = current {
+            geo_index.insert(geo_index_key(&current, id), ());
+        }
+    });
+}
+
+
+// Ids of the records in the cells around the point. Some may be further away
+// than `radius_km`.
+fn geo_index_candidates(
+    index: &'static std::thread::LocalKey<RefCell<GeoIndex>>,
+    latitude: f64,
+    longitude: f64,
+    radius_km: f64,
+) -> Vec<u32> {
+    index.with(|geo_index| {
+        let geo_index = geo_index.borrow();
+        geo::covering_geohashes(latitude, longitude, radius_km)
+            .iter()
+            .flat_map(|prefix| {
+                geo_index
+                    .range(types::GeoIndexKey { geohash: prefix.clone(), id: 0 }..)
+                    .take_while(|(key, _)| key.geohash.starts_with(prefix.as_str()))
+                    .map(|(key, _)| key.id)
+                    .collect::<Vec<u32>>()
+            })
+            .collect()
+    })
+}
+
+
+// Sessions within `radius_km` of the point, closest first
+pub fn get_sessions_near(latitude: f64, longitude: f64, radius_km: f64, page_num: i32) -> (Vec<types::NearbySession>, i32) {
+    if !geo::is_valid_coordinate(latitude, longitude) {
+        return (vec![], 0);
+    }
+
+    let mut res: Vec<types::NearbySession> = SESSION_STORE.with(|session_store| {
+        let store = session_store.borrow();
+        geo_index_candidates(&SESSION_GEO_INDEX, latitude, longitude, radius_km)
+            .into_iter()
+            .filter_map(|id| store.get(&id))
+            .filter_map(|session| {
+                let coordinates = session.coordinates?;
+                let distance_km = geo::distance_km(latitude, longitude, coordinates.latitude, coordinates.longitude);
+                (distance_km <= radius_km).then_some(types::NearbySession { session: with_attendance(session, None), distance_km })
+            })
+            .collect()
+    });
+    res.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+
+    let result: Vec<types::NearbySession> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+// Listings within `radius_km` of the point, closest first
+pub fn get_instruments_near(latitude: f64, longitude: f64, radius_km: f64, page_num: i32) -> (Vec<types::NearbyInstrument>, i32) {
+    if !geo::is_valid_coordinate(latitude, longitude) {
+        return (vec![], 0);
+    }
+
+    let mut res: Vec<types::NearbyInstrument> = INSTRUMENT_STORE.with(|instrument_store| {
+        let store = instrument_store.borrow();
+        geo_index_candidates(&INSTRUMENT_GEO_INDEX, latitude, longitude, radius_km)
+            .into_iter()
+            .filter_map(|id| store.get(&id))
+            .filter(|instrument| listed_with_status(instrument, None))
+            .filter_map(|instrument| {
+                let coordinates = instrument.coordinates?;
+                let distance_km = geo::distance_km(latitude, longitude, coordinates.latitude, coordinates.longitude);
+                (distance_km <= radius_km).then_some(types::NearbyInstrument { instrument, distance_km })
+            })
+            .collect()
+    });
+    res.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+
+    let result: Vec<types::NearbyInstrument> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                            // Session attendance
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+fn rsvp_key(session_id: u32, occurrence: u64, principal: &str) -> types::RsvpKey {
+    types::RsvpKey {
+        session_id,
+        occurrence,
+        principal: principal.to_string(),
+    }
+}
+
+
+fn session_member_key(session_id: u32, principal: &str) -> types::SessionMemberKey {
+    types::SessionMemberKey {
+        session_id,
+        principal: principal.to_string(),
+    }
+}
+
+
+fn occurrence_rsvps(session_id: u32, occurrence: u64) -> Vec<types::Rsvp> {
+    RSVP_STORE.with(|rsvp_store| {
+        rsvp_store
+            .borrow()
+            .range(rsvp_key(session_id, occurrence, "")..)
+            .take_while(|(key, _)| key.session_id == session_id && key.occurrence == occurrence)
+            .map(|(_, rsvp)| rsvp)
+            .collect()
+    })
+}
+
+
+fn session_regulars(session_id: u32) -> Vec<String> {
+    SESSION_REGULAR_STORE.with(|regular_store| {
+        regular_store
+            .borrow()
+            .range(session_member_key(session_id, "")..)
+            .take_while(|(key, _)| key.session_id == session_id)
+            .map(|(key, _)| key.principal)
+            .collect()
+    })
+}
+
+
+fn rsvps_of(principal: &str) -> Vec<types::Rsvp> {
+    RSVP_STORE.with(|rsvp_store| {
+        rsvp_store
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == principal)
+            .map(|(_, rsvp)| rsvp)
+            .collect()
+    })
+}
+
+
+fn regular_session_ids(principal: &str) -> Vec<u32> {
+    SESSION_REGULAR_STORE.with(|regular_store| {
+        regular_store
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.principal == principal)
+            .map(|(key, _)| key.session_id)
+            .collect()
+    })
+}
+
+
+fn remove_session_attendance(session_id: u32) {
+    RSVP_STORE.with(|rsvp_store| {
+        let mut store = rsvp_store.borrow_mut();
+        let keys: Vec<types::RsvpKey> = store
+            .range(rsvp_key(session_id, 0, "")..)
+            .take_while(|(key, _)| key.session_id == session_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            store.remove(&key);
+        }
+    });
+    for principal in session_regulars(session_id) {
+        SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow_mut().remove(&session_member_key(session_id, &principal)));
+    }
+}
+
+
+// Adds the attendee counts of `occurrence`, or of the next occurrence when
+// None
+fn with_attendance(session: types::Session, occurrence: Option<u64>) -> types::SessionView {
+    let occurrence = occurrence.or_else(|| {
+        let schedule = session.schedule.as_ref()?;
+        schedule::next_occurrence(schedule, ic_cdk::api::time())
+    });
+    types::SessionView {
+        going_count: occurrence.map(|occurrence| occurrence_rsvps(session.id, occurrence).len() as u32),
+        regular_count: session_regulars(session.id).len() as u32,
+        session,
+    }
+}
+
+
+// Attendees whose friends are hidden from the viewer, or who blocked or were
+// blocked by the viewer, are left out
+fn member_view(principal: &String, viewer: Option<&String>) -> Option<types::Friend> {
+    if viewer.is_some_and(|viewer| is_blocked(viewer, principal)) {
+        return None;
+    }
+    let profile = PROFILE_STORE.with(|profile_store| profile_store.borrow().get(principal))?;
+    if !can_view(&privacy_settings(&profile).friends, &profile, viewer) {
+        return None;
+    }
+    Some(types::Friend {
+        principal: profile.principal,
+        avatar: None,
+        username: profile.username,
+        avatar_id: profile.avatar_id,
+        avatar_thumbnail_id: profile.avatar_thumbnail_id,
+        since: None,
+    })
+}
+
+
+// Start and end of the occurrence of `session` starting at `occurrence`
+fn find_occurrence(session: &types::Session, occurrence: u64) -> Result<(u64, u64), String> {
+    let schedule = session.schedule.as_ref().ok_or("Session has no schedule")?;
+    schedule::occurrences(schedule, occurrence, occurrence.saturating_add(1), 1)
+        .into_iter()
+        .find(|(start, _)| *start == occurrence)
+        .ok_or("No occurrence of the session starts at that time".to_string())
+}
+
+
+// RSVPs are only accepted for actual occurrences of the session that haven't
+// ended yet
+pub fn rsvp_session(principal: String, session_id: u32, occurrence: u64) -> Result<(), String> {
+    if !profile_exists(&principal) {
+        return Err("Profile not found".to_string());
+    }
+    let session = SESSION_STORE
+        .with(|session_store| session_store.borrow().get(&session_id))
+        .ok_or("Session not found")?;
+    let (_, end) = find_occurrence(&session, occurrence)?;
+    if end <= ic_cdk::api::time() {
+        return Err("That occurrence is over".to_string());
+    }
+
+    let key = rsvp_key(session_id, occurrence, &principal);
+    if RSVP_STORE.with(|rsvp_store| rsvp_store.borrow().contains_key(&key)) {
+        return Ok(());
+    }
+    RSVP_STORE.with(|rsvp_store| {
+        rsvp_store.borrow_mut().insert(key, types::Rsvp {
+            session_id,
+            occurrence,
+            principal: principal.clone(),
+            created_at: ic_cdk::api::time(),
+        })
+    });
+    notify(&session.principal, types::NotificationKind::SessionRsvp, &principal, Some(session_id as u64), format!("is going to {}", session.name));
+    Ok(())
+}
+
+
+pub fn cancel_rsvp(principal: String, session_id: u32, occurrence: u64) -> bool {
+    RSVP_STORE.with(|rsvp_store| {
+        rsvp_store
+            .borrow_mut()
+            .remove(&rsvp_key(session_id, occurrence, &principal))
+            .is_some()
+    })
+}
+
+
+pub fn set_session_regular(principal: String, session_id: u32, regular: bool) -> bool {
+    let session = match SESSION_STORE.with(|session_store| session_store.borrow().get(&session_id)) {
+        Some(session) => session,
+        None => {
+            ic_cdk::println!("Session with ID {} not found", session_id);
+            return false;
+        }
+    };
+    if !profile_exists(&principal) {
+        ic_cdk::println!("No profile found for principal: {}", principal);
+        return false;
+    }
+
+    let key = session_member_key(session_id, &principal);
+    if !regular {
+        SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow_mut().remove(&key));
+        return true;
+    }
+    if SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow().contains_key(&key)) {
+        return true;
+    }
+    SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow_mut().insert(key, ic_cdk::api::time()));
+    notify(&session.principal, types::NotificationKind::SessionRegular, &principal, Some(session_id as u64), format!("is now a regular at {}", session.name));
+    true
+}
+
+
+// Who is going to an occurrence, and the session's regulars
+pub fn get_session_attendance(session_id: u32, occurrence: u64, viewer: Option<String>) -> Option<types::SessionAttendance> {
+    let viewer = viewer.as_ref();
+    if !SESSION_STORE.with(|session_store| session_store.borrow().contains_key(&session_id)) {
+        return None;
+    }
+    Some(types::SessionAttendance {
+        session_id,
+        occurrence,
+        going: occurrence_rsvps(session_id, occurrence)
+            .iter()
+            .filter_map(|rsvp| member_view(&rsvp.principal, viewer))
+            .collect(),
+        regulars: session_regulars(session_id)
+            .iter()
+            .filter_map(|principal| member_view(principal, viewer))
+            .collect(),
+    })
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                           // Session organizers
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+const MAX_CO_ORGANIZERS: usize = 10;
+
+
+pub fn can_edit_session(session: &types::Session, principal: &String) -> bool {
+    session.principal == *principal || session.co_organizers.iter().flatten().any(|co_organizer| co_organizer == principal)
+}
+
+
+fn changed_session_fields(previous: &types::Session, updated: &types::Session) -> Vec<String> {
+    let fields = [
+        ("username", previous.username != updated.username),
+        ("name", previous.name != updated.name),
+        ("location", previous.location != updated.location),
+        ("daytime", previous.daytime != updated.daytime),
+        ("contact", previous.contact != updated.contact),
+        ("comment", previous.comment != updated.comment),
+        ("recurring", previous.recurring != updated.recurring),
+        ("schedule", previous.schedule != updated.schedule),
+        ("coordinates", previous.coordinates != updated.coordinates),
+    ];
+    fields
+        .iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(field, _)| field.to_string())
+        .collect()
+}
+
+
+fn record_session_audit(session_id: u32, actor: &str, action: types::SessionAuditAction) {
+    SESSION_AUDIT_STORE.with(|audit_store| {
+        let mut store = audit_store.borrow_mut();
+        let id = store
+            .range((session_id, 0)..=(session_id, u64::MAX))
+            .next_back()
+            .map(|((_, id), _)| id + 1)
+            .unwrap_or(1);
+        store.insert((session_id, id), types::SessionAuditEntry {
+            session_id,
+            id,
+            actor: actor.to_string(),
+            action,
+            created_at: ic_cdk::api::time(),
+        });
+    });
+}
+
+
+fn remove_session_audit(session_id: u32) {
+    SESSION_AUDIT_STORE.with(|audit_store| {
+        let mut store = audit_store.borrow_mut();
+        let keys: Vec<(u32, u64)> = store
+            .range((session_id, 0)..=(session_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            store.remove(&key);
+        }
+    });
+}
+
+
+// The trail of other people's sessions outlives a deleted account, without
+// saying who made the changes
+fn anonymize_session_audit(principal: &String) {
+    SESSION_AUDIT_STORE.with(|audit_store| {
+        let mut store = audit_store.borrow_mut();
+        let entries: Vec<types::SessionAuditEntry> = store
+            .iter()
+            .filter(|(_, entry)| entry.actor == *principal)
+            .map(|(_, entry)| entry)
+            .collect();
+        for mut entry in entries {
+            entry.actor = String::new();
+            store.insert((entry.session_id, entry.id), entry);
+        }
+    });
+}
+
+
+fn editable_session(session_id: u32, principal: &String) -> Result<types::Session, String> {
+    let session = SESSION_STORE
+        .with(|session_store| session_store.borrow().get(&session_id))
+        .ok_or("Session not found")?;
+    if !can_edit_session(&session, principal) {
+        return Err("Only the organizers can do this".to_string());
+    }
+    Ok(session)
+}
+
+
+// Only the owner chooses co-organizers
+pub fn add_co_organizer(principal: String, session_id: u32, co_organizer: String) -> Result<(), String> {
+    let mut session = editable_session(session_id, &principal)?;
+    if session.principal != principal {
+        return Err("Only the owner can add co-organizers".to_string());
+    }
+    if !profile_exists(&co_organizer) {
+        return Err("Profile not found".to_string());
+    }
+    if can_edit_session(&session, &co_organizer) {
+        return Ok(());
+    }
+    let co_organizers = session.co_organizers.get_or_insert_with(Vec::new);
+    if co_organizers.len() >= MAX_CO_ORGANIZERS {
+        return Err(format!("A session can have at most {} co-organizers", MAX_CO_ORGANIZERS));
+    }
+    co_organizers.push(co_organizer.clone());
+
+    SESSION_STORE.with(|session_store| session_store.borrow_mut().insert(session_id, session));
+    record_session_audit(session_id, &principal, types::SessionAuditAction::CoOrganizerAdded(co_organizer));
+    Ok(())
+}
+
+
+// The owner can remove anyone, co-organizers can step down themselves
+pub fn remove_co_organizer(principal: String, session_id: u32, co_organizer: String) -> Result<(), String> {
+    let mut session = editable_session(session_id, &principal)?;
+    if session.principal != principal && principal != co_organizer {
+        return Err("Only the owner can remove other co-organizers".to_string());
+    }
+    let co_organizers = session.co_organizers.get_or_insert_with(Vec::new);
+    if !co_organizers.contains(&co_organizer) {
+        return Err("Not a co-organizer of this session".to_string());
+    }
+    co_organizers.retain(|existing| existing != &co_organizer);
+
+    SESSION_STORE.with(|session_store| session_store.borrow_mut().insert(session_id, session));
+    record_session_audit(session_id, &principal, types::SessionAuditAction::CoOrganizerRemoved(co_organizer));
+    Ok(())
+}
+
+
+// Hands the session over to `new_owner`, who stops being a co-organizer if
+// they were one
+pub fn transfer_session(principal: String, session_id: u32, new_owner: String) -> Result<(), String> {
+    let mut session = editable_session(session_id, &principal)?;
+    if session.principal != principal {
+        return Err("Only the owner can transfer the session".to_string());
+    }
+    let new_owner_profile = PROFILE_STORE
+        .with(|profile_store| profile_store.borrow().get(&new_owner))
+        .ok_or("Profile not found")?;
+    if new_owner == principal {
+        return Ok(());
+    }
+
+    session.principal = new_owner.clone();
+    session.username = new_owner_profile.username;
+    session.co_organizers.get_or_insert_with(Vec::new).retain(|co_organizer| co_organizer != &new_owner);
+    let name = session.name.clone();
+    SESSION_STORE.with(|session_store| session_store.borrow_mut().insert(session_id, session));
+
+    record_session_audit(
+        session_id,
+        &principal,
+        types::SessionAuditAction::OwnershipTransferred {
+            from: principal.clone(),
+            to: new_owner.clone(),
+        },
+    );
+    notify(&new_owner, types::NotificationKind::SessionTransferred, &principal, Some(session_id as u64), format!("handed {} over to you", name));
+    Ok(())
+}
+
+
+// Newest first, visible to the organizers only
+pub fn get_session_audit(session_id: u32, principal: String, page_num: i32) -> Result<(Vec<types::SessionAuditEntry>, i32), String> {
+    editable_session(session_id, &principal)?;
+    let res: Vec<types::SessionAuditEntry> = SESSION_AUDIT_STORE.with(|audit_store| {
+        audit_store
+            .borrow()
+            .range((session_id, 0)..=(session_id, u64::MAX))
+            .rev()
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+
+    let result: Vec<types::SessionAuditEntry> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    Ok((result, res.len() as i32))
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                              // Id allocation
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Sessions, listings, forums and posts used to take the current time as their
+// id, which collides for records created in the same round and silently
+// overwrote the earlier one. ID_COUNTER_STORE now holds the next id of each.
+const SESSION_IDS: &str = "sessions";
+const INSTRUMENT_IDS: &str = "instruments";
+const FORUM_IDS: &str = "forums";
+const FORUM_POST_IDS: &str = "forum_posts";
+const RECORDING_IDS: &str = "recordings";
+const BLOB_UPLOAD_IDS: &str = "blob_uploads";
+const CONVERSATION_IDS: &str = "conversations";
+
+
+// Hands out the next id of `entity`, wrapping around after `max` and skipping
+// ids that are still in use, which only matters for the truncated timestamps
+// older sessions and listings got.
+fn allocate_id<F>(entity: &str, max: u64, in_use: F) -> u64
+where
+    F: Fn(u64) -> bool,
+{
+    ID_COUNTER_STORE.with(|counter_store| {
+        let mut store = counter_store.borrow_mut();
+        let mut id = store.get(&entity.to_string()).unwrap_or(1);
+        while id == 0 || id > max || in_use(id) {
+            id = if id >= max { 1 } else { id + 1 };
+        }
+        store.insert(entity.to_string(), id.saturating_add(1));
+        id
+    })
+}
+
+
+// Starts every counter after the largest existing id, so that ids keep
+// increasing across the switch.
+pub fn migrate_id_counters() {
+    let seeds = [
+        (SESSION_IDS, SESSION_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id as u64))),
+        (INSTRUMENT_IDS, INSTRUMENT_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id as u64))),
+        (FORUM_IDS, FORUM_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id))),
+        (FORUM_POST_IDS, FORUM_DATA_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id))),
+        (RECORDING_IDS, RECORDING_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id))),
+        (BLOB_UPLOAD_IDS, BLOB_UPLOAD_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id))),
+        (CONVERSATION_IDS, CONVERSATION_STORE.with(|store| store.borrow().last_key_value().map(|(id, _)| id))),
+    ];
+    ID_COUNTER_STORE.with(|counter_store| {
+        let mut store = counter_store.borrow_mut();
+        for (entity, last_id) in seeds {
+            if !store.contains_key(&entity.to_string()) {
+                store.insert(entity.to_string(), last_id.map(|id| id.saturating_add(1)).unwrap_or(1));
+            }
+        }
+    });
+}
+
+
+// Logs the collisions find_id_collisions can see, from a timer after an
+// upgrade so that the scan doesn't hold up the upgrade itself
+pub fn report_id_collisions() {
+    for collision in find_id_collisions() {
+        ic_cdk::println!("Id collision in {} {}: {}", collision.entity, collision.id, collision.detail);
+    }
+}
+
+
+// Overwritten records are gone, and only some of them leave traces in the
+// records that referenced them:
+// - forum posts that two forums list, or that one forum lists twice, were
+//   overwritten by a post created at the same time;
+// - sessions whose owner differs from the one their audit trail ends with
+//   were overwritten after the audit trail existed. Sessions overwritten
+//   before that, and forums overwritten by one created in the same round,
+//   leave nothing behind;
+// - listings with a conversation their seller isn't part of were
+//   overwritten by another seller's listing.
+fn find_id_collisions() -> Vec<types::IdCollision> {
+    let mut collisions = vec![];
+    let mut collision = |entity: &str, id: u64, detail: String| {
+        collisions.push(types::IdCollision {
+            entity: entity.to_string(),
+            id,
+            detail,
+        })
+    };
+
+    FORUM_STORE.with(|forum_store| {
+        FORUM_DATA_STORE.with(|forum_data_store| {
+            let (forum_store, forum_data_store) = (forum_store.borrow(), forum_data_store.borrow());
+            for (forum_id, forum) in forum_store.iter() {
+                let mut listed = std::collections::BTreeSet::new();
+                for post_id in forum.threads.iter().flatten() {
+                    if !listed.insert(*post_id) {
+                        collision(FORUM_POST_IDS, *post_id, format!("listed twice in forum {}", forum_id));
+                        continue;
+                    }
+                    match forum_data_store.get(post_id).and_then(|post| post.forum_id) {
+                        Some(other_forum) if other_forum != forum_id => collision(
+                            FORUM_POST_IDS,
+                            *post_id,
+                            format!("listed in forum {} but belongs to forum {}", forum_id, other_forum),
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+        })
+    });
+
+    // Owner of each session according to its audit trail, and how often it
+    // was created
+    let mut trail: std::collections::BTreeMap<u32, (String, u32)> = std::collections::BTreeMap::new();
+    SESSION_AUDIT_STORE.with(|audit_store| {
+        for (_, entry) in audit_store.borrow().iter() {
+            let (owner, created) = trail.entry(entry.session_id).or_default();
+            match entry.action {
+                types::SessionAuditAction::Created => {
+                    *owner = entry.actor;
+                    *created += 1;
+                }
+                types::SessionAuditAction::OwnershipTransferred { to, .. } => *owner = to,
+                _ => {}
+            }
+        }
+    });
+    SESSION_STORE.with(|session_store| {
+        let session_store = session_store.borrow();
+        for (session_id, (owner, created)) in trail {
+            if created > 1 {
+                collision(SESSION_IDS, session_id as u64, format!("created {} times", created));
+                continue;
+            }
+            // Creators who deleted their account are no longer named
+            match session_store.get(&session_id) {
+                Some(session) if !owner.is_empty() && session.principal != owner => collision(
+                    SESSION_IDS,
+                    session_id as u64,
+                    format!("owned by {} but the audit trail ends with {}", session.principal, owner),
+                ),
+                _ => {}
+            }
+        }
+    });
+
+    CONVERSATION_STORE.with(|conversation_store| {
+        INSTRUMENT_STORE.with(|instrument_store| {
+            let instrument_store = instrument_store.borrow();
+            for (conversation_id, conversation) in conversation_store.borrow().iter() {
+                let instrument_id = match conversation.instrument_id {
+                    Some(instrument_id) => instrument_id,
+                    None => continue,
+                };
+                if let Some(instrument) = instrument_store.get(&instrument_id) {
+                    if !conversation.participants.contains(&instrument.seller_principal) {
+                        collision(
+                            INSTRUMENT_IDS,
+                            instrument_id as u64,
+                            format!("conversation {} is about a listing of another seller", conversation_id),
+                        );
+                    }
+                }
+            }
+        })
+    });
+
+    collisions
+}
+
+
+pub fn get_id_collisions(principal: String) -> Result<Vec<types::IdCollision>, String> {
+    if !is_admin(&principal) {
+        return Err("Only admins can do this".to_string());
+    }
+    Ok(find_id_collisions())
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                             // Played tunes
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+const MAX_TUNES_PER_SET: usize = 10;
+const MAX_SET_TITLES_LENGTH: usize = 3000; // Bytes, keeps sets within their storage bound
+
+
+fn occurrence_sets(session_id: u32, occurrence: u64) -> Vec<types::PlayedSet> {
+    PLAYED_SET_STORE.with(|played_set_store| {
+        played_set_store
+            .borrow()
+            .range((session_id, occurrence, 0)..=(session_id, occurrence, u64::MAX))
+            .map(|(_, set)| set)
+            .collect()
+    })
+}
+
+
+fn session_sets(session_id: u32) -> Vec<types::PlayedSet> {
+    PLAYED_SET_STORE.with(|played_set_store| {
+        played_set_store
+            .borrow()
+            .range((session_id, 0, 0)..=(session_id, u64::MAX, u64::MAX))
+            .map(|(_, set)| set)
+            .collect()
+    })
+}
+
+
+// TUNE_PLAY_COUNT_STORE counts the sets each tune was played in, across all
+// sessions
+fn count_plays(tunes: &[String], added: bool) {
+    TUNE_PLAY_COUNT_STORE.with(|count_store| {
+        let mut store = count_store.borrow_mut();
+        for title in tunes {
+            let count = store.get(title).unwrap_or(0);
+            let count = if added { count + 1 } else { count.saturating_sub(1) };
+            if count == 0 {
+                store.remove(title);
+            } else {
+                store.insert(title.clone(), count);
+            }
+        }
+    });
+}
+
+
+fn remove_played_sets(session_id: u32) {
+    for set in session_sets(session_id) {
+        PLAYED_SET_STORE.with(|played_set_store| played_set_store.borrow_mut().remove(&(set.session_id, set.occurrence, set.id)));
+        count_plays(&set.tunes, false);
+    }
+}
+
+
+fn anonymize_played_sets(principal: &String) {
+    PLAYED_SET_STORE.with(|played_set_store| {
+        let mut store = played_set_store.borrow_mut();
+        let sets: Vec<types::PlayedSet> = store
+            .iter()
+            .filter(|(_, set)| set.recorded_by == *principal)
+            .map(|(_, set)| set)
+            .collect();
+        for mut set in sets {
+            set.recorded_by = String::new();
+            store.insert((set.session_id, set.occurrence, set.id), set);
+        }
+    });
+}
+
+
+// Organizers and whoever was going to the occurrence or is a regular can
+// record what was played, once the occurrence has started
+pub fn add_played_set(principal: String, session_id: u32, occurrence: u64, tunes: Vec<String>) -> Result<types::PlayedSet, String> {
+    let session = SESSION_STORE
+        .with(|session_store| session_store.borrow().get(&session_id))
+        .ok_or("Session not found")?;
+    let (start, _) = find_occurrence(&session, occurrence)?;
+    if start > ic_cdk::api::time() {
+        return Err("That occurrence hasn't started yet".to_string());
+    }
+    let attended = RSVP_STORE.with(|rsvp_store| rsvp_store.borrow().contains_key(&rsvp_key(session_id, occurrence, &principal)))
+        || SESSION_REGULAR_STORE.with(|regular_store| regular_store.borrow().contains_key(&session_member_key(session_id, &principal)));
+    if !attended && !can_edit_session(&session, &principal) {
+        return Err("Only organizers and attendees can record what was played".to_string());
+    }
+
+    if tunes.is_empty() || tunes.len() > MAX_TUNES_PER_SET {
+        return Err(format!("A set has between 1 and {} tunes", MAX_TUNES_PER_SET));
+    }
+    if tunes.iter().map(String::len).sum::<usize>() > MAX_SET_TITLES_LENGTH {
+        return Err("The tune titles are too long".to_string());
+    }
+    if let Some(missing) = tunes
+        .iter()
+        .find(|title| !TUNE_STORE.with(|tune_store| tune_store.borrow().contains_key(*title)))
+    {
+        return Err(format!("Tune not found: {}", missing));
+    }
+
+    let set = PLAYED_SET_STORE.with(|played_set_store| {
+        let mut store = played_set_store.borrow_mut();
+        let id = store
+            .range((session_id, occurrence, 0)..=(session_id, occurrence, u64::MAX))
+            .next_back()
+            .map(|((_, _, id), _)| id + 1)
+            .unwrap_or(1);
+        let set = types::PlayedSet {
+            id,
+            session_id,
+            occurrence,
+            tunes,
+            recorded_by: principal,
+            created_at: ic_cdk::api::time(),
+        };
+        store.insert((session_id, occurrence, id), set.clone());
+        set
+    });
+    count_plays(&set.tunes, true);
+    Ok(set)
+}
+
+
+// The person who recorded a set and the organizers can remove it
+pub fn remove_played_set(principal: String, session_id: u32, occurrence: u64, set_id: u64) -> bool {
+    let set = match PLAYED_SET_STORE.with(|played_set_store| played_set_store.borrow().get(&(session_id, occurrence, set_id))) {
+        Some(set) => set,
+        None => {
+            ic_cdk::println!("Set {} not found", set_id);
+            return false;
+        }
+    };
+    let organizer = SESSION_STORE
+        .with(|session_store| session_store.borrow().get(&session_id))
+        .is_some_and(|session| can_edit_session(&session, &principal));
+    if set.recorded_by != principal && !organizer {
+        ic_cdk::println!("Unauthorized delete attempt by {}", principal);
+        return false;
+    }
+
+    PLAYED_SET_STORE.with(|played_set_store| played_set_store.borrow_mut().remove(&(session_id, occurrence, set_id)));
+    count_plays(&set.tunes, false);
+    true
+}
+
+
+// In the order they were played
+pub fn get_played_sets(session_id: u32, occurrence: u64) -> Vec<types::PlayedSet> {
+    occurrence_sets(session_id, occurrence)
+}
+
+
+fn ranked_tunes(mut res: Vec<types::TunePlayCount>, page_num: i32) -> (Vec<types::TunePlayCount>, i32) {
+    res.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.title.cmp(&b.title)));
+
+    let result: Vec<types::TunePlayCount> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+// Tunes of a session by the number of sets they were played in
+pub fn get_most_played_tunes(session_id: u32, page_num: i32) -> (Vec<types::TunePlayCount>, i32) {
+    let mut counts: Vec<types::TunePlayCount> = vec![];
+    for set in session_sets(session_id) {
+        for title in set.tunes.iter() {
+            match counts.iter_mut().find(|count| count.title == *title) {
+                Some(count) => {
+                    count.count += 1;
+                    count.last_played = count.last_played.max(Some(set.occurrence));
+                }
+                None => counts.push(types::TunePlayCount {
+                    title: title.clone(),
+                    count: 1,
+                    last_played: Some(set.occurrence),
+                }),
+            }
+        }
+    }
+    ranked_tunes(counts, page_num)
+}
+
+
+// Tunes by the number of sets they were played in across all sessions
+pub fn get_popular_tunes(page_num: i32) -> (Vec<types::TunePlayCount>, i32) {
+    let counts = TUNE_PLAY_COUNT_STORE.with(|count_store| {
+        count_store
+            .borrow()
+            .iter()
+            .map(|(title, count)| types::TunePlayCount {
+                title,
+                count,
+                last_played: None,
+            })
+            .collect()
+    });
+    ranked_tunes(counts, page_num)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                            // Listing lifecycle
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Active listings expire after this long unless they are renewed
+const LISTING_LIFETIME_NS: u64 = 90 * 86_400_000_000_000;
+
+
+pub fn listing_status(instrument: &types::Instrument) -> types::ListingStatus {
+    instrument.status.unwrap_or(types::ListingStatus::Active)
+}
+
+
+// Whether a listing shows up in public listings filtered by `status`. Drafts
+// never do.
+fn listed_with_status(instrument: &types::Instrument, status: Option<types::ListingStatus>) -> bool {
+    let current = listing_status(instrument);
+    match status {
+        _ if current == types::ListingStatus::Draft => false,
+        Some(status) => current == status,
+        None => matches!(current, types::ListingStatus::Active | types::ListingStatus::Reserved),
+    }
+}
+
+
+fn can_change_status(from: types::ListingStatus, to: types::ListingStatus) -> bool {
+    use types::ListingStatus::*;
+    matches!(
+        (from, to),
+        (Draft, Active) | (Active, Draft) | (Active, Active) | (Active, Reserved) | (Reserved, Active) | (Expired, Active)
+    )
+}
+
+
+fn seller_listing(id: u32, seller_principal: &String) -> Result<types::Instrument, String> {
+    let instrument = INSTRUMENT_STORE
+        .with(|instrument_store| instrument_store.borrow().get(&id))
+        .ok_or("Listing not found")?;
+    if instrument.seller_principal != *seller_principal {
+        return Err("Only the seller can do this".to_string());
+    }
+    Ok(instrument)
+}
+
+
+// Moves a listing to `status`. Setting an active or expired listing to active
+// renews it. Use mark_sold to sell it.
+pub fn set_listing_status(seller_principal: String, id: u32, status: types::ListingStatus) -> Result<(), String> {
+    let mut instrument = seller_listing(id, &seller_principal)?;
+    let current = listing_status(&instrument);
+    if !can_change_status(current, status) {
+        return Err(format!("A {:?} listing can't become {:?}", current, status));
+    }
+
+    instrument.status = Some(status);
+    instrument.expires_at = (status == types::ListingStatus::Active).then_some(ic_cdk::api::time() + LISTING_LIFETIME_NS);
+    if current == types::ListingStatus::Reserved {
+        instrument.buyer_principal = String::new();
+    }
+    INSTRUMENT_STORE.with(|instrument_store| instrument_store.borrow_mut().insert(id, instrument));
+    Ok(())
+}
+
+
+// `buyer` is None when the instrument was sold to someone who isn't on TuneBook
+pub fn mark_sold(seller_principal: String, id: u32, buyer: Option<String>) -> Result<(), String> {
+    let mut instrument = seller_listing(id, &seller_principal)?;
+    let current = listing_status(&instrument);
+    if !matches!(current, types::ListingStatus::Active | types::ListingStatus::Reserved) {
+        return Err(format!("A {:?} listing can't be sold", current));
+    }
+    if let Some(buyer) = buyer.as_ref() {
+        if *buyer == seller_principal || !profile_exists(buyer) {
+            return Err("Buyer not found".to_string());
+        }
+    }
+
+    instrument.status = Some(types::ListingStatus::Sold);
+    instrument.buyer_principal = buyer.unwrap_or_default();
+    instrument.expires_at = None;
+    instrument.sold_at = Some(ic_cdk::api::time());
+    INSTRUMENT_STORE.with(|instrument_store| instrument_store.borrow_mut().insert(id, instrument));
+    Ok(())
+}
+
+
+// All listings of the seller, drafts included, newest first
+pub fn get_my_instruments(seller_principal: String, status: Option<types::ListingStatus>, page_num: i32) -> (Vec<types::Instrument>, i32) {
+    let mut res: Vec<types::Instrument> = INSTRUMENT_STORE.with(|instrument_store| {
+        instrument_store
+            .borrow()
+            .iter()
+            .filter(|(_, instrument)| instrument.seller_principal == seller_principal)
+            .filter(|(_, instrument)| status.is_none_or(|status| listing_status(instrument) == status))
+            .map(|(_, instrument)| instrument)
+            .collect()
+    });
+    res.sort_by_key(|instrument| std::cmp::Reverse(instrument.created_at));
+
+    let result: Vec<types::Instrument> = res
+        .iter()
+        .skip(page_num as usize * 15)
+        .take(15)
+        .cloned()
+        .collect();
+
+    (result, res.len() as i32)
+}
+
+
+// Called periodically from a timer
+pub fn expire_listings() {
+    let now = ic_cdk::api::time();
+    INSTRUMENT_STORE.with(|instrument_store| {
+        let mut store = instrument_store.borrow_mut();
+        let stale: Vec<types::Instrument> = store
+            .iter()
+            .filter(|(_, instrument)| listing_status(instrument) == types::ListingStatus::Active)
+            .filter(|(_, instrument)| instrument.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(_, instrument)| instrument)
+            .collect();
+        for mut instrument in stale {
+            instrument.status = Some(types::ListingStatus::Expired);
+            instrument.expires_at = None;
+            store.insert(instrument.id, instrument);
+        }
+    });
+}
+
+
+// Listings created before statuses existed are active, and start their
+// lifetime now rather than expiring all at once
+fn migrate_listing_status(mut instrument: types::Instrument) -> Option<types::Instrument> {
+    if instrument.status.is_some() {
+        return None;
+    }
+    let now = ic_cdk::api::time();
+    instrument.status = Some(types::ListingStatus::Active);
+    instrument.created_at = instrument.created_at.or(Some(now));
+    instrument.expires_at = Some(now + LISTING_LIFETIME_NS);
+    Some(instrument)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                              // Listing prices
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Free text prices without a currency are assumed to be in this one
+const DEFAULT_CURRENCY: &str = "EUR";
+
+
+fn matches_price(instrument: &types::Instrument, filter: &types::InstrumentFilter) -> bool {
+    let currency = match filter.currency.as_ref() {
+        Some(currency) => currency,
+        None => return true,
+    };
+    match instrument.asking_price.as_ref() {
+        Some(price) => {
+            price.currency == *currency
+                && filter.min_price.is_none_or(|min_price| price.amount >= min_price)
+                && filter.max_price.is_none_or(|max_price| price.amount <= max_price)
+        }
+        None => false,
+    }
+}
+
+
+// Prices are ordered by currency first; listings without a price come last
+fn sort_instruments(instruments: &mut [types::Instrument], sort: Option<types::InstrumentSort>) {
+    match sort {
+        Some(types::InstrumentSort::Newest) => {
+            instruments.sort_by_key(|instrument| std::cmp::Reverse(instrument.created_at));
+        }
+        Some(types::InstrumentSort::PriceAscending) => instruments.sort_by(|a, b| {
+            match (a.asking_price.as_ref(), b.asking_price.as_ref()) {
+                (Some(a), Some(b)) => a.currency.cmp(&b.currency).then(a.amount.cmp(&b.amount)),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }
+        }),
+        Some(types::InstrumentSort::PriceDescending) => instruments.sort_by(|a, b| {
+            match (a.asking_price.as_ref(), b.asking_price.as_ref()) {
+                (Some(a), Some(b)) => a.currency.cmp(&b.currency).then(b.amount.cmp(&a.amount)),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }
+        }),
+        None => {}
+    }
+}
+
+
+// Derives a structured price from the text of listings created before
+// prices were structured. Listings whose price can't be understood keep
+// only the text.
+fn migrate_listing_price(mut instrument: types::Instrument) -> Option<types::Instrument> {
+    if instrument.asking_price.is_some() {
+        return None;
+    }
+    instrument.asking_price = price::parse(&instrument.price, DEFAULT_CURRENCY);
+    if instrument.asking_price.is_none() {
+        ic_cdk::println!("Could not parse the price of instrument {}: {}", instrument.id, instrument.price);
+    }
+    instrument.asking_price.is_some().then_some(instrument)
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                            // Listing details
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+const MAX_MAKER_LENGTH: usize = 100;
+const OLDEST_INSTRUMENT_YEAR: u32 = 1600;
+
+
+fn current_year() -> u32 {
+    1970 + (ic_cdk::api::time() / 31_556_952_000_000_000) as u32
+}
+
+
+fn validate_listing_details(details: &types::ListingDetails) -> Result<(), String> {
+    if details.maker.as_ref().is_some_and(|maker| maker.chars().count() > MAX_MAKER_LENGTH) {
+        return Err(format!("Maker must be at most {} characters", MAX_MAKER_LENGTH));
+    }
+    if details.year.is_some_and(|year| !(OLDEST_INSTRUMENT_YEAR..=current_year()).contains(&year)) {
+        return Err("Year is out of range".to_string());
+    }
+    if let Some(types::ListingCategory::Instrument(types::InstrumentKind::Other(name))) = details.category.as_ref() {
+        if name.trim().is_empty() {
+            return Err("Instrument name is required".to_string());
+        }
+    }
+    Ok(())
+}
+
+
+fn same_category(a: &types::ListingCategory, b: &types::ListingCategory) -> bool {
+    match (a, b) {
+        (types::ListingCategory::Instrument(a), types::ListingCategory::Instrument(b)) => same_instrument(a, b),
+        _ => a == b,
+    }
+}
+
+
+fn matches_details(instrument: &types::Instrument, filter: &types::InstrumentFilter) -> bool {
+    let matches = |wanted: Option<&types::ListingCategory>| {
+        wanted.is_none_or(|wanted| instrument.category.as_ref().is_some_and(|category| same_category(category, wanted)))
+    };
+    matches(filter.category.as_ref())
+        && filter.condition.is_none_or(|condition| instrument.condition == Some(condition))
+        && filter.maker.as_ref().is_none_or(|maker| {
+            instrument.maker.as_ref().is_some_and(|existing| existing.trim().eq_ignore_ascii_case(maker.trim()))
+        })
+        && filter.min_year.is_none_or(|min_year| instrument.year.is_some_and(|year| year >= min_year))
+        && filter.max_year.is_none_or(|max_year| instrument.year.is_some_and(|year| year <= max_year))
+}
+
+
+fn add_facet<T, F>(counts: &mut Vec<(T, u32)>, value: T, same: F)
+where
+    F: Fn(&T, &T) -> bool,
+{
+    match counts.iter_mut().find(|(existing, _)| same(existing, &value)) {
+        Some((_, count)) => *count += 1,
+        None => counts.push((value, 1)),
+    }
+}
+
+
+// Counts for the listings get_instruments would return with the same search
+// and filter, most common values first
+pub fn get_instrument_facets(sub_name: &str, filter: Option<types::InstrumentFilter>) -> types::InstrumentFacets {
+    let filter = filter.unwrap_or_default();
+    let without = |clear: fn(&mut types::InstrumentFilter)| {
+        let mut filter = filter.clone();
+        clear(&mut filter);
+        filter
+    };
+    let (by_category, by_condition, by_maker) = (
+        without(|filter| filter.category = None),
+        without(|filter| filter.condition = None),
+        without(|filter| filter.maker = None),
+    );
+
+    let mut facets = types::InstrumentFacets {
+        categories: vec![],
+        conditions: vec![],
+        makers: vec![],
+    };
+    INSTRUMENT_STORE.with(|instrument_store| {
+        for (_, instrument) in instrument_store.borrow().iter() {
+            let listed = (instrument.name.to_lowercase().contains(&sub_name.to_lowercase())
+                || instrument.location.to_lowercase().contains(&sub_name.to_lowercase()))
+                && listed_with_status(&instrument, filter.status)
+                && matches_price(&instrument, &filter);
+            if !listed {
+                continue;
+            }
+            if let Some(category) = instrument.category.clone().filter(|_| matches_details(&instrument, &by_category)) {
+                add_facet(&mut facets.categories, category, same_category);
+            }
+            if let Some(condition) = instrument.condition.filter(|_| matches_details(&instrument, &by_condition)) {
+                add_facet(&mut facets.conditions, condition, |a, b| a == b);
+            }
+            if let Some(maker) = instrument.maker.clone().filter(|_| matches_details(&instrument, &by_maker)) {
+                add_facet(&mut facets.makers, maker, |a, b| a.trim().eq_ignore_ascii_case(b.trim()));
+            }
+        }
+    });
+
+    facets.categories.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    facets.conditions.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    facets.makers.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    facets
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                                // Offers
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+const MAX_OFFER_MESSAGE_LENGTH: usize = 2000;
+
+
+fn listing_offers(instrument_id: u32) -> Vec<types::Offer> {
+    OFFER_STORE.with(|offer_store| {
+        offer_store
+            .borrow()
+            .range((instrument_id, 0)..=(instrument_id, u64::MAX))
+            .map(|(_, offer)| offer)
+            .collect()
+    })
+}
+
+
+// Removes the negotiations about a listing, only the one with `buyer` when given
+fn remove_offers(instrument_id: u32, buyer: Option<&String>) {
+    for offer in listing_offers(instrument_id) {
+        if buyer.is_none_or(|buyer| offer.buyer == *buyer) {
+            OFFER_STORE.with(|offer_store| offer_store.borrow_mut().remove(&(instrument_id, offer.id)));
+        }
+    }
+}
+
+
+fn save_offer(offer: &types::Offer) {
+    OFFER_STORE.with(|offer_store| offer_store.borrow_mut().insert((offer.instrument_id, offer.id), offer.clone()));
+}
+
+
+// Checks that `principal` can take part in the negotiation about `instrument`
+// with `buyer`, and returns the other side
+fn negotiation_counterpart(instrument: &types::Instrument, principal: &String, buyer: &String) -> Result<String, String> {
+    if *buyer == instrument.seller_principal {
+        return Err("You can't make offers on your own listing".to_string());
+    }
+    let counterpart = if *principal == instrument.seller_principal {
+        buyer.clone()
+    } else if principal == buyer {
+        instrument.seller_principal.clone()
+    } else {
+        return Err("Not part of this negotiation".to_string());
+    };
+    if !profile_exists(principal) || !profile_exists(&counterpart) {
+        return Err("Profile not found".to_string());
+    }
+    if is_blocked(principal, &counterpart) {
+        return Err("You can't negotiate with this user".to_string());
+    }
+    Ok(counterpart)
+}
+
+
+// Adds an offer to the negotiation, closing the offer that is still open in
+// it: a new offer from the same side replaces it, one from the other side
+// counters it
+fn add_to_negotiation(
+    instrument_id: u32,
+    buyer: &String,
+    author: &String,
+    message: String,
+    price: types::Price,
+) -> Result<types::Offer, String> {
+    if message.chars().count() > MAX_OFFER_MESSAGE_LENGTH {
+        return Err(format!("Message must be at most {} characters", MAX_OFFER_MESSAGE_LENGTH));
+    }
+    price::validate(&price)?;
+
+    let now = ic_cdk::api::time();
+    for mut open in listing_offers(instrument_id)
+        .into_iter()
+        .filter(|offer| offer.buyer == *buyer && offer.status == types::OfferStatus::Open)
+    {
+        open.status = if open.author == *author { types::OfferStatus::Withdrawn } else { types::OfferStatus::Countered };
+        open.updated_at = Some(now);
+        save_offer(&open);
+    }
+
+    let id = OFFER_STORE.with(|offer_store| {
+        offer_store
+            .borrow()
+            .range((instrument_id, 0)..=(instrument_id, u64::MAX))
+            .next_back()
+            .map(|((_, id), _)| id + 1)
+            .unwrap_or(1)
+    });
+    let offer = types::Offer {
+        id,
+        instrument_id,
+        buyer: buyer.clone(),
+        author: author.clone(),
+        message,
+        status: types::OfferStatus::Open,
+        price,
+        created_at: now,
+        updated_at: None,
+    };
+    save_offer(&offer);
+    Ok(offer)
+}
+
+
+fn negotiable_listing(instrument_id: u32) -> Result<types::Instrument, String> {
+    let instrument = INSTRUMENT_STORE
+        .with(|instrument_store| instrument_store.borrow().get(&instrument_id))
+        .ok_or("Listing not found")?;
+    if !matches!(listing_status(&instrument), types::ListingStatus::Active | types::ListingStatus::Reserved) {
+        return Err("This listing is no longer available".to_string());
+    }
+    Ok(instrument)
+}
+
+
+pub fn make_offer(buyer: String, instrument_id: u32, price: types::Price, message: String) -> Result<types::Offer, String> {
+    let instrument = negotiable_listing(instrument_id)?;
+    if listing_status(&instrument) != types::ListingStatus::Active {
+        return Err("This listing is reserved".to_string());
+    }
+    negotiation_counterpart(&instrument, &buyer, &buyer)?;
+    if let Some(asking_price) = instrument.asking_price.as_ref() {
+        if asking_price.currency != price.currency {
+            return Err(format!("Offers must be in {}", asking_price.currency));
+        }
+    }
+
+    let offer = add_to_negotiation(instrument_id, &buyer, &buyer, message, price)?;
+    notify(&instrument.seller_principal, types::NotificationKind::InstrumentOffer, &buyer, Some(instrument_id as u64), format!("made an offer on {}", instrument.name));
+    Ok(offer)
+}
+
+
+// Answers an open offer made by the other side. Accepting reserves the listing
+// for the buyer and closes every other open offer; countering returns the
+// counter-offer. A message with an answer goes to the conversation about the
+// listing, a counter-offer carries its own.
+pub fn respond_to_offer(
+    principal: String,
+    instrument_id: u32,
+    offer_id: u64,
+    response: types::OfferResponse,
+    message: Option<String>,
+) -> Result<types::Offer, String> {
+    let mut offer = OFFER_STORE
+        .with(|offer_store| offer_store.borrow().get(&(instrument_id, offer_id)))
+        .ok_or("Offer not found")?;
+    let mut instrument = negotiable_listing(instrument_id)?;
+    let counterpart = negotiation_counterpart(&instrument, &principal, &offer.buyer)?;
+    if offer.status != types::OfferStatus::Open {
+        return Err("This offer is not open".to_string());
+    }
+    if offer.author == principal {
+        return Err("You can't answer your own offer".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    match response {
+        types::OfferResponse::Accept => {
+            if listing_status(&instrument) != types::ListingStatus::Active {
+                return Err("This listing is reserved".to_string());
+            }
+            if let Some(message) = message.filter(|message| !message.trim().is_empty()) {
+                send_message(principal.clone(), counterpart.clone(), message, Some(instrument_id))?;
+            }
+            accept_offer(&mut offer, &mut instrument, now);
+            save_offer(&offer);
+
+            // Everyone else still negotiating learns that the listing is gone
+            for mut other in listing_offers(instrument_id)
+                .into_iter()
+                .filter(|other| other.id != offer_id && other.status == types::OfferStatus::Open)
+            {
+                other.status = if other.author == instrument.seller_principal {
+                    types::OfferStatus::Withdrawn
+                } else {
+                    types::OfferStatus::Declined
+                };
+                other.updated_at = Some(now);
+                save_offer(&other);
+                notify(&other.buyer, types::NotificationKind::OfferDeclined, &instrument.seller_principal, Some(instrument_id as u64), format!("reserved {} for another buyer", instrument.name));
+            }
+
+            INSTRUMENT_STORE.with(|instrument_store| instrument_store.borrow_mut().insert(instrument_id, instrument.clone()));
+
+            notify(&counterpart, types::NotificationKind::OfferAccepted, &principal, Some(instrument_id as u64), format!("accepted your offer on {}", instrument.name));
+            Ok(offer)
+        }
+        types::OfferResponse::Decline => {
+            if let Some(message) = message.filter(|message| !message.trim().is_empty()) {
+                send_message(principal.clone(), counterpart.clone(), message, Some(instrument_id))?;
+            }
+            offer.status = types::OfferStatus::Declined;
+            offer.updated_at = Some(now);
+            save_offer(&offer);
+
+            notify(&counterpart, types::NotificationKind::OfferDeclined, &principal, Some(instrument_id as u64), format!("declined your offer on {}", instrument.name));
+            Ok(offer)
+        }
+        types::OfferResponse::Counter(price) => {
+            if offer.price.currency != price.currency {
+                return Err("Counter-offers must be in the currency of the offer".to_string());
+            }
+            let counter = add_to_negotiation(instrument_id, &offer.buyer, &principal, message.unwrap_or_default(), price)?;
+            notify(&counterpart, types::NotificationKind::InstrumentOffer, &principal, Some(instrument_id as u64), format!("made a counter-offer on {}", instrument.name));
+            Ok(counter)
+        }
+    }
+}
+
+
+// Accepting reserves the listing for the buyer until it is sold or the
+// reservation is released
+fn accept_offer(offer: &mut types::Offer, instrument: &mut types::Instrument, now: u64) {
+    offer.status = types::OfferStatus::Accepted;
+    offer.updated_at = Some(now);
+    instrument.status = Some(types::ListingStatus::Reserved);
+    instrument.buyer_principal = offer.buyer.clone();
+    instrument.expires_at = None;
+}
+
+
+// Only the author of an open offer can withdraw it
+fn withdraw(offer: &mut types::Offer, principal: &str, now: u64) -> bool {
+    if offer.author != principal || offer.status != types::OfferStatus::Open {
+        return false;
+    }
+    offer.status = types::OfferStatus::Withdrawn;
+    offer.updated_at = Some(now);
+    true
+}
+
+
+pub fn withdraw_offer(principal: String, instrument_id: u32, offer_id: u64) -> bool {
+    let mut offer = match OFFER_STORE.with(|offer_store| offer_store.borrow().get(&(instrument_id, offer_id))) {
+        Some(offer) => offer,
+        None => return false,
+    };
+    if !withdraw(&mut offer, &principal, ic_cdk::api::time()) {
+        ic_cdk::println!("No open offer {} by {}", offer_id, principal);
+        return false;
+    }
+    save_offer(&offer);
+    true
+}
+
+
+// The whole negotiation history in order: every negotiation for the seller,
+// their own for a buyer
+pub fn get_offers(principal: String, instrument_id: u32) -> Result<Vec<types::Offer>, String> {
+    let instrument = INSTRUMENT_STORE
+        .with(|instrument_store| instrument_store.borrow().get(&instrument_id))
+        .ok_or("Listing not found")?;
+    Ok(listing_offers(instrument_id)
+        .into_iter()
+        .filter(|offer| instrument.seller_principal == principal || offer.buyer == principal)
+        .collect())
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+                          // Background migrations
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////
+
+
+// Migrations that do real work per record, such as decoding images, can't
+// run in post_upgrade without risking the upgrade instruction limit. They
+// run from a timer instead, one batch per message, each remembering the last
+// key it processed. An upgrade in the middle resumes where the last batch
+// stopped and every record is visited once, even when migrating it fails.
+const MIGRATION_INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+// Runs one batch of the named migration, returning whether it is finished
+type MigrationBatch = fn(&str) -> bool;
+
+// In the order they run. The username index and the friend graph come first
+// since lookups by username and everything built on relationships read only
+// the new stores; they do little per record and finish within a few batches.
+const BACKGROUND_MIGRATIONS: &[(&str, MigrationBatch)] = &[
+    ("username_index", |name| migrate_batch(name, &PROFILE_STORE, migrate_username_index)),
+    ("friend_graph", |name| migrate_batch(name, &PROFILE_STORE, migrate_friend_graph)),
+    ("listing_status", |name| migrate_batch(name, &INSTRUMENT_STORE, migrate_listing_status)),
+    ("session_schedules", |name| migrate_batch(name, &SESSION_STORE, migrate_session_schedule)),
+    ("listing_prices", |name| migrate_batch(name, &INSTRUMENT_STORE, migrate_listing_price)),
+    ("inline_avatars", |name| migrate_batch(name, &PROFILE_STORE, migrate_inline_avatar)),
+    ("inline_instrument_photos", |name| migrate_batch(name, &INSTRUMENT_STORE, migrate_inline_instrument_photos)),
+    ("inline_post_photos", |name| migrate_batch(name, &FORUM_DATA_STORE, migrate_inline_post_photos)),
+    ("avatar_thumbnails", |name| migrate_batch(name, &PROFILE_STORE, backfill_avatar_thumbnail)),
+    ("instrument_thumbnails", |name| migrate_batch(name, &INSTRUMENT_STORE, backfill_instrument_thumbnails)),
+    ("post_thumbnails", |name| migrate_batch(name, &FORUM_DATA_STORE, backfill_post_thumbnails)),
+];
+
+
+// Runs one batch of the first unfinished migration and schedules the next
+// one while anything is left.
+pub fn run_background_migrations() {
+    let pending = BACKGROUND_MIGRATIONS.iter().find(|(name, _)| {
+        MIGRATION_STORE
+            .with(|migration_store| migration_store.borrow().get(&name.to_string()))
+            .is_none_or(|progress| progress.finished_at.is_none())
+    });
+    if let Some((name, batch)) = pending {
+        if batch(name) {
+            ic_cdk::println!("Migration {} finished", name);
+        }
+        ic_cdk_timers::set_timer(std::time::Duration::ZERO, run_background_migrations);
+    }
+}
+
+
+// Passes the records after the migration's cursor to `migrate`, storing the
+// ones it returns, until the instruction budget is spent. Returns whether the
+// migration reached the end of the store.
+fn migrate_batch<K, V>(
+    name: &str,
+    store: &'static std::thread::LocalKey<RefCell<StableBTreeMap<K, V, Memory>>>,
+    migrate: fn(V) -> Option<V>,
+) -> bool
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut progress = MIGRATION_STORE
+        .with(|migration_store| migration_store.borrow().get(&name.to_string()))
+        .unwrap_or_default();
+    let mut cursor: Option<K> = progress.cursor.as_ref().map(|bytes| K::from_bytes(Cow::Borrowed(bytes)));
+    let start = ic_cdk::api::instruction_counter();
+
+    while progress.finished_at.is_none() && ic_cdk::api::instruction_counter() - start < MIGRATION_INSTRUCTION_BUDGET {
+        let next = store.with(|store| {
+            let store = store.borrow();
+            match cursor.clone() {
+                Some(key) => store.range((std::ops::Bound::Excluded(key), std::ops::Bound::Unbounded)).next(),
+                None => store.iter().next(),
+            }
+        });
+        match next {
+            Some((key, record)) => {
+                if let Some(migrated) = migrate(record) {
+                    store.with(|store| store.borrow_mut().insert(key.clone(), migrated));
+                }
+                progress.processed += 1;
+                cursor = Some(key);
+            }
+            None => progress.finished_at = Some(ic_cdk::api::time()),
+        }
+    }
+
+    progress.cursor = cursor.map(|key| key.to_bytes().into_owned());
+    let finished = progress.finished_at.is_some();
+    MIGRATION_STORE.with(|migration_store| migration_store.borrow_mut().insert(name.to_string(), progress));
+    finished
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::RelationshipState::*;
+
+    fn profile(principal: &str, privacy: types::PrivacySettings) -> types::Profile {
+        types::Profile {
+            principal: principal.to_string(),
+            username: principal.to_string(),
+            avatar: None,
+            avatar_id: None,
+            avatar_thumbnail_id: None,
+            pob: String::new(),
+            instruments: String::new(),
+            bio: None,
+            friends: vec![],
+            incoming_fr: vec![],
+            outcoming_fr: vec![],
+            played_instruments: None,
+            home: None,
+            privacy: Some(privacy),
+        }
+    }
+
+    fn befriend(a: &str, b: &str) {
+        RELATIONSHIP_STORE.with(|relationship_store| {
+            let mut store = relationship_store.borrow_mut();
+            for (owner, other) in [(a, b), (b, a)] {
+                store.insert(relationship_key(owner, other), types::Relationship {
+                    state: Friends,
+                    created_at: 0,
+                    updated_at: 0,
+                });
+            }
+        });
+    }
+
+    fn listing(seller: &str) -> types::Instrument {
+        types::Instrument {
+            id: 1,
+            seller_principal: seller.to_string(),
+            buyer_principal: String::new(),
+            username: seller.to_string(),
+            name: "Fiddle".to_string(),
+            location: String::new(),
+            product: String::new(),
+            comment: String::new(),
+            price: "€850".to_string(),
+            asking_price: None,
+            photos: None,
+            photo_ids: None,
+            thumbnail_ids: None,
+            coordinates: None,
+            status: Some(types::ListingStatus::Active),
+            created_at: Some(0),
+            expires_at: Some(LISTING_LIFETIME_NS),
+            sold_at: None,
+            category: None,
+            condition: None,
+            maker: None,
+            year: None,
+            updated_at: None,
+        }
+    }
+
+    fn open_offer(buyer: &str, author: &str) -> types::Offer {
+        types::Offer {
+            id: 1,
+            instrument_id: 1,
+            buyer: buyer.to_string(),
+            author: author.to_string(),
+            message: String::new(),
+            price: types::Price {
+                amount: 80_000,
+                currency: "EUR".to_string(),
+                negotiable: false,
+            },
+            status: types::OfferStatus::Open,
+            created_at: 0,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn only_the_receiver_of_a_request_can_accept_it() {
+        let (sent, received) = relationship_transition(RelationshipChange::Request, None, None).unwrap();
+        assert_eq!((sent.clone(), received.clone()), (Some(RequestSent), Some(RequestReceived)));
+
+        assert_eq!(relationship_transition(RelationshipChange::Accept, sent.as_ref(), received.as_ref()), None);
+        assert_eq!(
+            relationship_transition(RelationshipChange::Accept, received.as_ref(), sent.as_ref()),
+            Some((Some(Friends), Some(Friends)))
+        );
+        assert_eq!(relationship_transition(RelationshipChange::Accept, None, None), None);
+        assert_eq!(relationship_transition(RelationshipChange::Request, Some(&Friends), Some(&Friends)), None);
+    }
+
+    #[test]
+    fn blocking_replaces_a_friendship() {
+        assert_eq!(
+            relationship_transition(RelationshipChange::Block, Some(&Friends), Some(&Friends)),
+            Some((Some(Blocked), Some(BlockedBy)))
+        );
+        assert_eq!(
+            relationship_transition(RelationshipChange::Block, Some(&BlockedBy), Some(&Blocked)),
+            Some((Some(Blocked), Some(Blocked)))
+        );
+        // The blocked user can't get back in touch or unblock themselves
+        assert_eq!(relationship_transition(RelationshipChange::Request, Some(&BlockedBy), Some(&Blocked)), None);
+        assert_eq!(relationship_transition(RelationshipChange::Unfriend, Some(&BlockedBy), Some(&Blocked)), None);
+        assert_eq!(relationship_transition(RelationshipChange::Unblock, Some(&BlockedBy), Some(&Blocked)), None);
+
+        assert_eq!(
+            relationship_transition(RelationshipChange::Unblock, Some(&Blocked), Some(&BlockedBy)),
+            Some((None, None))
+        );
+        assert_eq!(
+            relationship_transition(RelationshipChange::Unblock, Some(&Blocked), Some(&Blocked)),
+            Some((Some(BlockedBy), Some(Blocked)))
+        );
+    }
+
+    #[test]
+    fn sections_follow_the_owners_privacy_settings() {
+        let privacy = types::PrivacySettings {
+            bio: types::Visibility::Private,
+            instruments: types::Visibility::Public,
+            friends: types::Visibility::FriendsOnly,
+            tunebook: types::Visibility::Public,
+            messages: None,
+        };
+        PROFILE_STORE.with(|profile_store| profile_store.borrow_mut().insert("alice".to_string(), profile("alice", privacy)));
+        befriend("alice", "bob");
+        let (alice, bob, carol) = ("alice".to_string(), "bob".to_string(), "carol".to_string());
+
+        assert!(can_view_section(&alice, Some(&bob), |privacy| &privacy.friends));
+        assert!(!can_view_section(&alice, Some(&carol), |privacy| &privacy.friends));
+        assert!(!can_view_section(&alice, None, |privacy| &privacy.friends));
+        assert!(can_view_section(&alice, Some(&carol), |privacy| &privacy.instruments));
+        assert!(can_view_section(&alice, None, |privacy| &privacy.instruments));
+        assert!(!can_view_section(&alice, Some(&bob), |privacy| &privacy.bio));
+        assert!(can_view_section(&alice, Some(&alice), |privacy| &privacy.bio));
+        assert!(can_view_section(&carol, None, |privacy| &privacy.bio));
+    }
+
+    #[test]
+    fn usernames_are_compared_in_their_normalized_form() {
+        assert_eq!(normalize_username(" Ａｌｉｃｅ "), "alice");
+        assert_eq!(normalize_username("ﬁddler"), "fiddler");
+
+        assert!(check_username_available("p1".to_string(), "Admin".to_string()).is_err());
+        assert!(check_username_available("p1".to_string(), "ＡＤＭＩＮ".to_string()).is_err());
+        assert!(check_username_available("p1".to_string(), "ab".to_string()).is_err());
+        assert!(check_username_available("p1".to_string(), "a".repeat(MAX_USERNAME_LENGTH + 1)).is_err());
+
+        USERNAME_STORE.with(|username_store| username_store.borrow_mut().insert("alice".to_string(), "p1".to_string()));
+        assert!(check_username_available("p2".to_string(), "ALICE".to_string()).is_err());
+        assert!(check_username_available("p2".to_string(), "Ａｌｉｃｅ".to_string()).is_err());
+        assert_eq!(check_username_available("p1".to_string(), "Alice".to_string()), Ok(()));
+    }
+
+    #[test]
+    fn allocated_ids_wrap_around_and_skip_ids_in_use() {
+        ID_COUNTER_STORE.with(|counter_store| counter_store.borrow_mut().insert("wrapping".to_string(), 5));
+        assert_eq!(allocate_id("wrapping", 5, |_| false), 5);
+        assert_eq!(allocate_id("wrapping", 5, |_| false), 1);
+        assert_eq!(allocate_id("wrapping", 5, |id| id == 2), 3);
+
+        assert_eq!(allocate_id("colliding", 100, |id| id <= 3), 4);
+        assert_eq!(allocate_id("colliding", 100, |_| false), 5);
+    }
+
+    #[test]
+    fn accepting_an_offer_reserves_the_listing_for_the_buyer() {
+        let mut instrument = listing("seller");
+        let mut offer = open_offer("buyer", "buyer");
+        accept_offer(&mut offer, &mut instrument, 42);
+
+        assert_eq!(offer.status, types::OfferStatus::Accepted);
+        assert_eq!(offer.updated_at, Some(42));
+        assert_eq!(listing_status(&instrument), types::ListingStatus::Reserved);
+        assert_eq!(instrument.buyer_principal, "buyer");
+        assert_eq!(instrument.expires_at, None);
+    }
+
+    #[test]
+    fn only_the_author_can_withdraw_an_open_offer() {
+        let mut counter_offer = open_offer("buyer", "seller");
+        assert!(!withdraw(&mut counter_offer, "buyer", 42));
+        assert_eq!(counter_offer.status, types::OfferStatus::Open);
+
+        assert!(withdraw(&mut counter_offer, "seller", 42));
+        assert_eq!(counter_offer.status, types::OfferStatus::Withdrawn);
+        assert!(!withdraw(&mut counter_offer, "seller", 43));
+        assert_eq!(counter_offer.updated_at, Some(42));
+    }
+}
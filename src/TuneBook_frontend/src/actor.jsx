@@ -19,3 +19,24 @@ export const createActor = (identity = null) => {
   
     return Actor.createActor(idlFactory, { agent, canisterId });
 };
+
+// Avatars and photos are served by the backend canister's HTTP interface
+const blobHost = process.env.NODE_ENV === 'development'
+  ? `http://${canisterId}.localhost:4943`
+  : `https://${canisterId}.icp0.io`;
+
+// Candid optionals arrive as [] or [value]
+const unwrap = (value) => (Array.isArray(value) ? value[0] : value);
+
+export const blobUrl = (id) => `${blobHost}/blobs/${id}`;
+
+// Prefers the thumbnail, falling back to the full avatar or the given default
+export const avatarUrl = (profile, fallback = '/DefaultAvatar.png') => {
+  const id = unwrap(profile?.avatar_thumbnail_id) || unwrap(profile?.avatar_id);
+  return id ? blobUrl(id) : fallback;
+};
+
+export const photoUrls = (item, { thumbnails = false } = {}) => {
+  const ids = (thumbnails && unwrap(item?.thumbnail_ids)) || unwrap(item?.photo_ids) || [];
+  return ids.map(blobUrl);
+};
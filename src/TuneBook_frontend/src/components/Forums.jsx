@@ -1,6 +1,7 @@
 import React, { useState, useEffect, useRef } from 'react';
 import LoadingSpinner from './LoadingSpinner';
 import imageCompression from 'browser-image-compression';
+import { photoUrls } from '../actor';
 
 function Forums({ actor, currentPrincipal }) {
   const [forums, setForums] = useState([]);
@@ -354,13 +355,6 @@ function Forums({ actor, currentPrincipal }) {
   };
      
   
-  useEffect(() => {
-    if (posts.length > 0) {
-      posts.forEach((post) => {
-        if (!post.photos) fetchPhotosForPost(post.id);
-      });
-    }
-  }, [posts]);
   
     
 
@@ -506,12 +500,12 @@ function Forums({ actor, currentPrincipal }) {
     
                       {/* Post Photos */}
                       <div className="post-image-container">
-                        {post.photos && post.photos.length > 0 ? (
-                          post.photos.map((photo, index) => {
+                        {photoUrls(post).length > 0 ? (
+                          photoUrls(post).map((url, index) => {
                               return (
                                 <img
                                   key={index}
-                                  src={`data:image/png;base64,${convertUint8ArrayToBase64(photo[index])}`}
+                                  src={url}
                                   alt={`post ${index + 1}`}
                                   className="instrument-photo"
                                   style={{ minWidth: '125px', minHeight: '125px', margin: '5px', borderRadius: '10px', display: 'flex', maxWidth: '300px', maxHeight: '300px' }}
@@ -5,6 +5,7 @@ import 'abcjs/abcjs-audio.css';
 import Modal from 'react-modal';
 import FriendRequests from './Request';
 import LoadingSpinner from './LoadingSpinner';
+import { avatarUrl } from '../actor';
 
 
 
@@ -191,12 +192,7 @@ const fetchFriends = async () => {
   const fetchPotentialFriends = async () => {
     try {
       const result = await actor.browse_people(currentPrincipal, searchTerm, 0);
-      // Ensure valid avatars before setting the state
-      const processedFriends = result[0].map(friend => ({
-        ...friend,
-        avatar: friend.avatar ? friend.avatar : null // Handle missing avatars
-      }));
-      setPotentialFriends(processedFriends);
+      setPotentialFriends(result[0]);
     } catch (error) {
       console.error('Failed to fetch potential friends:', error);
     }
@@ -236,18 +232,19 @@ const fetchFriends = async () => {
 const fetchFriendProfile = async (principal) => {
   console.log(`Fetching profile for principal: ${principal}`);
   try {
-    const profileArray = await actor.authentication(principal);
-    const profile = profileArray[0];  // Assuming profileArray returns an array with profile as the first element
-    
+    // The public profile leaves out whatever the owner's privacy settings hide from us
+    const profileArray = await actor.get_profile(principal);
+    const profile = profileArray[0];
+
     if (profile && profile.username) {
-      // Store the friend's full profile (username, pob, instruments, and avatar)
       setSelectedFriendProfile({
         username: profile.username,
-        pob: profile.pob || 'Unknown',       // Fetching pob
-        instruments: profile.instruments || 'None listed',  // Fet ching instruments
-        bio: profile.bio || '...',
-        avatar: profile.avatar,              // Avatar
-        friends: profile.friends
+        pob: profile.pob[0] || 'Unknown',
+        instruments: profile.instruments[0] || 'None listed',
+        bio: profile.bio[0] || '...',
+        avatar_id: profile.avatar_id,
+        avatar_thumbnail_id: profile.avatar_thumbnail_id,
+        friends: profile.friends[0] || []
       });
     } else {
       console.warn(`No profile found for principal: ${principal}`);
@@ -255,7 +252,6 @@ const fetchFriendProfile = async (principal) => {
         username: 'Unknown',
         pob: 'Unknown',
         instruments: 'None listed',
-        avatar: '',  // Empty avatar if no profile found
       });
     }
   } catch (error) {
@@ -264,7 +260,6 @@ const fetchFriendProfile = async (principal) => {
       username: 'Unknown',
       pob: 'Unknown',
       instruments: 'None listed',
-      avatar: '',  // Handle errors with empty profile details
     });
   }
 };
@@ -386,22 +381,6 @@ const handleFriendRequestsClick = async () => {
     }
   }, [searchTerm]);
 
-// Helper function to convert Uint8Array to Base64 string
-const convertUint8ArrayToBase64 = (uint8Array) => {
-  if (uint8Array && uint8Array.byteLength) {
-    let binary = '';
-    const len = uint8Array.byteLength;
-    for (let i = 0; i < len; i++) {
-      binary += String.fromCharCode(uint8Array[i]);
-    }
-    return window.btoa(binary); 
-  } else {
-    console.warn("Invalid Uint8Array passed to convertUint8ArrayToBase64");
-    return ''; 
-  }
-};
-
-
   // Toggle Add Tune Modal
   const toggleAddTuneModal = () => {
     setShowAddTuneModal(!showAddTuneModal);
@@ -739,7 +718,7 @@ const fetchFriendRequests = async () => {
               onClick={() => displayFriendProfile(friend)} // Call the function to display the friend's profile
             >
               <img
-                src={`data:image/png;base64,${convertUint8ArrayToBase64(friend.avatar)}`}
+                src={avatarUrl(friend)}
                 alt="Avatar"
                 className="friend-avatar"
               />
@@ -762,7 +741,7 @@ const fetchFriendRequests = async () => {
             >
         <div style={{ display: 'flex', alignItems: 'center' }}>
           <img
-            src={`data:image/png;base64,${convertUint8ArrayToBase64(potentialFriend.avatar)}`}
+            src={avatarUrl(potentialFriend)}
             alt="Avatar"
             className="friend-avatar"
           />
@@ -795,7 +774,7 @@ const fetchFriendRequests = async () => {
         <div className="profile-display">
 
           <img
-            src={`data:image/png;base64,${convertUint8ArrayToBase64(myProfile.avatar)}`}
+            src={avatarUrl(myProfile)}
             alt="My Avatar"
             className="profile-avatar"
             style={{ width: '130px', height: '130px', alignSelf: 'center', border: '1px solid white' }}
@@ -1029,7 +1008,7 @@ const fetchFriendRequests = async () => {
 
           <div className="profile-display">
           <img
-            src={`data:image/png;base64,${convertUint8ArrayToBase64(selectedFriendProfile.avatar)}`}
+            src={avatarUrl(selectedFriendProfile)}
             alt={`${selectedFriendProfile.username}'s Avatar`}
             className="profile-avatar"
             style={{ width: '130px', height: '130px', alignSelf: 'center', border: '1px solid white' }}
@@ -1223,11 +1202,7 @@ const fetchFriendRequests = async () => {
             receivedRequests.map((request, index) => (
               <div key={index} className="friend-request-item">
                 <img
-                  src={
-                    request.avatar instanceof Uint8Array && request.avatar.byteLength
-                      ? `data:image/png;base64,${convertUint8ArrayToBase64(request.avatar)}`
-                      : '/path/to/default-avatar.png'
-                  }
+                  src={avatarUrl(request)}
                   alt="Avatar"
                   className="request-avatar"
                 />
@@ -1246,11 +1221,7 @@ const fetchFriendRequests = async () => {
             sentRequests.map((request, index) => (
               <div key={index} className="friend-request-item">
                 <img
-                  src={
-                    request.avatar instanceof Uint8Array && request.avatar.byteLength
-                      ? `data:image/png;base64,${convertUint8ArrayToBase64(request.avatar)}`
-                      : '/path/to/default-avatar.png'
-                  }
+                  src={avatarUrl(request)}
                   alt="Avatar"
                   style={{ width: '50px', height: '50px', borderRadius: '15px' }}
                   className="request-avatar"
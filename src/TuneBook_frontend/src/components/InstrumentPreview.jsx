@@ -1,6 +1,7 @@
 import React, { useState, useEffect } from "react";
 import LoadingSpinner from "./LoadingSpinner";
 import { useNavigate } from "react-router-dom";
+import { photoUrls } from "../actor";
 
 function InstrumentPreview({ actor, currentPrincipal }) {
   const navigate = useNavigate();
@@ -8,19 +9,6 @@ function InstrumentPreview({ actor, currentPrincipal }) {
   const [searchTerm, setSearchTerm] = useState("");
   const [loading, setLoading] = useState(false);
 
-  const convertUint8ArrayToBase64 = (uint8Array) => {
-    if (uint8Array && uint8Array.byteLength) {
-      let binary = "";
-      const len = uint8Array.byteLength;
-      for (let i = 0; i < len; i++) {
-        binary += String.fromCharCode(uint8Array[i]);
-      }
-      return window.btoa(binary);
-    } else {
-      console.warn("Invalid Uint8Array passed to convertUint8ArrayToBase64");
-      return "";
-    }
-  };
 
   const fetchInstruments = async () => {
     setLoading(true);
@@ -56,11 +44,9 @@ function InstrumentPreview({ actor, currentPrincipal }) {
           instruments.map((instrument, index) => (
             <div key={index} className="instrument-card-P">
               <div className="instrument-image-container-P">
-                {instrument.photos && instrument.photos.length > 0 ? (
+                {photoUrls(instrument, { thumbnails: true }).length > 0 ? (
                   <img
-                    src={`data:image/png;base64,${convertUint8ArrayToBase64(
-                      instrument.photos[0]
-                    )}`}
+                    src={photoUrls(instrument, { thumbnails: true })[0]}
                     alt={`${instrument.name}`}
                     className="instrument-image-P"
                   />
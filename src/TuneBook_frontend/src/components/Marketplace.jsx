@@ -3,6 +3,7 @@ import imageCompression from "browser-image-compression";
 import LoadingSpinner from "./LoadingSpinner";
 import { useNavigate } from 'react-router-dom';
 import Select from 'react-select';
+import { photoUrls } from '../actor';
 
 
 function Marketplace({ actor, currentPrincipal }) {
@@ -26,19 +27,6 @@ function Marketplace({ actor, currentPrincipal }) {
     fetchInstruments();
   }, [searchTerm, pageNum]);
 
-  const convertUint8ArrayToBase64 = (uint8Array) => {
-    if (uint8Array && uint8Array.byteLength) {
-      let binary = '';
-      const len = uint8Array.byteLength;
-      for (let i = 0; i < len; i++) {
-        binary += String.fromCharCode(uint8Array[i]);
-      }
-      return window.btoa(binary); 
-    } else {
-      console.warn("Invalid Uint8Array passed to convertUint8ArrayToBase64");
-      return ''; 
-    }
-  };
 
 
   const compressImage = async (file) => {
@@ -238,11 +226,11 @@ function Marketplace({ actor, currentPrincipal }) {
             <h3>{instrument.name}</h3>
 
             <div className="instrument-photos">
-            {instrument.photos && instrument.photos.length > 0 ? (
-              instrument.photos.map((photo, i) => (
+            {photoUrls(instrument, { thumbnails: true }).length > 0 ? (
+              photoUrls(instrument, { thumbnails: true }).map((url, i) => (
                 <img
                   key={i}
-                  src={`data:image/png;base64,${convertUint8ArrayToBase64(photo)}`}
+                  src={url}
                   alt={`Instrument ${i + 1}`}
                   className="instrument-photo"
                   style={{ width: '100px', height: '100px', margin: '5px' }}
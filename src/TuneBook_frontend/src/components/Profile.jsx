@@ -91,7 +91,7 @@ function Profile({ actor, currentPrincipal }) {
       const instrumentsString = instruments.map(option => option.value).join(', ');
   
       // Call the backend to create/update the profile
-      const newProfile = await actor.update_profile(
+      const result = await actor.update_profile(
         currentPrincipal,
         username,
         pob,
@@ -99,6 +99,10 @@ function Profile({ actor, currentPrincipal }) {
         bio,
         avatar
       );
+      if ('Err' in result) {
+        throw new Error(result.Err);
+      }
+      const newProfile = result.Ok;
   
       setMessageStatus('Profile Updated!');
       console.log('Profile created:', newProfile);
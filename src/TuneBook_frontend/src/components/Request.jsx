@@ -1,17 +1,5 @@
 import React from 'react';
-
-// Helper function to convert Uint8Array to Base64 string
-const convertUint8ArrayToBase64 = (uint8Array) => {
-  if (uint8Array && uint8Array.byteLength) {
-    let binary = '';
-    for (let i = 0; i < uint8Array.byteLength; i++) {
-      binary += String.fromCharCode(uint8Array[i]);
-    }
-    return window.btoa(binary);
-  } else {
-    return ''; // Return empty string if no valid data is passed
-  }
-};
+import { avatarUrl } from '../actor';
 
 function FriendRequests({
   sentRequests,
@@ -31,11 +19,7 @@ function FriendRequests({
             <button onClick={() => acceptFriendRequest(request.principal)}>Accept</button>
             <button onClick={() => cancelFriendRequest(request.principal)}>Reject</button>
             <img
-              src={
-                request.avatar instanceof Uint8Array && request.avatar.byteLength
-                  ? `data:image/png;base64,${convertUint8ArrayToBase64(request.avatar)}`
-                  : '/path/to/default-avatar.png'
-              }
+              src={avatarUrl(request)}
               alt="Avatar"
               style={{ width: '30px', height: '30px', borderRadius: '15px' }}
             />
@@ -50,11 +34,7 @@ function FriendRequests({
         sentRequests.map((request, index) => (
           <div key={index} className="friend-request-item">
             <img
-              src={
-                request.avatar instanceof Uint8Array && request.avatar.byteLength
-                  ? `data:image/png;base64,${convertUint8ArrayToBase64(request.avatar)}`
-                  : '/path/to/default-avatar.png'
-              }
+              src={avatarUrl(request)}
               alt="Avatar"
               style={{ width: '30px', height: '30px', borderRadius: '15px' }}
             />
@@ -64,17 +64,18 @@ function Sessions({ actor, currentPrincipal }) {
  const fetchSessions = async () => {
     try {
       const result = await actor.get_sessions("", 0); // Assuming empty search term and page 0
-      console.log("Sessions Data:", result[0]); // Log session data to inspect
-      setSessions(result[0]); // Set session data
+      const fetchedSessions = result[0].map((view) => view.session); // Attendee counts aren't shown here
+      console.log("Sessions Data:", fetchedSessions); // Log session data to inspect
+      setSessions(fetchedSessions); // Set session data
       setTotalSessions(result[1]); // Set total session count
  
  
       // Fetch usernames for each principal
-      result[0].forEach(async (session) => {
+      fetchedSessions.forEach(async (session) => {
         if (!usernames[session.principal]) {
           console.log(`Fetching profile for principal: ${session.principal}`);
           try {
-            const profile = await actor.authentication(session.principal); // Fetch profile using the principal
+            const profile = await actor.get_profile(session.principal); // Fetch the public profile using the principal
             console.log(`Fetched Profile:`, profile);
  
  